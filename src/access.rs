@@ -0,0 +1,390 @@
+// Jackson Coxson
+//
+// Access control for usbmuxd clients. Rules match a client by Unix peer
+// credentials or TCP source address and grant a set of operations; the first
+// matching rule wins. With no rules configured every client may do anything,
+// which is how usbmuxd itself behaves.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Who is on the other end of an accepted client stream.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    /// Unix socket client, identified by its peer credentials.
+    Unix {
+        uid: u32,
+        gid: u32,
+    },
+    Tcp(SocketAddr),
+    /// A client handed in by the embedding process. Rules don't apply.
+    InProcess,
+    /// A client whose identity couldn't be read. Only rules that match
+    /// everyone apply.
+    Unknown,
+}
+
+/// Something a client can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    ListDevices,
+    Listen,
+    Connect,
+    ReadPairRecord,
+    SavePairRecord,
+    DeletePairRecord,
+    ReadBuid,
+    ListListeners,
+    /// netmuxd's AddDevice / RemoveDevice extensions.
+    ManageDevices,
+    ReloadConfig,
+    /// ApprovePairing / DenyPairing / ListPairings.
+    ManagePairing,
+}
+
+impl Operation {
+    const ALL: [Operation; 11] = [
+        Self::ListDevices,
+        Self::Listen,
+        Self::Connect,
+        Self::ReadPairRecord,
+        Self::SavePairRecord,
+        Self::DeletePairRecord,
+        Self::ReadBuid,
+        Self::ListListeners,
+        Self::ManageDevices,
+        Self::ReloadConfig,
+        Self::ManagePairing,
+    ];
+
+    /// Name used in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ListDevices => "list-devices",
+            Self::Listen => "listen",
+            Self::Connect => "connect",
+            Self::ReadPairRecord => "read-pair-record",
+            Self::SavePairRecord => "save-pair-record",
+            Self::DeletePairRecord => "delete-pair-record",
+            Self::ReadBuid => "read-buid",
+            Self::ListListeners => "list-listeners",
+            Self::ManageDevices => "manage-devices",
+            Self::ReloadConfig => "reload-config",
+            Self::ManagePairing => "manage-pairing",
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A set of [`Operation`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions(u16);
+
+impl Permissions {
+    pub const ALL: Permissions = Permissions((1 << Operation::ALL.len()) - 1);
+    pub const NONE: Permissions = Permissions(0);
+
+    pub fn allows(self, op: Operation) -> bool {
+        self.0 & op.bit() != 0
+    }
+
+    /// Parse config names (plus `all`) into a set.
+    pub fn parse<S: AsRef<str>>(names: &[S]) -> Result<Self, String> {
+        let mut set = Self::NONE;
+        for name in names {
+            let name = name.as_ref();
+            if name == "all" {
+                set = Self::ALL;
+                continue;
+            }
+            match Operation::ALL.iter().find(|op| op.name() == name) {
+                Some(op) => set.0 |= op.bit(),
+                None => return Err(format!("unknown operation {name:?}")),
+            }
+        }
+        Ok(set)
+    }
+
+    pub fn without(self, other: Permissions) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`. A bare
+/// address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Treat IPv4-mapped IPv6 peers (dual-stack listeners) as IPv4.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("prefix length must be 0-{max}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// One access rule. A Unix client matches if its uid or gid is listed; a TCP
+/// client matches if its address is in one of the networks. A rule that
+/// lists nothing matches every client.
+#[derive(Debug, Clone)]
+pub struct AccessRule {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub networks: Vec<Cidr>,
+    pub permissions: Permissions,
+}
+
+impl AccessRule {
+    fn matches(&self, peer: &Peer) -> bool {
+        if self.uids.is_empty() && self.gids.is_empty() && self.networks.is_empty() {
+            return true;
+        }
+        match peer {
+            Peer::Unix { uid, gid } => self.uids.contains(uid) || self.gids.contains(gid),
+            Peer::Tcp(addr) => self.networks.iter().any(|n| n.contains(addr.ip())),
+            Peer::InProcess | Peer::Unknown => false,
+        }
+    }
+}
+
+/// What `peer` may do under `rules`. `None` means it may not connect at all.
+pub fn permissions(rules: &[AccessRule], peer: &Peer) -> Option<Permissions> {
+    if rules.is_empty() || matches!(peer, Peer::InProcess) {
+        return Some(Permissions::ALL);
+    }
+    rules
+        .iter()
+        .find(|r| r.matches(peer))
+        .map(|r| r.permissions)
+        .filter(|p| *p != Permissions::NONE)
+}
+
+/// Look up a user's uid in `/etc/passwd`.
+#[cfg(unix)]
+pub fn uid_by_name(name: &str) -> Option<u32> {
+    lookup_id("/etc/passwd", name)
+}
+
+/// Look up a group's gid in `/etc/group`.
+#[cfg(unix)]
+pub fn gid_by_name(name: &str) -> Option<u32> {
+    lookup_id("/etc/group", name)
+}
+
+/// Both files are `name:password:id:...` lines.
+#[cfg(unix)]
+fn lookup_id(file: &str, name: &str) -> Option<u32> {
+    let contents = std::fs::read_to_string(file).ok()?;
+    contents.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(uids: &[u32], networks: &[&str], permissions: Permissions) -> AccessRule {
+        AccessRule {
+            uids: uids.to_vec(),
+            gids: Vec::new(),
+            networks: networks.iter().map(|n| n.parse().unwrap()).collect(),
+            permissions,
+        }
+    }
+
+    fn tcp(addr: &str) -> Peer {
+        Peer::Tcp(SocketAddr::new(addr.parse().unwrap(), 50000))
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parses_networks_and_hosts() {
+        let net: Cidr = "10.20.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.20.255.1")));
+        assert!(!net.contains(ip("10.21.0.1")));
+
+        let host: Cidr = "192.168.1.5".parse().unwrap();
+        assert!(host.contains(ip("192.168.1.5")));
+        assert!(!host.contains(ip("192.168.1.6")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_zero_prefix_matches_its_whole_family() {
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("203.0.113.9")));
+        assert!(!any.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cidr_treats_mapped_ipv6_as_ipv4() {
+        let net: Cidr = "127.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_rejects_bad_input() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("not-an-ip".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn permission_sets() {
+        let set = Permissions::parse(&["list-devices", "connect"]).unwrap();
+        assert!(set.allows(Operation::ListDevices));
+        assert!(set.allows(Operation::Connect));
+        assert!(!set.allows(Operation::Listen));
+
+        let all = Permissions::parse(&["all"]).unwrap();
+        assert_eq!(all, Permissions::ALL);
+        assert!(Operation::ALL.iter().all(|op| all.allows(*op)));
+        assert!(
+            Operation::ALL
+                .iter()
+                .all(|op| !Permissions::NONE.allows(*op))
+        );
+
+        let fewer = all.without(Permissions::parse(&["reload-config"]).unwrap());
+        assert!(!fewer.allows(Operation::ReloadConfig));
+        assert!(fewer.allows(Operation::ManagePairing));
+
+        assert!(Permissions::parse(&["launch-missiles"]).is_err());
+    }
+
+    #[test]
+    fn every_operation_has_its_own_bit_and_name() {
+        for (i, a) in Operation::ALL.iter().enumerate() {
+            for b in &Operation::ALL[i + 1..] {
+                assert_ne!(a.bit(), b.bit());
+                assert_ne!(a.name(), b.name());
+            }
+            assert_eq!(
+                Permissions::parse(&[a.name()]).unwrap(),
+                Permissions(a.bit())
+            );
+        }
+    }
+
+    #[test]
+    fn no_rules_allow_everyone_everything() {
+        assert_eq!(
+            permissions(&[], &tcp("203.0.113.9")),
+            Some(Permissions::ALL)
+        );
+        assert_eq!(permissions(&[], &Peer::Unknown), Some(Permissions::ALL));
+    }
+
+    #[test]
+    fn in_process_clients_skip_the_rules() {
+        let rules = [rule(&[0], &[], Permissions::NONE)];
+        assert_eq!(
+            permissions(&rules, &Peer::InProcess),
+            Some(Permissions::ALL)
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let list = Permissions::parse(&["list-devices"]).unwrap();
+        let rules = [
+            rule(&[], &["10.0.0.0/8"], list),
+            rule(&[], &["10.1.0.0/16"], Permissions::ALL),
+        ];
+        assert_eq!(permissions(&rules, &tcp("10.1.2.3")), Some(list));
+    }
+
+    #[test]
+    fn unix_rules_match_uid_or_gid() {
+        let rules = [AccessRule {
+            uids: vec![0],
+            gids: vec![46],
+            networks: Vec::new(),
+            permissions: Permissions::ALL,
+        }];
+        let root = Peer::Unix { uid: 0, gid: 0 };
+        let plugdev = Peer::Unix { uid: 1000, gid: 46 };
+        let other = Peer::Unix {
+            uid: 1000,
+            gid: 1000,
+        };
+        assert_eq!(permissions(&rules, &root), Some(Permissions::ALL));
+        assert_eq!(permissions(&rules, &plugdev), Some(Permissions::ALL));
+        assert_eq!(permissions(&rules, &other), None);
+    }
+
+    #[test]
+    fn unmatched_or_empty_grants_are_refused() {
+        let rules = [
+            rule(&[], &["10.0.0.0/8"], Permissions::NONE),
+            rule(&[0], &[], Permissions::ALL),
+        ];
+        // Matched, but allowed nothing.
+        assert_eq!(permissions(&rules, &tcp("10.0.0.1")), None);
+        // Matched by nothing.
+        assert_eq!(permissions(&rules, &tcp("192.168.0.1")), None);
+        // An unreadable Unix peer only matches catch-all rules.
+        assert_eq!(permissions(&rules, &Peer::Unknown), None);
+    }
+
+    #[test]
+    fn catch_all_rule_matches_anyone() {
+        let list = Permissions::parse(&["list-devices"]).unwrap();
+        let rules = [rule(&[0], &[], Permissions::ALL), rule(&[], &[], list)];
+        assert_eq!(permissions(&rules, &Peer::Unknown), Some(list));
+        assert_eq!(permissions(&rules, &tcp("198.51.100.1")), Some(list));
+    }
+}
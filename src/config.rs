@@ -1,7 +1,30 @@
 // Jackson Coxson
 
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use idevice::usbmuxd::UsbmuxdAddr;
 
+use crate::access::{AccessRule, Cidr, Permissions};
+#[cfg(unix)]
+use crate::access::{gid_by_name, uid_by_name};
+use crate::{
+    liveness::UsbLiveness,
+    mdns::AddressPolicy,
+    pairing_policy::{PairingMode, PairingPolicy},
+    pairing_store::StoreKind,
+    policy::{ConnectionPolicy, DevicePolicies},
+    static_devices::StaticDevice,
+    tls::{TlsClientFiles, TlsServerFiles},
+    upstream::UpstreamAddr,
+};
+use log::{LevelFilter, warn};
+use serde::Deserialize;
+
 #[cfg(unix)]
 pub const DEFAULT_SOCKET_PATH: &str = "/var/run/usbmuxd";
 
@@ -10,22 +33,80 @@ pub struct NetmuxdConfig {
     pub port: u16,
     pub host: Option<String>,
     pub plist_storage: Option<String>,
+    /// Where pairing records are kept, and the database for the SQLite store.
+    pub pairing_store: StoreKind,
+    pub pairing_database: Option<PathBuf>,
     pub use_heartbeat: bool,
+    /// Reconnect attempts before a network device whose heartbeat failed is
+    /// dropped, and the wait before the first one (doubling after each).
+    pub heartbeat_retries: u32,
+    pub heartbeat_backoff: Duration,
+    /// Periodic lockdownd checks on USB devices. Off unless an interval is
+    /// set.
+    pub usb_liveness: UsbLiveness,
+    /// Network devices to add without mDNS, and how often to re-probe the
+    /// ones that aren't listed.
+    pub static_devices: Vec<StaticDevice>,
+    pub static_device_interval: Duration,
     #[cfg(unix)]
     pub use_unix: bool,
     pub use_mdns: bool,
+    /// Interfaces to run mDNS on (empty for all) and ones to leave out.
+    pub mdns_interfaces: Vec<String>,
+    pub mdns_exclude_interfaces: Vec<String>,
+    /// Which of a service's addresses to use, and whether to check that
+    /// lockdownd answers on it first.
+    pub mdns_address_policy: AddressPolicy,
+    pub mdns_probe: bool,
+    /// Unicast DNS server to browse `dnssd_domain` on for wide-area Bonjour,
+    /// and how often to poll it.
+    pub dnssd_server: Option<SocketAddr>,
+    pub dnssd_domain: Option<String>,
+    pub dnssd_interval: Duration,
     pub use_usb: bool,
+    /// Turn on Wi-Fi connections on devices paired over USB.
+    pub enable_wifi_connections: bool,
+    /// Which unpaired USB devices to pair, and how long to wait for Trust.
+    pub pairing_policy: PairingPolicy,
     pub apple_mux: bool,
     #[cfg(target_os = "windows")]
     pub kill_amds: bool,
     #[cfg(target_os = "windows")]
     pub restart_amds_on_exit: bool,
-    pub upstream: Option<UsbmuxdAddr>,
+    pub upstream: Option<UpstreamAddr>,
+    /// Certificates for a `tls://` upstream.
+    pub upstream_tls: TlsClientFiles,
+    /// `host:port` to serve usbmuxd over TLS on, using `tls_cert`/`tls_key`.
+    pub tls_listen: Option<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Require TLS clients to present a certificate signed by this CA.
+    pub tls_client_ca: Option<PathBuf>,
     #[cfg(unix)]
     pub socket_path: String,
+    /// Owner, group, and mode given to the Unix socket after binding it.
+    #[cfg(unix)]
+    pub socket_owner: Option<u32>,
+    #[cfg(unix)]
+    pub socket_group: Option<u32>,
+    #[cfg(unix)]
+    pub socket_mode: u32,
+    /// Client access rules, first match wins. Empty allows everyone.
+    pub access: Vec<AccessRule>,
+    /// Which transport to list and connect over for devices seen over both
+    /// USB and the network.
+    pub connection_policy: DevicePolicies,
+    /// Where to keep DeviceIDs so they stay the same across restarts.
+    pub device_ids_file: Option<PathBuf>,
+    /// How long shutdown waits for clients and USB devices to finish up.
+    pub drain_timeout: Duration,
+    /// Maximum log level. `None` leaves it to `RUST_LOG`.
+    pub log_level: Option<LevelFilter>,
+    /// Config file the settings were loaded from, if any.
+    pub config_path: Option<PathBuf>,
 }
 
-impl NetmuxdConfig {
+impl Default for NetmuxdConfig {
     fn default() -> Self {
         Self {
             port: 27015,
@@ -34,227 +115,1658 @@ impl NetmuxdConfig {
             #[cfg(not(unix))]
             host: Some("127.0.0.1".to_string()),
             plist_storage: None,
+            pairing_store: StoreKind::default(),
+            pairing_database: None,
             use_heartbeat: true,
+            heartbeat_retries: 3,
+            heartbeat_backoff: Duration::from_secs(2),
+            usb_liveness: UsbLiveness::default(),
+            static_devices: Vec::new(),
+            static_device_interval: Duration::from_secs(30),
             #[cfg(unix)]
             use_unix: true,
             use_mdns: true,
+            mdns_interfaces: Vec::new(),
+            mdns_exclude_interfaces: Vec::new(),
+            mdns_address_policy: AddressPolicy::default(),
+            mdns_probe: false,
+            dnssd_server: None,
+            dnssd_domain: None,
+            dnssd_interval: Duration::from_secs(60),
             use_usb: true,
+            enable_wifi_connections: false,
+            pairing_policy: PairingPolicy::default(),
             apple_mux: true,
             #[cfg(target_os = "windows")]
             kill_amds: false,
             #[cfg(target_os = "windows")]
             restart_amds_on_exit: false,
             upstream: None,
+            upstream_tls: TlsClientFiles::default(),
+            tls_listen: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
             #[cfg(unix)]
             socket_path: DEFAULT_SOCKET_PATH.to_string(),
+            #[cfg(unix)]
+            socket_owner: None,
+            #[cfg(unix)]
+            socket_group: None,
+            #[cfg(unix)]
+            socket_mode: 0o666,
+            access: Vec::new(),
+            connection_policy: DevicePolicies::default(),
+            device_ids_file: None,
+            drain_timeout: Duration::from_secs(5),
+            log_level: None,
+            config_path: None,
+        }
+    }
+}
+
+impl NetmuxdConfig {
+    /// Build the configuration from, in increasing precedence: built-in
+    /// defaults, the config file, `NETMUXD_*` environment variables, and
+    /// command-line flags.
+    ///
+    /// The config file is `--config <path>`, else `$NETMUXD_CONFIG`, else
+    /// [`default_config_path`]. Only an explicitly named file has to exist.
+    pub fn collect() -> Result<Self, ConfigError> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+
+        // Answer these before a broken config file can get in the way.
+        for arg in &args {
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    print_help();
+                    std::process::exit(0);
+                }
+                "--about" => {
+                    println!(
+                        "netmuxd v{} - a network multiplexer",
+                        env!("CARGO_PKG_VERSION")
+                    );
+                    println!("Copyright (c) 2020 Jackson Coxson");
+                    println!("Licensed under the MIT License");
+                    std::process::exit(0);
+                }
+                _ => {}
+            }
         }
+
+        Self::from_sources(
+            &args,
+            env_var("NETMUXD_CONFIG").map(PathBuf::from),
+            ConfigFile::from_env()?,
+        )
     }
-    pub fn collect() -> Self {
+
+    /// [`collect`](Self::collect) with the environment passed in:
+    /// `env_config` is `$NETMUXD_CONFIG` and `env` the other `NETMUXD_*`
+    /// variables.
+    fn from_sources(
+        args: &[String],
+        env_config: Option<PathBuf>,
+        env: ConfigFile,
+    ) -> Result<Self, ConfigError> {
+        // Walk the flags once up front to find `--config` where the real pass
+        // would, not wherever the string shows up (say, as another flag's
+        // value).
+        let mut flags = Self::default();
+        flags.apply_args(args)?;
+
         let mut res = Self::default();
-        // Loop through args
+        let (path, explicit) = match flags.config_path.or(env_config) {
+            Some(p) => (p, true),
+            None => (default_config_path(), false),
+        };
+        match ConfigFile::load(&path) {
+            Ok(file) => {
+                res.apply(file, &path.display().to_string())?;
+                res.config_path = Some(path);
+            }
+            Err(ConfigError::Io { source, .. })
+                if !explicit && source.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        res.apply(env, "environment")?;
+        res.apply_args(args)?;
+        res.validate()?;
+        Ok(res)
+    }
+
+    /// Overlay every setting present in `file`. `origin` names where it came
+    /// from, for error messages.
+    fn apply(&mut self, file: ConfigFile, origin: &str) -> Result<(), ConfigError> {
+        if let Some(port) = file.port {
+            self.port = port;
+        }
+        if let Some(host) = file.host {
+            self.host = Some(host);
+        }
+        if let Some(plist_storage) = file.plist_storage {
+            self.plist_storage = Some(plist_storage);
+        }
+        if let Some(store) = file.pairing_store {
+            self.pairing_store = store.parse().map_err(|reason| ConfigError::InvalidValue {
+                key: format!("pairing_store ({origin})"),
+                value: store.clone(),
+                reason,
+            })?;
+        }
+        if let Some(path) = file.pairing_database {
+            self.pairing_database = Some(path);
+        }
+        if let Some(heartbeat) = file.heartbeat {
+            self.use_heartbeat = heartbeat;
+        }
+        if let Some(retries) = file.heartbeat_retries {
+            self.heartbeat_retries = retries;
+        }
+        if let Some(secs) = file.heartbeat_backoff {
+            self.heartbeat_backoff = Duration::from_secs(secs);
+        }
+        if let Some(secs) = file.usb_liveness_interval {
+            self.usb_liveness.interval = secs_or_off(secs);
+        }
+        if let Some(secs) = file.usb_liveness_timeout {
+            self.usb_liveness.timeout = Duration::from_secs(secs);
+        }
+        if let Some(failures) = file.usb_liveness_failures {
+            self.usb_liveness.failures = failures;
+        }
+        if let Some(devices) = file.usb_liveness_devices {
+            for (udid, secs) in devices {
+                self.usb_liveness.devices.insert(udid, secs_or_off(secs));
+            }
+        }
+        if let Some(devices) = file.static_devices {
+            self.static_devices = devices
+                .into_iter()
+                .map(|d| d.resolve(origin))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(secs) = file.static_device_interval {
+            self.static_device_interval = Duration::from_secs(secs.max(1));
+        }
+        if let Some(mdns) = file.mdns {
+            self.use_mdns = mdns;
+        }
+        if let Some(interfaces) = file.mdns_interfaces {
+            self.mdns_interfaces = interfaces;
+        }
+        if let Some(interfaces) = file.mdns_exclude_interfaces {
+            self.mdns_exclude_interfaces = interfaces;
+        }
+        if let Some(policy) = file.mdns_address_policy {
+            self.mdns_address_policy =
+                policy.parse().map_err(|reason| ConfigError::InvalidValue {
+                    key: format!("mdns_address_policy ({origin})"),
+                    value: policy.clone(),
+                    reason,
+                })?;
+        }
+        if let Some(probe) = file.mdns_probe {
+            self.mdns_probe = probe;
+        }
+        if let Some(server) = file.dnssd_server {
+            self.dnssd_server =
+                Some(
+                    parse_dns_server(&server).map_err(|reason| ConfigError::InvalidValue {
+                        key: format!("dnssd_server ({origin})"),
+                        value: server.clone(),
+                        reason,
+                    })?,
+                );
+        }
+        if let Some(domain) = file.dnssd_domain {
+            self.dnssd_domain = Some(domain);
+        }
+        if let Some(secs) = file.dnssd_interval {
+            self.dnssd_interval = Duration::from_secs(secs.max(1));
+        }
+        if let Some(usb) = file.usb {
+            self.use_usb = usb;
+        }
+        if let Some(enable) = file.enable_wifi_connections {
+            self.enable_wifi_connections = enable;
+        }
+        if let Some(mode) = file.pairing_policy {
+            self.pairing_policy.mode =
+                mode.parse().map_err(|reason| ConfigError::InvalidValue {
+                    key: format!("pairing_policy ({origin})"),
+                    value: mode.clone(),
+                    reason,
+                })?;
+        }
+        if let Some(allowlist) = file.pairing_allowlist {
+            self.pairing_policy.allowlist = allowlist;
+        }
+        if let Some(secs) = file.pairing_timeout {
+            self.pairing_policy.timeout = Duration::from_secs(secs.max(1));
+        }
+        if let Some(retries) = file.pairing_retries {
+            self.pairing_policy.retries = retries;
+        }
+        if let Some(secs) = file.drain_timeout {
+            self.drain_timeout = Duration::from_secs(secs);
+        }
+        if let Some(level) = file.log_level {
+            self.log_level =
+                Some(
+                    parse_log_level(&level).map_err(|reason| ConfigError::InvalidValue {
+                        key: format!("log_level ({origin})"),
+                        value: level.clone(),
+                        reason,
+                    })?,
+                );
+        }
+
+        #[cfg(unix)]
+        {
+            if let Some(unix) = file.unix {
+                self.use_unix = unix;
+            }
+            if let Some(socket_path) = file.socket_path {
+                self.socket_path = socket_path;
+            }
+            if let Some(owner) = file.socket_owner {
+                self.socket_owner = Some(resolve_id(owner, uid_by_name, "socket_owner", origin)?);
+            }
+            if let Some(group) = file.socket_group {
+                self.socket_group = Some(resolve_id(group, gid_by_name, "socket_group", origin)?);
+            }
+            if let Some(mode) = file.socket_mode {
+                self.socket_mode =
+                    parse_mode(&mode).map_err(|reason| ConfigError::InvalidValue {
+                        key: format!("socket_mode ({origin})"),
+                        value: mode.to_string(),
+                        reason,
+                    })?;
+            }
+        }
+        #[cfg(not(unix))]
+        if file.unix.is_some()
+            || file.socket_path.is_some()
+            || file.socket_owner.is_some()
+            || file.socket_group.is_some()
+            || file.socket_mode.is_some()
+        {
+            warn!("{origin}: unix and socket_* settings only apply on Unix, ignoring");
+        }
+
+        if let Some(path) = file.device_ids_file {
+            self.device_ids_file = Some(path);
+        }
+        if let Some(policy) = file.connection_policy {
+            self.connection_policy.default =
+                policy.parse().map_err(|reason| ConfigError::InvalidValue {
+                    key: format!("connection_policy ({origin})"),
+                    value: policy.clone(),
+                    reason,
+                })?;
+        }
+        if let Some(devices) = file.device_connection_policy {
+            for (udid, policy) in devices {
+                let parsed: ConnectionPolicy =
+                    policy.parse().map_err(|reason| ConfigError::InvalidValue {
+                        key: format!("device_connection_policy.{udid} ({origin})"),
+                        value: policy.clone(),
+                        reason,
+                    })?;
+                self.connection_policy.devices.insert(udid, parsed);
+            }
+        }
+
+        if let Some(rules) = file.access {
+            self.access = rules
+                .into_iter()
+                .map(|r| r.resolve(origin))
+                .collect::<Result<_, _>>()?;
+        }
+
+        #[cfg(all(windows, feature = "libusbk"))]
+        if let Some(libusbk) = file.libusbk {
+            self.apple_mux = !libusbk;
+        }
+        #[cfg(not(all(windows, feature = "libusbk")))]
+        if file.libusbk.is_some() {
+            warn!("{origin}: libusbk needs a Windows build with the libusbk feature, ignoring");
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            if let Some(kill_amds) = file.kill_amds {
+                self.kill_amds = kill_amds;
+            }
+            if let Some(restart) = file.restart_amds_on_exit {
+                self.restart_amds_on_exit = restart;
+            }
+        }
+        #[cfg(not(target_os = "windows"))]
+        if file.kill_amds.is_some() || file.restart_amds_on_exit.is_some() {
+            warn!("{origin}: kill_amds and restart_amds_on_exit only apply on Windows, ignoring");
+        }
+
+        if let Some(listen) = file.tls_listen {
+            self.tls_listen = Some(listen);
+        }
+        if let Some(cert) = file.tls_cert {
+            self.tls_cert = Some(cert);
+        }
+        if let Some(key) = file.tls_key {
+            self.tls_key = Some(key);
+        }
+        if let Some(ca) = file.tls_client_ca {
+            self.tls_client_ca = Some(ca);
+        }
+        if let Some(ca) = file.upstream_tls_ca {
+            self.upstream_tls.ca = Some(ca);
+        }
+        if let Some(cert) = file.upstream_tls_cert {
+            self.upstream_tls.cert = Some(cert);
+        }
+        if let Some(key) = file.upstream_tls_key {
+            self.upstream_tls.key = Some(key);
+        }
+        if let Some(name) = file.upstream_tls_server_name {
+            self.upstream_tls.server_name = Some(name);
+        }
+
+        match file.upstream_usbmuxd {
+            Some(UpstreamSetting::Enabled(true)) => {
+                self.upstream = Some(UpstreamAddr::Usbmuxd(
+                    UsbmuxdAddr::from_env_var().unwrap_or_default(),
+                ));
+                self.use_usb = false;
+            }
+            Some(UpstreamSetting::Enabled(false)) => self.upstream = None,
+            Some(UpstreamSetting::Addr(addr)) => {
+                self.upstream =
+                    Some(
+                        parse_upstream(&addr).map_err(|reason| ConfigError::InvalidValue {
+                            key: format!("upstream_usbmuxd ({origin})"),
+                            value: addr.clone(),
+                            reason,
+                        })?,
+                    );
+                self.use_usb = false;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), ConfigError> {
+        // Repeated --static-device flags add up, replacing any list from the
+        // file or environment.
+        let mut static_devices = Vec::new();
         let mut i = 0;
-        while i < std::env::args().len() {
-            match std::env::args().nth(i).unwrap().as_str() {
+        while i < args.len() {
+            match args[i].as_str() {
+                // Loaded by `from_sources`, underneath everything else.
+                "--config" => {
+                    self.config_path = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
                 "-p" | "--port" => {
-                    res.port = std::env::args()
-                        .nth(i + 1)
-                        .expect("port flag passed without number")
-                        .parse::<u16>()
-                        .expect("port isn't a number");
+                    let value = flag_value(args, i)?;
+                    self.port = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
                     i += 2;
                 }
                 "--host" => {
-                    res.host = Some(
-                        std::env::args()
-                            .nth(i + 1)
-                            .expect("host flag passed without host")
-                            .to_string(),
-                    );
+                    self.host = Some(flag_value(args, i)?.to_string());
                     i += 2;
                 }
                 "--plist-storage" => {
-                    res.plist_storage = Some(
-                        std::env::args()
-                            .nth(i + 1)
-                            .expect("flag passed without value"),
-                    );
-                    i += 1;
+                    self.plist_storage = Some(flag_value(args, i)?.to_string());
+                    i += 2;
+                }
+                "--pairing-store" => {
+                    let value = flag_value(args, i)?;
+                    self.pairing_store =
+                        value.parse().map_err(|reason| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        })?;
+                    i += 2;
+                }
+                "--pairing-database" => {
+                    self.pairing_database = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--drain-timeout" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.drain_timeout = Duration::from_secs(secs);
+                    i += 2;
+                }
+                "--log-level" => {
+                    let value = flag_value(args, i)?;
+                    self.log_level = Some(parse_log_level(value).map_err(|reason| {
+                        ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        }
+                    })?);
+                    i += 2;
                 }
                 #[cfg(unix)]
                 "--disable-unix" => {
-                    res.use_unix = false;
+                    self.use_unix = false;
                     i += 1;
                 }
                 "--disable-mdns" => {
-                    res.use_mdns = false;
+                    self.use_mdns = false;
                     i += 1;
                 }
+                "--mdns-interfaces" => {
+                    self.mdns_interfaces = split_list(flag_value(args, i)?);
+                    i += 2;
+                }
+                "--mdns-exclude-interfaces" => {
+                    self.mdns_exclude_interfaces = split_list(flag_value(args, i)?);
+                    i += 2;
+                }
+                "--mdns-address-policy" => {
+                    let value = flag_value(args, i)?;
+                    self.mdns_address_policy =
+                        value.parse().map_err(|reason| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        })?;
+                    i += 2;
+                }
+                "--mdns-probe" => {
+                    self.mdns_probe = true;
+                    i += 1;
+                }
+                "--dnssd-server" => {
+                    let value = flag_value(args, i)?;
+                    self.dnssd_server = Some(parse_dns_server(value).map_err(|reason| {
+                        ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        }
+                    })?);
+                    i += 2;
+                }
+                "--dnssd-domain" => {
+                    self.dnssd_domain = Some(flag_value(args, i)?.to_string());
+                    i += 2;
+                }
+                "--dnssd-interval" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.dnssd_interval = Duration::from_secs(secs.max(1));
+                    i += 2;
+                }
                 "--disable-usb" => {
-                    res.use_usb = false;
+                    self.use_usb = false;
+                    i += 1;
+                }
+                "--enable-wifi-connections" => {
+                    self.enable_wifi_connections = true;
                     i += 1;
                 }
+                "--pairing-policy" => {
+                    let value = flag_value(args, i)?;
+                    self.pairing_policy.mode =
+                        value.parse().map_err(|reason| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        })?;
+                    i += 2;
+                }
+                "--pairing-allowlist" => {
+                    self.pairing_policy.allowlist = split_list(flag_value(args, i)?);
+                    i += 2;
+                }
+                "--pairing-timeout" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.pairing_policy.timeout = Duration::from_secs(secs.max(1));
+                    i += 2;
+                }
+                "--pairing-retries" => {
+                    let value = flag_value(args, i)?;
+                    self.pairing_policy.retries =
+                        value.parse().map_err(|e| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason: format!("{e}"),
+                        })?;
+                    i += 2;
+                }
                 #[cfg(all(windows, feature = "libusbk"))]
                 "--libusbk" => {
-                    res.apple_mux = false;
+                    self.apple_mux = false;
                     i += 1;
                 }
                 #[cfg(target_os = "windows")]
                 "--kill-amds" => {
-                    res.kill_amds = true;
+                    self.kill_amds = true;
                     i += 1;
                 }
                 #[cfg(target_os = "windows")]
                 "--restart-amds-on-exit" => {
-                    res.restart_amds_on_exit = true;
+                    self.restart_amds_on_exit = true;
                     i += 1;
                 }
                 "--disable-heartbeat" => {
-                    res.use_heartbeat = false;
+                    self.use_heartbeat = false;
                     i += 1;
                 }
+                "--heartbeat-retries" => {
+                    let value = flag_value(args, i)?;
+                    self.heartbeat_retries =
+                        value.parse().map_err(|e| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason: format!("{e}"),
+                        })?;
+                    i += 2;
+                }
+                "--heartbeat-backoff" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.heartbeat_backoff = Duration::from_secs(secs);
+                    i += 2;
+                }
+                "--usb-liveness-interval" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.usb_liveness.interval = secs_or_off(secs);
+                    i += 2;
+                }
+                "--usb-liveness-timeout" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.usb_liveness.timeout = Duration::from_secs(secs);
+                    i += 2;
+                }
+                "--usb-liveness-failures" => {
+                    let value = flag_value(args, i)?;
+                    self.usb_liveness.failures =
+                        value.parse().map_err(|e| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason: format!("{e}"),
+                        })?;
+                    i += 2;
+                }
+                "--static-device" => {
+                    let value = flag_value(args, i)?;
+                    static_devices.push(value.parse().map_err(|reason| {
+                        ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        }
+                    })?);
+                    i += 2;
+                }
+                "--static-device-interval" => {
+                    let value = flag_value(args, i)?;
+                    let secs: u64 = value.parse().map_err(|e| ConfigError::InvalidValue {
+                        key: args[i].clone(),
+                        value: value.to_string(),
+                        reason: format!("{e}"),
+                    })?;
+                    self.static_device_interval = Duration::from_secs(secs.max(1));
+                    i += 2;
+                }
                 "--upstream-usbmuxd" => {
-                    match std::env::args().nth(i + 1) {
+                    match args.get(i + 1) {
                         Some(addr) if !addr.starts_with('-') => {
-                            res.upstream = Some(parse_upstream(&addr));
+                            self.upstream = Some(parse_upstream(addr).map_err(|reason| {
+                                ConfigError::InvalidValue {
+                                    key: args[i].clone(),
+                                    value: addr.clone(),
+                                    reason,
+                                }
+                            })?);
                             i += 2;
                         }
                         _ => {
-                            res.upstream = Some(UsbmuxdAddr::from_env_var().unwrap_or_default());
+                            self.upstream = Some(UpstreamAddr::Usbmuxd(
+                                UsbmuxdAddr::from_env_var().unwrap_or_default(),
+                            ));
                             i += 1;
                         }
                     }
-                    res.use_usb = false;
+                    self.use_usb = false;
+                }
+                "--device-ids-file" => {
+                    self.device_ids_file = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--connection-policy" => {
+                    let value = flag_value(args, i)?;
+                    self.connection_policy.default =
+                        value.parse().map_err(|reason| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        })?;
+                    i += 2;
+                }
+                "--tls-listen" => {
+                    self.tls_listen = Some(flag_value(args, i)?.to_string());
+                    i += 2;
+                }
+                "--tls-cert" => {
+                    self.tls_cert = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--tls-key" => {
+                    self.tls_key = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--tls-client-ca" => {
+                    self.tls_client_ca = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--upstream-tls-ca" => {
+                    self.upstream_tls.ca = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--upstream-tls-cert" => {
+                    self.upstream_tls.cert = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--upstream-tls-key" => {
+                    self.upstream_tls.key = Some(PathBuf::from(flag_value(args, i)?));
+                    i += 2;
+                }
+                "--upstream-tls-server-name" => {
+                    self.upstream_tls.server_name = Some(flag_value(args, i)?.to_string());
+                    i += 2;
                 }
                 #[cfg(unix)]
                 "--socket-path" => {
-                    res.socket_path = std::env::args()
-                        .nth(i + 1)
-                        .expect("--socket-path passed without a path");
+                    self.socket_path = flag_value(args, i)?.to_string();
                     i += 2;
                 }
-                "-h" | "--help" => {
-                    println!("netmuxd - a network multiplexer");
-                    println!("Usage:");
-                    #[cfg(unix)]
-                    println!("  netmuxd [options]");
-                    #[cfg(all(windows, feature = "libusbk"))]
-                    {
-                        println!("  netmuxd [argument] [options]");
-                        println!("Arguments:");
-                        println!("  install (installs the libusbK driver)");
-                        println!("  uninstall (uninstalls the libusbK driver)");
-                        println!("  export-driver (exports the driver files for signing)");
-                    }
-                    #[cfg(all(windows, not(feature = "libusbk")))]
-                    println!("  netmuxd [options]");
-                    println!("Options:");
-                    println!("  -p, --port <port>");
-                    println!("  --host <host>");
-                    println!("  --plist-storage <path>");
-                    println!("  --disable-heartbeat");
-                    #[cfg(unix)]
-                    println!("  --disable-unix");
-                    println!("  --disable-mdns");
-                    println!("  --disable-usb");
-                    #[cfg(all(windows, feature = "libusbk"))]
-                    {
-                        println!(
-                            "  --libusbk                  (Windows: use the legacy libusbK backend instead of the"
-                        );
-                        println!(
-                            "                              default Apple-driver backend. Requires libusbK.dll and the"
-                        );
-                        println!(
-                            "                              netmuxd-installed driver, see the `install` command. By"
-                        );
-                        println!(
-                            "                              default netmuxd drives iOS devices through Apple's installed"
-                        );
-                        println!(
-                            "                              WinUSB stack, which needs no libusbK.dll but requires Apple's"
-                        );
-                        println!(
-                            "                              Mobile Device Support / Apple Devices app installed.)"
-                        );
-                    }
-                    #[cfg(target_os = "windows")]
-                    {
-                        println!(
-                            "  --kill-amds                (Windows: find and terminate Apple Mobile Device Service"
-                        );
-                        println!(
-                            "                              (AppleMobileDeviceService.exe) at startup so netmuxd owns the"
-                        );
-                        println!(
-                            "                              device and the :27015 listener before we bind it.)"
-                        );
-                        println!(
-                            "  --restart-amds-on-exit     (Windows: on shutdown (Ctrl+C) start the \"Apple Mobile Device"
-                        );
-                        println!(
-                            "                              Service\" back up via the SCM, restoring Apple's stack. Pairs"
-                        );
-                        println!("                              with --kill-amds.)");
-                    }
-                    println!(
-                        "  --upstream-usbmuxd [addr]  (shim mode: forward USB/most requests to this muxer;"
-                    );
-                    println!(
-                        "                              addr is a unix socket path or IP:port, defaulting to the"
-                    );
-                    println!(
-                        "                              system usbmuxd / USBMUXD_SOCKET_ADDRESS; implies --disable-usb)"
-                    );
-                    #[cfg(unix)]
-                    println!(
-                        "  --socket-path <path>       (unix socket to listen on; default {DEFAULT_SOCKET_PATH})"
-                    );
-                    println!("  -h, --help");
-                    println!("  --about");
-                    println!(
-                        "\n\nSet RUST_LOG to info, debug, warn, error, or trace to see more logs. Default is error."
-                    );
-                    std::process::exit(0);
+                #[cfg(unix)]
+                "--socket-owner" => {
+                    let value = NumberOrString::from_arg(flag_value(args, i)?);
+                    self.socket_owner =
+                        Some(resolve_id(value, uid_by_name, &args[i], "command line")?);
+                    i += 2;
                 }
-                "--about" => {
-                    println!(
-                        "netmuxd v{} - a network multiplexer",
-                        env!("CARGO_PKG_VERSION")
-                    );
-                    println!("Copyright (c) 2020 Jackson Coxson");
-                    println!("Licensed under the MIT License");
-                    std::process::exit(0);
+                #[cfg(unix)]
+                "--socket-group" => {
+                    let value = NumberOrString::from_arg(flag_value(args, i)?);
+                    self.socket_group =
+                        Some(resolve_id(value, gid_by_name, &args[i], "command line")?);
+                    i += 2;
+                }
+                #[cfg(unix)]
+                "--socket-mode" => {
+                    let value = flag_value(args, i)?;
+                    self.socket_mode = parse_mode(&NumberOrString::String(value.to_string()))
+                        .map_err(|reason| ConfigError::InvalidValue {
+                            key: args[i].clone(),
+                            value: value.to_string(),
+                            reason,
+                        })?;
+                    i += 2;
                 }
-                _ => {
+                // Handled by `collect`.
+                "-h" | "--help" | "--about" => {
                     i += 1;
                 }
+                other => return Err(ConfigError::UnknownFlag(other.to_string())),
             }
         }
-        res
+        if !static_devices.is_empty() {
+            self.static_devices = static_devices;
+        }
+        Ok(())
+    }
+
+    /// Reject combinations that can't work, after every source is merged.
+    fn validate(&self) -> Result<(), ConfigError> {
+        // Binding our socket deletes whatever is at the path first.
+        #[cfg(unix)]
+        if self.use_unix
+            && let Some(UpstreamAddr::Usbmuxd(UsbmuxdAddr::UnixSocket(up))) = &self.upstream
+            && *up == self.socket_path
+        {
+            return Err(ConfigError::Conflict(format!(
+                "socket path ({}) is the same as the upstream usbmuxd socket; the shim must \
+                 listen on a different path",
+                self.socket_path
+            )));
+        }
+        if self.pairing_store == StoreKind::Sqlite {
+            if self.pairing_database.is_none() {
+                return Err(ConfigError::Conflict(
+                    "pairing_store = \"sqlite\" needs a pairing_database path".to_string(),
+                ));
+            }
+            if !cfg!(feature = "sqlite") {
+                return Err(ConfigError::Conflict(
+                    "pairing_store = \"sqlite\" needs a build with the sqlite feature".to_string(),
+                ));
+            }
+        }
+        if self.pairing_policy.mode == PairingMode::Allowlist
+            && self.pairing_policy.allowlist.is_empty()
+        {
+            return Err(ConfigError::Conflict(
+                "pairing_policy = \"allowlist\" needs a pairing_allowlist".to_string(),
+            ));
+        }
+        if self.dnssd_server.is_some() && self.dnssd_domain.is_none() {
+            return Err(ConfigError::Conflict(
+                "dnssd_server needs a dnssd_domain to browse".to_string(),
+            ));
+        }
+        if self.tls_listen.is_some() && (self.tls_cert.is_none() || self.tls_key.is_none()) {
+            return Err(ConfigError::Conflict(
+                "tls_listen needs both tls_cert and tls_key".to_string(),
+            ));
+        }
+        if matches!(self.upstream, Some(UpstreamAddr::Tls { .. })) && self.upstream_tls.ca.is_none()
+        {
+            return Err(ConfigError::Conflict(
+                "a tls:// upstream needs upstream_tls_ca to verify it".to_string(),
+            ));
+        }
+        if self.upstream_tls.cert.is_some() != self.upstream_tls.key.is_some() {
+            return Err(ConfigError::Conflict(
+                "upstream_tls_cert and upstream_tls_key must be given together".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Certificates for the TLS listener, if `tls_listen` is set.
+    pub fn tls_server_files(&self) -> Option<TlsServerFiles> {
+        self.tls_listen.as_ref()?;
+        Some(TlsServerFiles {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: self.tls_client_ca.clone(),
+        })
     }
 }
 
-/// Parse an `--upstream-usbmuxd` value into a [`UsbmuxdAddr`].
-///
-/// On Unix a value without a `:` is treated as a socket path; otherwise it's
-/// parsed as an `IP:port` TCP address. On non-Unix only TCP is supported.
-fn parse_upstream(addr: &str) -> UsbmuxdAddr {
+/// Errors from [`NetmuxdConfig::collect`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{0} requires a value")]
+    MissingValue(String),
+    #[error("invalid value {value:?} for {key}: {reason}")]
+    InvalidValue {
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[error("unknown option {0:?}, see --help")]
+    UnknownFlag(String),
+    #[error("failed to read config file {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{0}")]
+    Conflict(String),
+}
+
+/// Where the config file is looked for when neither `--config` nor
+/// `NETMUXD_CONFIG` names one.
+pub fn default_config_path() -> PathBuf {
     #[cfg(unix)]
     {
-        if addr.contains(':') {
-            UsbmuxdAddr::TcpSocket(
-                addr.parse()
-                    .expect("--upstream-usbmuxd TCP address must be IP:port"),
-            )
-        } else {
-            UsbmuxdAddr::UnixSocket(addr.to_string())
-        }
+        PathBuf::from("/etc/netmuxd/netmuxd.toml")
     }
     #[cfg(not(unix))]
     {
-        UsbmuxdAddr::TcpSocket(
-            addr.parse()
-                .expect("--upstream-usbmuxd TCP address must be IP:port"),
+        let base = std::env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+        PathBuf::from(base).join("netmuxd").join("netmuxd.toml")
+    }
+}
+
+/// The config file, and the `NETMUXD_*` environment mapped onto the same
+/// keys. Every setting is optional; missing ones keep their earlier value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    port: Option<u16>,
+    host: Option<String>,
+    plist_storage: Option<String>,
+    /// `directory`, `memory`, or `sqlite`.
+    pairing_store: Option<String>,
+    pairing_database: Option<PathBuf>,
+    heartbeat: Option<bool>,
+    heartbeat_retries: Option<u32>,
+    /// Seconds.
+    heartbeat_backoff: Option<u64>,
+    /// Seconds between USB liveness checks; 0 turns them off.
+    usb_liveness_interval: Option<u64>,
+    /// Seconds.
+    usb_liveness_timeout: Option<u64>,
+    usb_liveness_failures: Option<u32>,
+    /// UDID to check interval in seconds, overriding `usb_liveness_interval`.
+    usb_liveness_devices: Option<HashMap<String, u64>>,
+    /// `[[static_devices]]` tables; `NETMUXD_STATIC_DEVICES` is a
+    /// comma-separated list of `UDID=host[:port]`.
+    static_devices: Option<Vec<StaticDeviceFile>>,
+    /// Seconds.
+    static_device_interval: Option<u64>,
+    unix: Option<bool>,
+    mdns: Option<bool>,
+    /// Interface names; comma-separated in the environment.
+    mdns_interfaces: Option<Vec<String>>,
+    mdns_exclude_interfaces: Option<Vec<String>>,
+    /// `prefer-ipv4`, `prefer-ipv6`, `ipv4-only`, or `ipv6-only`.
+    mdns_address_policy: Option<String>,
+    mdns_probe: Option<bool>,
+    /// `IP` or `IP:port`.
+    dnssd_server: Option<String>,
+    dnssd_domain: Option<String>,
+    /// Seconds.
+    dnssd_interval: Option<u64>,
+    usb: Option<bool>,
+    enable_wifi_connections: Option<bool>,
+    /// `auto`, `allowlist`, `manual`, or `never`.
+    pairing_policy: Option<String>,
+    /// UDIDs or USB serial numbers; comma-separated in the environment.
+    pairing_allowlist: Option<Vec<String>>,
+    /// Seconds.
+    pairing_timeout: Option<u64>,
+    pairing_retries: Option<u32>,
+    libusbk: Option<bool>,
+    kill_amds: Option<bool>,
+    restart_amds_on_exit: Option<bool>,
+    /// `true` for the system usbmuxd, or a socket path / `IP:port` /
+    /// `tls://host:port`.
+    upstream_usbmuxd: Option<UpstreamSetting>,
+    upstream_tls_ca: Option<PathBuf>,
+    upstream_tls_cert: Option<PathBuf>,
+    upstream_tls_key: Option<PathBuf>,
+    upstream_tls_server_name: Option<String>,
+    /// `host:port`.
+    tls_listen: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>,
+    socket_path: Option<String>,
+    socket_owner: Option<NumberOrString>,
+    socket_group: Option<NumberOrString>,
+    /// Octal, as a string (`"0660"`) or a TOML `0o660` literal.
+    socket_mode: Option<NumberOrString>,
+    access: Option<Vec<AccessRuleFile>>,
+    /// `both`, `prefer-usb`, `prefer-network`, `usb-only`, or `network-only`.
+    connection_policy: Option<String>,
+    /// UDID to policy, overriding `connection_policy` for that device.
+    device_connection_policy: Option<HashMap<String, String>>,
+    device_ids_file: Option<PathBuf>,
+    /// Seconds.
+    drain_timeout: Option<u64>,
+    log_level: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum UpstreamSetting {
+    Enabled(bool),
+    Addr(String),
+}
+
+/// A uid/gid or user/group name; or a socket mode.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(u32),
+    String(String),
+}
+
+impl NumberOrString {
+    fn from_arg(v: &str) -> Self {
+        match v.parse() {
+            Ok(n) => Self::Number(n),
+            Err(_) => Self::String(v.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for NumberOrString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => f.write_str(s),
+        }
+    }
+}
+
+/// A `[[static_devices]]` table, or a `UDID=host[:port]` string.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StaticDeviceFile {
+    Table {
+        udid: String,
+        host: String,
+        port: Option<u16>,
+    },
+    Entry(String),
+}
+
+impl StaticDeviceFile {
+    fn resolve(self, origin: &str) -> Result<StaticDevice, ConfigError> {
+        match self {
+            Self::Table { udid, host, port } => Ok(StaticDevice { udid, host, port }),
+            Self::Entry(entry) => entry.parse().map_err(|reason| ConfigError::InvalidValue {
+                key: format!("static_devices ({origin})"),
+                value: entry.clone(),
+                reason,
+            }),
+        }
+    }
+}
+
+/// An `[[access]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessRuleFile {
+    #[serde(default)]
+    uids: Vec<u32>,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    gids: Vec<u32>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    networks: Vec<String>,
+    /// Defaults to everything.
+    allow: Option<Vec<String>>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl AccessRuleFile {
+    fn resolve(self, origin: &str) -> Result<AccessRule, ConfigError> {
+        let invalid = |key: &str, value: String, reason: String| ConfigError::InvalidValue {
+            key: format!("access.{key} ({origin})"),
+            value,
+            reason,
+        };
+
+        #[cfg(unix)]
+        let (uids, gids) = {
+            let mut uids = self.uids;
+            let mut gids = self.gids;
+            for user in self.users {
+                uids.push(
+                    uid_by_name(&user)
+                        .ok_or_else(|| invalid("users", user, "no such user".to_string()))?,
+                );
+            }
+            for group in self.groups {
+                gids.push(
+                    gid_by_name(&group)
+                        .ok_or_else(|| invalid("groups", group, "no such group".to_string()))?,
+                );
+            }
+            (uids, gids)
+        };
+        #[cfg(not(unix))]
+        let (uids, gids) = {
+            if !self.users.is_empty() || !self.groups.is_empty() {
+                warn!("{origin}: access users/groups only apply on Unix, ignoring");
+            }
+            (self.uids, self.gids)
+        };
+
+        let networks = self
+            .networks
+            .into_iter()
+            .map(|n| {
+                n.parse::<Cidr>()
+                    .map_err(|reason| invalid("networks", n, reason))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let allow = match &self.allow {
+            Some(names) => Permissions::parse(names)
+                .map_err(|reason| invalid("allow", names.join(", "), reason))?,
+            None => Permissions::ALL,
+        };
+        let deny = Permissions::parse(&self.deny)
+            .map_err(|reason| invalid("deny", self.deny.join(", "), reason))?;
+
+        Ok(AccessRule {
+            uids,
+            gids,
+            networks,
+            permissions: allow.without(deny),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn resolve_id(
+    value: NumberOrString,
+    by_name: fn(&str) -> Option<u32>,
+    key: &str,
+    origin: &str,
+) -> Result<u32, ConfigError> {
+    match value {
+        NumberOrString::Number(id) => Ok(id),
+        NumberOrString::String(name) => by_name(&name).ok_or_else(|| ConfigError::InvalidValue {
+            key: format!("{key} ({origin})"),
+            value: name,
+            reason: "no such user or group".to_string(),
+        }),
+    }
+}
+
+#[cfg(unix)]
+fn parse_mode(mode: &NumberOrString) -> Result<u32, String> {
+    let mode = match mode {
+        NumberOrString::Number(n) => *n,
+        NumberOrString::String(s) => u32::from_str_radix(s.trim_start_matches("0o"), 8)
+            .map_err(|_| "expected an octal mode like 0660".to_string())?,
+    };
+    if mode > 0o7777 {
+        return Err("expected an octal mode like 0660".to_string());
+    }
+    Ok(mode)
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            port: env_parse("NETMUXD_PORT")?,
+            host: env_var("NETMUXD_HOST"),
+            plist_storage: env_var("NETMUXD_PLIST_STORAGE"),
+            pairing_store: env_var("NETMUXD_PAIRING_STORE"),
+            pairing_database: env_var("NETMUXD_PAIRING_DATABASE").map(PathBuf::from),
+            heartbeat: env_bool("NETMUXD_HEARTBEAT")?,
+            heartbeat_retries: env_parse("NETMUXD_HEARTBEAT_RETRIES")?,
+            heartbeat_backoff: env_parse("NETMUXD_HEARTBEAT_BACKOFF")?,
+            usb_liveness_interval: env_parse("NETMUXD_USB_LIVENESS_INTERVAL")?,
+            usb_liveness_timeout: env_parse("NETMUXD_USB_LIVENESS_TIMEOUT")?,
+            usb_liveness_failures: env_parse("NETMUXD_USB_LIVENESS_FAILURES")?,
+            usb_liveness_devices: None,
+            static_devices: env_var("NETMUXD_STATIC_DEVICES").map(|v| {
+                split_list(&v)
+                    .into_iter()
+                    .map(StaticDeviceFile::Entry)
+                    .collect()
+            }),
+            static_device_interval: env_parse("NETMUXD_STATIC_DEVICE_INTERVAL")?,
+            unix: env_bool("NETMUXD_UNIX")?,
+            mdns: env_bool("NETMUXD_MDNS")?,
+            mdns_interfaces: env_var("NETMUXD_MDNS_INTERFACES").map(|v| split_list(&v)),
+            mdns_exclude_interfaces: env_var("NETMUXD_MDNS_EXCLUDE_INTERFACES")
+                .map(|v| split_list(&v)),
+            mdns_address_policy: env_var("NETMUXD_MDNS_ADDRESS_POLICY"),
+            mdns_probe: env_bool("NETMUXD_MDNS_PROBE")?,
+            dnssd_server: env_var("NETMUXD_DNSSD_SERVER"),
+            dnssd_domain: env_var("NETMUXD_DNSSD_DOMAIN"),
+            dnssd_interval: env_parse("NETMUXD_DNSSD_INTERVAL")?,
+            usb: env_bool("NETMUXD_USB")?,
+            enable_wifi_connections: env_bool("NETMUXD_ENABLE_WIFI_CONNECTIONS")?,
+            pairing_policy: env_var("NETMUXD_PAIRING_POLICY"),
+            pairing_allowlist: env_var("NETMUXD_PAIRING_ALLOWLIST").map(|v| split_list(&v)),
+            pairing_timeout: env_parse("NETMUXD_PAIRING_TIMEOUT")?,
+            pairing_retries: env_parse("NETMUXD_PAIRING_RETRIES")?,
+            libusbk: env_bool("NETMUXD_LIBUSBK")?,
+            kill_amds: env_bool("NETMUXD_KILL_AMDS")?,
+            restart_amds_on_exit: env_bool("NETMUXD_RESTART_AMDS_ON_EXIT")?,
+            upstream_usbmuxd: env_var("NETMUXD_UPSTREAM_USBMUXD").map(|v| match parse_bool(&v) {
+                Some(b) => UpstreamSetting::Enabled(b),
+                None => UpstreamSetting::Addr(v),
+            }),
+            upstream_tls_ca: env_var("NETMUXD_UPSTREAM_TLS_CA").map(PathBuf::from),
+            upstream_tls_cert: env_var("NETMUXD_UPSTREAM_TLS_CERT").map(PathBuf::from),
+            upstream_tls_key: env_var("NETMUXD_UPSTREAM_TLS_KEY").map(PathBuf::from),
+            upstream_tls_server_name: env_var("NETMUXD_UPSTREAM_TLS_SERVER_NAME"),
+            tls_listen: env_var("NETMUXD_TLS_LISTEN"),
+            tls_cert: env_var("NETMUXD_TLS_CERT").map(PathBuf::from),
+            tls_key: env_var("NETMUXD_TLS_KEY").map(PathBuf::from),
+            tls_client_ca: env_var("NETMUXD_TLS_CLIENT_CA").map(PathBuf::from),
+            socket_path: env_var("NETMUXD_SOCKET_PATH"),
+            socket_owner: env_var("NETMUXD_SOCKET_OWNER").map(|v| NumberOrString::from_arg(&v)),
+            socket_group: env_var("NETMUXD_SOCKET_GROUP").map(|v| NumberOrString::from_arg(&v)),
+            socket_mode: env_var("NETMUXD_SOCKET_MODE").map(NumberOrString::String),
+            access: None,
+            connection_policy: env_var("NETMUXD_CONNECTION_POLICY"),
+            device_connection_policy: None,
+            device_ids_file: env_var("NETMUXD_DEVICE_IDS_FILE").map(PathBuf::from),
+            drain_timeout: env_parse("NETMUXD_DRAIN_TIMEOUT")?,
+            log_level: env_var("NETMUXD_LOG_LEVEL"),
+        })
+    }
+}
+
+/// The argument following the flag at `args[i]`.
+fn flag_value(args: &[String], i: usize) -> Result<&str, ConfigError> {
+    args.get(i + 1)
+        .map(String::as_str)
+        .ok_or_else(|| ConfigError::MissingValue(args[i].clone()))
+}
+
+/// A set, non-empty environment variable.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn env_parse<T>(name: &str) -> Result<Option<T>, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    env_var(name)
+        .map(|v| {
+            v.parse().map_err(|e: T::Err| ConfigError::InvalidValue {
+                key: name.to_string(),
+                value: v.clone(),
+                reason: e.to_string(),
+            })
+        })
+        .transpose()
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>, ConfigError> {
+    env_var(name)
+        .map(|v| {
+            parse_bool(&v).ok_or_else(|| ConfigError::InvalidValue {
+                key: name.to_string(),
+                value: v.clone(),
+                reason: "expected true/false, 1/0, yes/no, or on/off".to_string(),
+            })
+        })
+        .transpose()
+}
+
+fn parse_bool(v: &str) -> Option<bool> {
+    match v.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// A comma-separated list, without empty entries.
+fn split_list(v: &str) -> Vec<String> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// A seconds setting where 0 means off.
+fn secs_or_off(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn parse_log_level(v: &str) -> Result<LevelFilter, String> {
+    v.parse()
+        .map_err(|_| "expected off, error, warn, info, debug, or trace".to_string())
+}
+
+fn print_help() {
+    println!("netmuxd - a network multiplexer");
+    println!("Usage:");
+    #[cfg(unix)]
+    println!("  netmuxd [options]");
+    #[cfg(all(windows, feature = "libusbk"))]
+    {
+        println!("  netmuxd [argument] [options]");
+        println!("Arguments:");
+        println!("  install (installs the libusbK driver)");
+        println!("  uninstall (uninstalls the libusbK driver)");
+        println!("  export-driver (exports the driver files for signing)");
+    }
+    #[cfg(all(windows, not(feature = "libusbk")))]
+    println!("  netmuxd [options]");
+    println!("Options:");
+    println!(
+        "  --config <path>            (TOML config file; default {})",
+        default_config_path().display()
+    );
+    println!("  -p, --port <port>");
+    println!("  --host <host>");
+    println!("  --plist-storage <path>");
+    println!("  --pairing-store <kind>     (directory, memory, or sqlite; default directory)");
+    println!("  --pairing-database <path>  (SQLite database for --pairing-store sqlite)");
+    println!("  --log-level <level>        (off, error, warn, info, debug, or trace)");
+    println!("  --drain-timeout <secs>     (how long shutdown waits for clients; default 5)");
+    println!("  --device-ids-file <path>   (keep DeviceIDs stable across restarts)");
+    println!("  --disable-heartbeat");
+    println!(
+        "  --heartbeat-retries <n>    (reconnects before dropping a network device; default 3)"
+    );
+    println!("  --heartbeat-backoff <secs> (wait before the first reconnect, doubling; default 2)");
+    println!(
+        "  --usb-liveness-interval <secs>  (query USB devices' lockdownd this often; 0 = off,"
+    );
+    println!("                              the default)");
+    println!("  --usb-liveness-timeout <secs>   (how long a liveness check may take; default 5)");
+    println!(
+        "  --usb-liveness-failures <n>     (missed checks before the USB mux is reset; default 3)"
+    );
+    println!(
+        "  --static-device <udid>=<host>[:port]  (add a network device without mDNS; repeatable)"
+    );
+    println!("  --static-device-interval <secs>  (re-probe unlisted static devices; default 30)");
+    println!("  --connection-policy <p>    (devices on both USB and network: both, prefer-usb,");
+    println!(
+        "                              prefer-network, usb-only, or network-only; default both)"
+    );
+    #[cfg(unix)]
+    println!("  --disable-unix");
+    println!("  --disable-mdns");
+    println!("  --mdns-interfaces <a,b>    (only run mDNS on these interfaces)");
+    println!("  --mdns-exclude-interfaces <a,b>  (never run mDNS on these interfaces)");
+    println!("  --mdns-address-policy <p>  (prefer-ipv4, prefer-ipv6, ipv4-only, or ipv6-only;");
+    println!("                              default prefer-ipv4)");
+    println!("  --mdns-probe               (use the first address whose lockdownd answers)");
+    println!("  --dnssd-server <ip[:port]>  (also browse for devices on this unicast DNS server)");
+    println!("  --dnssd-domain <domain>    (wide-area Bonjour domain to browse, e.g. example.com)");
+    println!("  --dnssd-interval <secs>    (how often to browse; default 60)");
+    println!("  --disable-usb");
+    println!("  --enable-wifi-connections  (let devices paired over USB connect over Wi-Fi)");
+    println!("  --pairing-policy <p>       (which unpaired USB devices to pair: auto, allowlist,");
+    println!("                              manual, or never; default auto)");
+    println!("  --pairing-allowlist <a,b>  (UDIDs or serials that may pair without approval)");
+    println!(
+        "  --pairing-timeout <secs>   (how long to wait for Trust on the device; default 120)"
+    );
+    println!("  --pairing-retries <n>      (further Trust prompts after a timeout; default 2)");
+    #[cfg(all(windows, feature = "libusbk"))]
+    {
+        println!(
+            "  --libusbk                  (Windows: use the legacy libusbK backend instead of the"
+        );
+        println!(
+            "                              default Apple-driver backend. Requires libusbK.dll and the"
+        );
+        println!(
+            "                              netmuxd-installed driver, see the `install` command. By"
+        );
+        println!(
+            "                              default netmuxd drives iOS devices through Apple's installed"
+        );
+        println!(
+            "                              WinUSB stack, which needs no libusbK.dll but requires Apple's"
+        );
+        println!(
+            "                              Mobile Device Support / Apple Devices app installed.)"
+        );
+    }
+    #[cfg(target_os = "windows")]
+    {
+        println!(
+            "  --kill-amds                (Windows: find and terminate Apple Mobile Device Service"
+        );
+        println!(
+            "                              (AppleMobileDeviceService.exe) at startup so netmuxd owns the"
+        );
+        println!(
+            "                              device and the :27015 listener before we bind it.)"
+        );
+        println!(
+            "  --restart-amds-on-exit     (Windows: on shutdown (Ctrl+C) start the \"Apple Mobile Device"
+        );
+        println!(
+            "                              Service\" back up via the SCM, restoring Apple's stack. Pairs"
+        );
+        println!("                              with --kill-amds.)");
+    }
+    println!("  --tls-listen <host:port>   (also serve usbmuxd over TLS here)");
+    println!("  --tls-cert <path>          (PEM certificate chain for --tls-listen)");
+    println!("  --tls-key <path>           (PEM private key for --tls-listen)");
+    println!("  --tls-client-ca <path>     (require TLS client certificates signed by this CA)");
+    println!("  --upstream-usbmuxd [addr]  (shim mode: forward USB/most requests to this muxer;");
+    println!(
+        "                              addr is a unix socket path, IP:port, or tls://host:port,"
+    );
+    println!(
+        "                              defaulting to the system usbmuxd / USBMUXD_SOCKET_ADDRESS;"
+    );
+    println!("                              implies --disable-usb)");
+    println!(
+        "  --upstream-tls-ca <path>   (CA that a tls:// upstream's certificate must chain to)"
+    );
+    println!("  --upstream-tls-cert <path> (client certificate for a tls:// upstream)");
+    println!("  --upstream-tls-key <path>  (client key for a tls:// upstream)");
+    println!("  --upstream-tls-server-name <name>  (name to verify a tls:// upstream against)");
+    #[cfg(unix)]
+    {
+        println!(
+            "  --socket-path <path>       (unix socket to listen on; default {DEFAULT_SOCKET_PATH})"
+        );
+        println!("  --socket-owner <user>      (owner of the unix socket)");
+        println!("  --socket-group <group>     (group of the unix socket)");
+        println!(
+            "  --socket-mode <mode>       (octal permissions of the unix socket; default 0666)"
+        );
+    }
+    println!("  -h, --help");
+    println!("  --about");
+    println!(
+        "\nEvery option can also be set in the config file or as a NETMUXD_* environment variable\n\
+         (e.g. port = 27015 / NETMUXD_PORT=27015, mdns = false / NETMUXD_MDNS=0). Flags override\n\
+         the environment, which overrides the config file."
+    );
+    println!(
+        "\n\nSet RUST_LOG to info, debug, warn, error, or trace to see more logs. Default is error."
+    );
+}
+
+/// Parse a DNS server address, defaulting to port 53.
+fn parse_dns_server(addr: &str) -> Result<SocketAddr, String> {
+    if let Ok(a) = addr.parse() {
+        return Ok(a);
+    }
+    addr.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| "expected IP or IP:port".to_string())
+}
+
+/// Parse an `--upstream-usbmuxd` value into an [`UpstreamAddr`].
+///
+/// `tls://host:port` is a remote netmuxd's TLS listener. Otherwise, on Unix a
+/// value without a `:` is treated as a socket path, and anything else is
+/// parsed as an `IP:port` TCP address. On non-Unix only TCP is supported.
+fn parse_upstream(addr: &str) -> Result<UpstreamAddr, String> {
+    if let Some(rest) = addr.strip_prefix("tls://") {
+        let (host, port) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| "TLS upstream must be tls://host:port".to_string())?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("TLS upstream must be tls://host:port".to_string());
+        }
+        let port = port.parse().map_err(|e| format!("bad port: {e}"))?;
+        return Ok(UpstreamAddr::Tls {
+            host: host.to_string(),
+            port,
+        });
+    }
+    #[cfg(unix)]
+    if !addr.contains(':') {
+        return Ok(UpstreamAddr::Usbmuxd(UsbmuxdAddr::UnixSocket(
+            addr.to_string(),
+        )));
+    }
+    addr.parse()
+        .map(|a| UpstreamAddr::Usbmuxd(UsbmuxdAddr::TcpSocket(a)))
+        .map_err(|_| "TCP address must be IP:port".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// A config file that's removed again when dropped.
+    struct TempConfig(PathBuf);
+
+    impl TempConfig {
+        fn new(contents: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("netmuxd-config-test-{}.toml", uuid::Uuid::new_v4()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn missing_path() -> PathBuf {
+        std::env::temp_dir().join(format!("netmuxd-missing-{}.toml", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn defaults_without_any_source() {
+        let config =
+            NetmuxdConfig::from_sources(&[], None, ConfigFile::default()).unwrap_or_else(|e| {
+                // Only a config file installed on this machine could get in
+                // the way.
+                panic!("{e} (is {} present?)", default_config_path().display())
+            });
+        assert_eq!(config.port, 27015);
+        assert!(config.use_mdns);
+        assert!(config.use_usb);
+        assert_eq!(config.heartbeat_retries, 3);
+    }
+
+    #[test]
+    fn file_then_env_then_flags() {
+        let file =
+            TempConfig::new("port = 1000\nhost = \"file\"\nheartbeat_retries = 9\nmdns = false\n");
+        let env = ConfigFile {
+            port: Some(2000),
+            host: Some("env".to_string()),
+            ..Default::default()
+        };
+        let config = NetmuxdConfig::from_sources(
+            &args(&["--config", file.path(), "--port", "3000"]),
+            None,
+            env,
         )
+        .unwrap();
+        assert_eq!(config.port, 3000);
+        assert_eq!(config.host.as_deref(), Some("env"));
+        assert_eq!(config.heartbeat_retries, 9);
+        assert!(!config.use_mdns);
+        assert_eq!(config.config_path.as_deref(), Some(file.0.as_path()));
+    }
+
+    #[test]
+    fn config_flag_wins_over_env_config() {
+        let flag = TempConfig::new("port = 1\n");
+        let config = NetmuxdConfig::from_sources(
+            &args(&["--config", flag.path()]),
+            Some(missing_path()),
+            ConfigFile::default(),
+        )
+        .unwrap();
+        assert_eq!(config.port, 1);
+    }
+
+    #[test]
+    fn env_config_names_the_file() {
+        let file = TempConfig::new("port = 2\n");
+        let config =
+            NetmuxdConfig::from_sources(&[], Some(file.0.clone()), ConfigFile::default()).unwrap();
+        assert_eq!(config.port, 2);
+    }
+
+    #[test]
+    fn config_as_another_flags_value_is_not_a_config_flag() {
+        // A plain search for "--config" would load "--plist-storage".
+        let file = TempConfig::new("port = 5\n");
+        let config = NetmuxdConfig::from_sources(
+            &args(&["--host", "--config", "--plist-storage", "/tmp/x"]),
+            Some(file.0.clone()),
+            ConfigFile::default(),
+        )
+        .unwrap();
+        assert_eq!(config.host.as_deref(), Some("--config"));
+        assert_eq!(config.port, 5);
+    }
+
+    #[test]
+    fn explicit_missing_file_is_an_error() {
+        let missing = missing_path();
+        let result = NetmuxdConfig::from_sources(
+            &args(&["--config", missing.to_str().unwrap()]),
+            None,
+            ConfigFile::default(),
+        );
+        assert!(matches!(result, Err(ConfigError::Io { .. })));
+    }
+
+    #[test]
+    fn unknown_file_key_is_rejected() {
+        let file = TempConfig::new("prot = 1\n");
+        let result = NetmuxdConfig::from_sources(
+            &args(&["--config", file.path()]),
+            None,
+            ConfigFile::default(),
+        );
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn unknown_flag_is_rejected() {
+        let mut config = NetmuxdConfig::default();
+        let result = config.apply_args(&args(&["--port", "1", "--bogus"]));
+        assert!(matches!(result, Err(ConfigError::UnknownFlag(f)) if f == "--bogus"));
+    }
+
+    #[test]
+    fn flag_without_value_is_rejected() {
+        let mut config = NetmuxdConfig::default();
+        let result = config.apply_args(&args(&["--port"]));
+        assert!(matches!(result, Err(ConfigError::MissingValue(f)) if f == "--port"));
+    }
+
+    #[test]
+    fn bad_flag_value_is_rejected() {
+        let mut config = NetmuxdConfig::default();
+        let result = config.apply_args(&args(&["--port", "many"]));
+        assert!(matches!(result, Err(ConfigError::InvalidValue { key, .. }) if key == "--port"));
+    }
+
+    #[test]
+    fn repeated_static_devices_add_up() {
+        let mut config = NetmuxdConfig::default();
+        config
+            .apply_args(&args(&[
+                "--static-device",
+                "A=10.0.0.1",
+                "--static-device",
+                "B=10.0.0.2:1234",
+            ]))
+            .unwrap();
+        let udids: Vec<&str> = config
+            .static_devices
+            .iter()
+            .map(|d| d.udid.as_str())
+            .collect();
+        assert_eq!(udids, ["A", "B"]);
+        assert_eq!(config.static_devices[1].port, Some(1234));
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        NetmuxdConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_conflicts() {
+        let conflict = |config: NetmuxdConfig| {
+            assert!(
+                matches!(config.validate(), Err(ConfigError::Conflict(_))),
+                "{config:?} should not validate"
+            );
+        };
+
+        let mut config = NetmuxdConfig {
+            pairing_policy: PairingPolicy {
+                mode: PairingMode::Allowlist,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        conflict(config.clone());
+        config.pairing_policy.allowlist = vec!["00008030-001A2B3C4D5E6F70".to_string()];
+        config.validate().unwrap();
+
+        conflict(NetmuxdConfig {
+            dnssd_server: Some("127.0.0.1:53".parse().unwrap()),
+            ..Default::default()
+        });
+        conflict(NetmuxdConfig {
+            tls_listen: Some("0.0.0.0:27016".to_string()),
+            tls_cert: Some(PathBuf::from("cert.pem")),
+            ..Default::default()
+        });
+        conflict(NetmuxdConfig {
+            pairing_store: StoreKind::Sqlite,
+            ..Default::default()
+        });
+        conflict(NetmuxdConfig {
+            upstream_tls: TlsClientFiles {
+                cert: Some(PathBuf::from("client.pem")),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
     }
 }
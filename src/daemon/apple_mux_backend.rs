@@ -11,17 +11,14 @@ use log::{debug, info, trace, warn};
 use tokio::sync::{Mutex, oneshot};
 
 use crate::apple_mux::{AppleMuxReader, AppleMuxWriter, Device, enumerate_paths};
-use crate::config::NetmuxdConfig;
 use crate::manager::ManagerSender;
-use crate::pairing_file::PairingFileFinder;
 use crate::usb::mux::{self, UsbMuxHandle};
 
-use super::{DeviceMeta, connect_device, send_remove};
+use super::{DeviceMeta, UsbPairing, connect_device, send_remove};
 
 const POLL_INTERVAL: Duration = Duration::from_secs(2);
 
-pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
-    let pairing_file_finder = PairingFileFinder::new(&config);
+pub(super) async fn run(sender: ManagerSender, pairing: UsbPairing) {
     // Map interface path -> UDID. The path is stable for a physical
     // connection (its instance id changes across replug), so it's a
     // good hotplug key.
@@ -49,13 +46,7 @@ pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
             if active.contains(&path) {
                 continue;
             }
-            handle_connected(
-                path,
-                sender.clone(),
-                pairing_file_finder.clone(),
-                known.clone(),
-            )
-            .await;
+            handle_connected(path, sender.clone(), pairing.clone(), known.clone()).await;
         }
 
         for stale in active.difference(&current) {
@@ -73,7 +64,7 @@ pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
 async fn handle_connected(
     path: String,
     sender: ManagerSender,
-    pairing_file_finder: PairingFileFinder,
+    pairing: UsbPairing,
     known: Arc<Mutex<HashMap<String, String>>>,
 ) {
     let product_id = parse_pid(&path).unwrap_or(0) as u64;
@@ -131,9 +122,7 @@ async fn handle_connected(
 
     let map_udid = connect_device(
         &sender,
-        &pairing_file_finder,
-        &known,
-        path.clone(),
+        &pairing,
         handle,
         raw_udid.clone(),
         DeviceMeta {
@@ -16,15 +16,13 @@ use log::{debug, info, trace, warn};
 use tokio::sync::Mutex;
 use tokio::sync::oneshot;
 
-use crate::config::NetmuxdConfig;
 use crate::libusbk::{Device, DeviceList, LibusbkReader, LibusbkWriter};
 use crate::manager::ManagerSender;
-use crate::pairing_file::PairingFileFinder;
 use crate::usb::mux::{self, UsbMuxHandle};
 
 use super::{
     APPLE_VID, DeviceMeta, INTERFACE_CLASS, INTERFACE_PROTOCOL, INTERFACE_SUBCLASS, PID_RANGE_HIGH,
-    PID_RANGE_LOW, connect_device, send_remove,
+    PID_RANGE_LOW, UsbPairing, connect_device, send_remove,
 };
 
 const POLL_INTERVAL: Duration = Duration::from_secs(2);
@@ -43,9 +41,7 @@ const ENDPOINT_TYPE_MASK: u8 = 0x03;
 
 // --- entry point -------------------------------------------------------
 
-pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
-    let pairing_file_finder = PairingFileFinder::new(&config);
-
+pub(super) async fn run(sender: ManagerSender, pairing: UsbPairing) {
     // Map device-instance ID -> UDID. The Windows DeviceID string is
     // stable across the lifetime of a single physical connection.
     let known: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
@@ -67,13 +63,7 @@ pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
             if active.contains(&cand.device_id) {
                 continue;
             }
-            handle_connected(
-                cand,
-                sender.clone(),
-                pairing_file_finder.clone(),
-                known.clone(),
-            )
-            .await;
+            handle_connected(cand, sender.clone(), pairing.clone(), known.clone()).await;
         }
 
         for stale in active.difference(&current) {
@@ -135,7 +125,7 @@ fn is_apple_mux(vid: u16, pid: u16) -> bool {
 async fn handle_connected(
     cand: Candidate,
     sender: ManagerSender,
-    pairing_file_finder: PairingFileFinder,
+    pairing: UsbPairing,
     known: Arc<Mutex<HashMap<String, String>>>,
 ) {
     let device_id = cand.device_id.clone();
@@ -228,9 +218,7 @@ async fn handle_connected(
 
     let map_udid = connect_device(
         &sender,
-        &pairing_file_finder,
-        &known,
-        device_id.clone(),
+        &pairing,
         handle,
         raw_udid.clone(),
         DeviceMeta {
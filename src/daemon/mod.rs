@@ -10,16 +10,17 @@
 // Both backends end up calling the helpers below to pair (if needed)
 // and register the device with the manager.
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::time::Duration;
 
 use idevice::{Idevice, IdeviceError, services::lockdown::LockdownClient};
 use log::{info, warn};
-use tokio::sync::Mutex;
+use tokio::sync::oneshot;
 
 use crate::config::NetmuxdConfig;
+use crate::devices::UsbStatus;
 use crate::manager::{ManagerRequest, ManagerRequestType, ManagerSender};
 use crate::pairing_file::PairingFileFinder;
+use crate::pairing_policy::{PairingMode, PairingPolicy, PairingState};
 use crate::usb::mux::UsbMuxHandle;
 
 // Re-export the Apple-specific constants the daemon backends share with
@@ -42,6 +43,20 @@ mod nusb_backend;
 pub(crate) const PID_RANGE_LOW: u16 = *PID_RANGE.start();
 pub(crate) const PID_RANGE_HIGH: u16 = *PID_RANGE.end();
 
+/// Pause between pairing attempts, so a device that refuses straight away
+/// (e.g. locked with a passcode) isn't asked again in a tight loop.
+const PAIRING_RETRY_DELAY: Duration = Duration::from_secs(3);
+
+/// Pairing records, and how USB devices without one get paired. Cloned
+/// into every backend's per-device task.
+#[derive(Clone, Debug)]
+pub(crate) struct UsbPairing {
+    pub finder: PairingFileFinder,
+    /// Turn on Wi-Fi connections (`EnableWifiConnections`) on devices we pair.
+    pub enable_wifi: bool,
+    pub policy: PairingPolicy,
+}
+
 pub fn usb_available(apple_mux: bool) -> bool {
     #[cfg(target_os = "windows")]
     {
@@ -67,22 +82,31 @@ pub fn usb_available(apple_mux: bool) -> bool {
 }
 
 /// Entry point. Dispatches to the platform's USB backend.
-pub async fn discover(sender: ManagerSender, config: NetmuxdConfig) {
+pub async fn discover(
+    sender: ManagerSender,
+    config: NetmuxdConfig,
+    pairing_file_finder: PairingFileFinder,
+) {
+    let pairing = UsbPairing {
+        finder: pairing_file_finder,
+        enable_wifi: config.enable_wifi_connections,
+        policy: config.pairing_policy.clone(),
+    };
     #[cfg(not(target_os = "windows"))]
-    nusb_backend::run(sender, config).await;
+    nusb_backend::run(sender, pairing).await;
     #[cfg(target_os = "windows")]
     {
         // Default: the apple_mux backend (rides Apple's installed WinUSB
         // stack, no driver of our own). `--libusbk` opts into the legacy
         // libusbK backend, when compiled in.
         if config.apple_mux {
-            apple_mux_backend::run(sender, config).await;
+            apple_mux_backend::run(sender, pairing).await;
         } else {
             #[cfg(feature = "libusbk")]
-            libusbk_backend::run(sender, config).await;
+            libusbk_backend::run(sender, pairing).await;
             #[cfg(not(feature = "libusbk"))]
             {
-                let _ = (sender, config);
+                let _ = (sender, pairing);
                 log::error!(
                     "--libusbk was requested but this build has no libusbK backend compiled in"
                 );
@@ -100,6 +124,7 @@ pub(crate) async fn register_with_manager(
     location_id: u64,
     product_id: u64,
     speed: u64,
+    status: Option<UsbStatus>,
 ) {
     if let Err(e) = sender
         .send(ManagerRequest {
@@ -109,6 +134,7 @@ pub(crate) async fn register_with_manager(
                 product_id,
                 speed,
                 handle,
+                status,
             },
             response: None,
         })
@@ -118,6 +144,22 @@ pub(crate) async fn register_with_manager(
     }
 }
 
+async fn set_pairing_state(sender: &ManagerSender, serial: &str, udid: &str, state: PairingState) {
+    if let Err(e) = sender
+        .send(ManagerRequest {
+            request_type: ManagerRequestType::SetPairingState {
+                serial: serial.to_string(),
+                udid: udid.to_string(),
+                state,
+            },
+            response: None,
+        })
+        .await
+    {
+        warn!("Failed to publish pairing state of {serial}: {e:?}");
+    }
+}
+
 fn synthesize_udid(raw: &str) -> String {
     if raw.len() == 24 && raw.chars().all(|c| c.is_ascii_hexdigit()) {
         format!("{}-{}", &raw[..8], &raw[8..])
@@ -126,48 +168,127 @@ fn synthesize_udid(raw: &str) -> String {
     }
 }
 
-/// Run the lockdown Pair flow over the USB mux. Blocks while the
-/// device displays the Trust prompt to the user. On success, writes
-/// the pairing record to disk and returns the canonical UDID we used
-/// as the filename.
-pub(crate) async fn pair_via_usb(
-    pairing_finder: &PairingFileFinder,
-    handle: &UsbMuxHandle,
-    raw_udid: &str,
-) -> Result<String, String> {
-    let stream = handle
-        .connect(LockdownClient::LOCKDOWND_PORT)
-        .await
-        .map_err(|e| format!("usb connect to lockdown: {e:?}"))?;
+/// Ask lockdownd, which answers without pairing, what mode the device is in
+/// and what its UDID is. The UDID falls back to the one the USB serial
+/// number gives.
+async fn probe_device(handle: &UsbMuxHandle, raw_udid: &str) -> (String, UsbStatus) {
+    let synthesized = synthesize_udid(raw_udid);
+    let stream = match handle.connect(LockdownClient::LOCKDOWND_PORT).await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to reach lockdown on {raw_udid}: {e:?}");
+            return (synthesized, UsbStatus::Unpaired);
+        }
+    };
 
-    let idevice = Idevice::new(Box::new(stream), "netmuxd-pair");
+    let idevice = Idevice::new(Box::new(stream), "netmuxd-probe");
     let mut lockdown = LockdownClient { idevice };
 
     match lockdown.idevice.get_type().await {
         Ok(ty) if ty != "com.apple.mobile.lockdown" => {
-            info!("{raw_udid} is running '{ty}' (restore mode); exposing without pairing");
-            return Ok(synthesize_udid(raw_udid));
+            info!("{raw_udid} is running '{ty}' (restore mode); listing without pairing");
+            return (synthesized, UsbStatus::Restore);
         }
         Ok(_) => {}
+        Err(e) => warn!("QueryType failed for {raw_udid}: {e:?}"),
+    }
+
+    match lockdown.get_value(Some("UniqueDeviceID"), None).await {
+        Ok(plist::Value::String(udid)) => (udid, UsbStatus::Unpaired),
+        Ok(other) => {
+            warn!("UniqueDeviceID of {raw_udid} is not a string: {other:?}");
+            (synthesized, UsbStatus::Unpaired)
+        }
         Err(e) => {
-            warn!("QueryType failed for {raw_udid}: {e:?}; attempting to pair anyway");
+            warn!("GetValue(UniqueDeviceID) failed for {raw_udid}: {e:?}; using synthesized UDID");
+            (synthesized, UsbStatus::Unpaired)
         }
     }
+}
 
-    // Ask the device for its canonical UDID
-    let canonical_udid = match lockdown.get_value(Some("UniqueDeviceID"), None).await {
-        Ok(v) => v
-            .as_string()
-            .map(|s| s.to_string())
-            .ok_or_else(|| "UniqueDeviceID not a string".to_string())?,
-        Err(e) => {
-            // Fallback: synthesize the dashed form from the raw 24-char serial.
-            warn!("GetValue(UniqueDeviceID) failed: {e:?}; using synthesized UDID");
-            synthesize_udid(raw_udid)
+/// Pair `udid` if `pairing.policy` allows it, publishing each step to the
+/// manager. On failure, returns the state it ended in.
+async fn pair_with_policy(
+    sender: &ManagerSender,
+    pairing: &UsbPairing,
+    handle: &UsbMuxHandle,
+    raw_udid: &str,
+    udid: &str,
+) -> Result<(), PairingState> {
+    let policy = &pairing.policy;
+    let allowlisted = policy.allowlisted(raw_udid, udid);
+    match policy.mode {
+        PairingMode::Auto => {}
+        PairingMode::Allowlist | PairingMode::Manual if allowlisted => {}
+        PairingMode::Manual => {
+            info!("Pairing with {raw_udid} is waiting for ApprovePairing or DenyPairing");
+            let (tx, rx) = oneshot::channel();
+            if let Err(e) = sender
+                .send(ManagerRequest {
+                    request_type: ManagerRequestType::AwaitPairingApproval {
+                        serial: raw_udid.to_string(),
+                        udid: udid.to_string(),
+                        response: tx,
+                    },
+                    response: None,
+                })
+                .await
+            {
+                warn!("Failed to ask for approval to pair {raw_udid}: {e:?}");
+            }
+            // The sender is dropped if the device goes away first.
+            if !rx.await.unwrap_or(false) {
+                info!("Pairing with {raw_udid} was denied");
+                return Err(PairingState::Denied);
+            }
         }
-    };
+        PairingMode::Allowlist | PairingMode::Never => {
+            info!(
+                "Not pairing with {raw_udid} (pairing_policy = {})",
+                policy.mode.name()
+            );
+            return Err(PairingState::Denied);
+        }
+    }
+
+    let attempts = policy.retries.saturating_add(1);
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            tokio::time::sleep(PAIRING_RETRY_DELAY).await;
+        }
+        set_pairing_state(sender, raw_udid, udid, PairingState::AwaitingTrust).await;
+        match tokio::time::timeout(policy.timeout, pair_via_usb(pairing, handle, udid)).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => {
+                warn!("Pairing attempt {attempt}/{attempts} with {raw_udid} failed: {e}")
+            }
+            Err(_) => warn!(
+                "Trust prompt on {raw_udid} went unanswered for {:?} (attempt {attempt}/{attempts})",
+                policy.timeout
+            ),
+        }
+    }
+    Err(PairingState::Failed)
+}
 
-    let (host_id, system_buid) = pairing_finder
+/// Run the lockdown Pair flow over the USB mux. Blocks while the
+/// device displays the Trust prompt to the user. On success, saves
+/// the pairing record under `canonical_udid` (from [`probe_device`]).
+pub(crate) async fn pair_via_usb(
+    pairing: &UsbPairing,
+    handle: &UsbMuxHandle,
+    canonical_udid: &str,
+) -> Result<(), String> {
+    let stream = handle
+        .connect(LockdownClient::LOCKDOWND_PORT)
+        .await
+        .map_err(|e| format!("usb connect to lockdown: {e:?}"))?;
+
+    let idevice = Idevice::new(Box::new(stream), "netmuxd-pair");
+    let mut lockdown = LockdownClient { idevice };
+
+    let (host_id, system_buid) = pairing
+        .finder
         .get_host_identity()
         .await
         .map_err(|e| format!("read host identity: {e:?}"))?;
@@ -177,21 +298,87 @@ pub(crate) async fn pair_via_usb(
         .pair(host_id, system_buid, None)
         .await
         .map_err(|e| format!("lockdown pair: {e:?}"))?;
-    pairing_file.udid = Some(canonical_udid.clone());
+    pairing_file.udid = Some(canonical_udid.to_string());
 
-    let bytes = pairing_file
+    let mut bytes = pairing_file
         .serialize()
         .map_err(|e| format!("serialize pairing file: {e:?}"))?;
-    let path = std::path::PathBuf::from(pairing_finder.plist_storage())
-        .join(format!("{canonical_udid}.plist"));
-    if let Some(parent) = path.parent() {
-        let _ = tokio::fs::create_dir_all(parent).await;
+
+    // The rest needs a session with the new record. None of it is worth
+    // failing the pairing over.
+    match lockdown.start_session(&pairing_file).await {
+        Ok(_) => {
+            bytes = add_wifi_address(&mut lockdown, bytes, canonical_udid).await;
+            if pairing.enable_wifi {
+                enable_wifi_connections(&mut lockdown, canonical_udid).await;
+            }
+        }
+        Err(e) => warn!(
+            "Could not start a session with {canonical_udid} after pairing ({e:?}); \
+             saving the record without its Wi-Fi address"
+        ),
     }
-    tokio::fs::write(&path, &bytes)
+
+    pairing
+        .finder
+        .save_pairing_record(canonical_udid, &bytes)
         .await
-        .map_err(|e| format!("write {path:?}: {e:?}"))?;
+        .map_err(|e| format!("save pairing record: {e:?}"))?;
+
+    Ok(())
+}
 
-    Ok(canonical_udid)
+/// `record` with the device's `WiFiMACAddress` added, which is what
+/// Bonjour services from iOS < 26.4 are matched on. Unchanged if it already
+/// has one or the device won't say. Needs a lockdown session.
+async fn add_wifi_address(lockdown: &mut LockdownClient, record: Vec<u8>, udid: &str) -> Vec<u8> {
+    let mut dict: plist::Dictionary = match plist::from_bytes(&record) {
+        Ok(d) => d,
+        Err(e) => {
+            warn!("Pairing record for {udid} is not a plist: {e:?}");
+            return record;
+        }
+    };
+    if dict.contains_key("WiFiMACAddress") {
+        return record;
+    }
+    let mac = match lockdown.get_value(Some("WiFiAddress"), None).await {
+        Ok(plist::Value::String(mac)) => mac,
+        Ok(other) => {
+            warn!("WiFiAddress of {udid} is not a string: {other:?}");
+            return record;
+        }
+        Err(e) => {
+            warn!("Could not read WiFiAddress of {udid}: {e:?}");
+            return record;
+        }
+    };
+    info!("Recording Wi-Fi address {mac} for {udid}");
+    dict.insert("WiFiMACAddress".into(), mac.into());
+    let mut out = Vec::new();
+    match plist::to_writer_xml(&mut out, &dict) {
+        Ok(()) => out,
+        Err(e) => {
+            warn!("Failed to serialize pairing record for {udid}: {e:?}");
+            record
+        }
+    }
+}
+
+/// Let the device be reached over Wi-Fi, like ticking "Show this iPhone when
+/// on Wi-Fi" in Finder. Needs a lockdown session.
+async fn enable_wifi_connections(lockdown: &mut LockdownClient, udid: &str) {
+    match lockdown
+        .set_value(
+            "EnableWifiConnections",
+            plist::Value::Boolean(true),
+            Some("com.apple.mobile.wireless_lockdown"),
+        )
+        .await
+    {
+        Ok(()) => info!("Enabled Wi-Fi connections on {udid}"),
+        Err(e) => warn!("Failed to enable Wi-Fi connections on {udid}: {e:?}"),
+    }
 }
 
 pub(crate) async fn resolve_paired_udid(finder: &PairingFileFinder, raw: &str) -> Option<String> {
@@ -209,6 +396,9 @@ pub(crate) async fn resolve_paired_udid(finder: &PairingFileFinder, raw: &str) -
 
 enum RecordCheck {
     Valid,
+    /// The device is running something other than lockdownd, so the record
+    /// can't be checked.
+    Restore(String),
     Stale(String),
     Unknown(String),
 }
@@ -233,9 +423,7 @@ async fn check_pairing_record(
 
     match lockdown.idevice.get_type().await {
         Ok(ty) if ty != "com.apple.mobile.lockdown" => {
-            return RecordCheck::Unknown(format!(
-                "device is running '{ty}' (restore mode); skipping validation"
-            ));
+            return RecordCheck::Restore(ty);
         }
         Ok(_) => {}
         Err(e) => {
@@ -244,7 +432,10 @@ async fn check_pairing_record(
     }
 
     match lockdown.start_session(&pairing_file).await {
-        Ok(_legacy) => RecordCheck::Valid,
+        Ok(_legacy) => {
+            backfill_wifi_address(pairing_finder, &mut lockdown, udid).await;
+            RecordCheck::Valid
+        }
         Err(IdeviceError::InvalidHostID) => {
             RecordCheck::Stale("device does not recognize this host's pairing".to_string())
         }
@@ -255,6 +446,25 @@ async fn check_pairing_record(
     }
 }
 
+/// Records made before netmuxd stored the Wi-Fi address (or by a tool
+/// that doesn't) get it on the device's next USB connection.
+async fn backfill_wifi_address(
+    pairing_finder: &PairingFileFinder,
+    lockdown: &mut LockdownClient,
+    udid: &str,
+) {
+    let record = match pairing_finder.store().get(udid).await {
+        Ok(Some(r)) => r,
+        _ => return,
+    };
+    let enriched = add_wifi_address(lockdown, record.clone(), udid).await;
+    if enriched != record
+        && let Err(e) = pairing_finder.save_pairing_record(udid, &enriched).await
+    {
+        warn!("Failed to save Wi-Fi address for {udid}: {e:?}");
+    }
+}
+
 /// The bits of `ListDevices` info a backend has on hand at connect
 /// time but that `connect_device` only ever threads through unchanged.
 pub(crate) struct DeviceMeta {
@@ -263,29 +473,33 @@ pub(crate) struct DeviceMeta {
     pub speed: u64,
 }
 
-pub(crate) async fn connect_device<K>(
+/// Register a USB device with the manager and return the UDID it's listed
+/// under. A device without a valid pairing record is listed straight away
+/// with a `Status`, like usbmuxd lists it, and paired in the background as
+/// the pairing policy allows.
+pub(crate) async fn connect_device(
     sender: &ManagerSender,
-    pairing_file_finder: &PairingFileFinder,
-    known: &Arc<Mutex<HashMap<K, String>>>,
-    key: K,
+    pairing: &UsbPairing,
     handle: UsbMuxHandle,
     raw_udid: String,
     meta: DeviceMeta,
-) -> String
-where
-    K: Eq + std::hash::Hash + Clone + Send + 'static,
-{
+) -> String {
     let DeviceMeta {
         location_id,
         product_id,
         speed,
     } = meta;
 
+    let pairing_file_finder = &pairing.finder;
     let existing_udid = resolve_paired_udid(pairing_file_finder, &raw_udid).await;
 
-    let validated_udid = match existing_udid {
+    let validated = match existing_udid {
         Some(udid) => match check_pairing_record(pairing_file_finder, &handle, &udid).await {
-            RecordCheck::Valid => Some(udid),
+            RecordCheck::Valid => Some((udid, None)),
+            RecordCheck::Restore(ty) => {
+                info!("{udid} is running '{ty}' (restore mode); listing without validation");
+                Some((udid, Some(UsbStatus::Restore)))
+            }
             RecordCheck::Stale(reason) => {
                 warn!("Pairing record for {udid} is stale ({reason}); removing and re-pairing");
                 if let Err(e) = pairing_file_finder.remove_pairing_record(&udid).await {
@@ -297,59 +511,66 @@ where
                 warn!(
                     "Could not validate pairing record for {udid} ({reason}); trusting it anyway"
                 );
-                Some(udid)
+                Some((udid, None))
             }
         },
         None => None,
     };
 
-    match validated_udid {
-        Some(udid) => {
-            register_with_manager(sender, udid.clone(), handle, location_id, product_id, speed)
-                .await;
-            info!(
-                "Registered USB device {udid} (location_id=0x{location_id:x}, pid=0x{product_id:04x})"
-            );
-            udid
-        }
-        None => {
-            info!(
-                "No valid pairing record for {raw_udid}; starting pair flow (tap Trust on the device when prompted)"
-            );
-            let pairing_finder = pairing_file_finder.clone();
-            let handle_for_pair = handle.clone();
-            let sender_for_pair = sender.clone();
-            let known_for_pair = known.clone();
-            let raw_udid_for_pair = raw_udid.clone();
-            tokio::spawn(async move {
-                match pair_via_usb(&pairing_finder, &handle_for_pair, &raw_udid_for_pair).await {
-                    Ok(udid) => {
-                        info!("Successfully paired {udid}");
-                        {
-                            let mut k = known_for_pair.lock().await;
-                            if k.contains_key(&key) {
-                                k.insert(key, udid.clone());
-                            }
-                        }
-                        register_with_manager(
-                            &sender_for_pair,
-                            udid,
-                            handle_for_pair,
-                            location_id,
-                            product_id,
-                            speed,
-                        )
-                        .await;
-                    }
-                    Err(e) => {
-                        warn!("Pairing failed for {raw_udid_for_pair}: {e:?}");
-                        handle_for_pair.shutdown().await;
-                    }
-                }
-            });
-            raw_udid
-        }
+    let (udid, status) = match validated {
+        Some(found) => found,
+        None => probe_device(&handle, &raw_udid).await,
+    };
+    register_with_manager(
+        sender,
+        udid.clone(),
+        handle.clone(),
+        location_id,
+        product_id,
+        speed,
+        status,
+    )
+    .await;
+    info!(
+        "Registered USB device {udid} (location_id=0x{location_id:x}, pid=0x{product_id:04x}{})",
+        status
+            .map(|s| format!(", {}", s.name()))
+            .unwrap_or_default()
+    );
+    if status != Some(UsbStatus::Unpaired) {
+        return udid;
     }
+
+    info!(
+        "No valid pairing record for {udid}; starting pair flow (tap Trust on the device when prompted)"
+    );
+    let pairing = pairing.clone();
+    let sender = sender.clone();
+    let udid_for_pair = udid.clone();
+    tokio::spawn(async move {
+        // Give up if the device is unplugged; its state has already been
+        // cleared.
+        let result = tokio::select! {
+            r = pair_with_policy(&sender, &pairing, &handle, &raw_udid, &udid_for_pair) => r,
+            _ = handle.closed() => return,
+        };
+        let state = match result {
+            Ok(()) => {
+                info!("Successfully paired {udid_for_pair}");
+                PairingState::Paired
+            }
+            // The device stays listed as unpaired, so a client can still
+            // pair it over Connect.
+            Err(state) => {
+                if state == PairingState::Failed {
+                    warn!("Pairing failed for {udid_for_pair}");
+                }
+                state
+            }
+        };
+        set_pairing_state(&sender, &raw_udid, &udid_for_pair, state).await;
+    });
+    udid
 }
 
 /// Sentinel sent over the device-removal channel for both backends.
@@ -10,6 +10,7 @@
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
@@ -22,16 +23,14 @@ use nusb::io::EndpointWrite;
 use nusb::transfer::{Bulk, ControlIn, ControlType, Direction, In, Out, Recipient};
 use tokio::io::AsyncWrite;
 use tokio::sync::Mutex;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::config::NetmuxdConfig;
 use crate::manager::ManagerSender;
-use crate::pairing_file::PairingFileFinder;
 use crate::usb::mux::{self, UsbMuxHandle};
 
 use super::{
     APPLE_VID, DeviceMeta, INTERFACE_CLASS, INTERFACE_PROTOCOL, INTERFACE_SUBCLASS, PID_RANGE_HIGH,
-    PID_RANGE_LOW, connect_device, send_remove,
+    PID_RANGE_LOW, UsbPairing, connect_device, send_remove,
 };
 
 const READER_BUF: usize = 16384;
@@ -86,13 +85,18 @@ const APPLE_VEND_SET_MODE: u8 = 0x52;
 // available.
 const TARGET_MODE: u16 = 3;
 
-pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
-    let pairing_file_finder = PairingFileFinder::new(&config);
+// Give a device whose mux was reset a moment before claiming it again.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
 
+pub(super) async fn run(sender: ManagerSender, pairing: UsbPairing) {
     // Map nusb DeviceId -> UDID, so we can issue RemoveDevice on
     // disconnect events (which only carry the DeviceId).
     let known: Arc<Mutex<HashMap<DeviceId, String>>> = Arc::new(Mutex::new(HashMap::new()));
 
+    // Devices whose mux was reset by a liveness check. Hotplug won't fire
+    // for them since they never left the bus, so reopen them from here.
+    let (reopen_tx, mut reopen_rx) = mpsc::unbounded_channel::<DeviceId>();
+
     // Start the hotplug stream first so we don't miss events that
     // arrive between the initial enumeration and now.
     let mut watch = match nusb::watch_devices() {
@@ -113,8 +117,9 @@ pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
                 handle_connected(
                     info,
                     sender.clone(),
-                    pairing_file_finder.clone(),
+                    pairing.clone(),
                     known.clone(),
+                    reopen_tx.clone(),
                 )
                 .await;
             }
@@ -122,25 +127,60 @@ pub(super) async fn run(sender: ManagerSender, config: NetmuxdConfig) {
         Err(e) => warn!("USB list_devices failed: {e:?}"),
     }
 
-    while let Some(event) = watch.next().await {
-        match event {
-            HotplugEvent::Connected(info) => {
-                if !is_apple_mux(&info) {
-                    continue;
+    loop {
+        tokio::select! {
+            event = watch.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                match event {
+                    HotplugEvent::Connected(info) => {
+                        if !is_apple_mux(&info) {
+                            continue;
+                        }
+                        handle_connected(
+                            info,
+                            sender.clone(),
+                            pairing.clone(),
+                            known.clone(),
+                            reopen_tx.clone(),
+                        )
+                        .await;
+                    }
+                    HotplugEvent::Disconnected(id) => {
+                        let udid = { known.lock().await.remove(&id) };
+                        if let Some(udid) = udid {
+                            info!("USB device {udid} disconnected");
+                            send_remove(&sender, udid).await;
+                        }
+                    }
                 }
-                handle_connected(
-                    info,
-                    sender.clone(),
-                    pairing_file_finder.clone(),
-                    known.clone(),
-                )
-                .await;
             }
-            HotplugEvent::Disconnected(id) => {
-                let udid = { known.lock().await.remove(&id) };
-                if let Some(udid) = udid {
-                    info!("USB device {udid} disconnected");
-                    send_remove(&sender, udid).await;
+            Some(id) = reopen_rx.recv() => {
+                if known.lock().await.contains_key(&id) {
+                    // Already picked up again through hotplug.
+                    continue;
+                }
+                let info = match nusb::list_devices().await {
+                    Ok(mut iter) => iter.find(|info| info.id() == id),
+                    Err(e) => {
+                        warn!("USB list_devices failed: {e:?}");
+                        None
+                    }
+                };
+                match info {
+                    Some(info) => {
+                        info!("Reopening USB device after a mux reset");
+                        handle_connected(
+                            info,
+                            sender.clone(),
+                            pairing.clone(),
+                            known.clone(),
+                            reopen_tx.clone(),
+                        )
+                        .await;
+                    }
+                    None => debug!("USB device {id:?} left the bus during its mux reset"),
                 }
             }
         }
@@ -184,8 +224,9 @@ fn is_apple_mux(info: &nusb::DeviceInfo) -> bool {
 async fn handle_connected(
     info: nusb::DeviceInfo,
     sender: ManagerSender,
-    pairing_file_finder: PairingFileFinder,
+    pairing: UsbPairing,
     known: Arc<Mutex<HashMap<DeviceId, String>>>,
+    reopen: mpsc::UnboundedSender<DeviceId>,
 ) {
     let id = info.id();
     let serial = info.serial_number().map(|s| {
@@ -316,13 +357,12 @@ async fn handle_connected(
     // We always need the mux task running before we can either pair
     // (which talks to lockdown over the mux) or register the device
     let (exit_tx, exit_rx) = oneshot::channel::<u64>();
-    let handle: UsbMuxHandle = mux::spawn(0, raw_udid.clone(), reader, writer, exit_tx);
+    let mut handle: UsbMuxHandle = mux::spawn(0, raw_udid.clone(), reader, writer, exit_tx);
+    let reset = handle.enable_reset();
 
     let map_udid = connect_device(
         &sender,
-        &pairing_file_finder,
-        &known,
-        id,
+        &pairing,
         handle,
         raw_udid.clone(),
         DeviceMeta {
@@ -347,6 +387,10 @@ async fn handle_connected(
             trace!("USB mux task for {udid} exited");
             send_remove(&sender, udid).await;
         }
+        if reset.load(Ordering::Acquire) {
+            tokio::time::sleep(REOPEN_DELAY).await;
+            let _ = reopen.send(id);
+        }
     });
 }
 
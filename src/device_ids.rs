@@ -0,0 +1,226 @@
+// Jackson Coxson
+//
+// DeviceID allocation. Each (UDID, connection type) keeps the ID it was first
+// given for as long as the daemon runs, so clients that cached an ID from a
+// Listen event can still use it after a replug or a heartbeat blip. With a
+// store file the IDs also survive restarts; it's written from a task of its
+// own so a slow disk doesn't hold up the manager.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use tokio::sync::watch;
+
+type Ids = HashMap<(String, String), u64>;
+
+pub struct DeviceIds {
+    ids: Ids,
+    next: u64,
+    /// Feeds the writer task, if there's a store file.
+    saved: Option<watch::Sender<Ids>>,
+}
+
+impl DeviceIds {
+    /// Start handing out IDs from `base`, picking up any saved in `path`.
+    /// Saved IDs below `base` are dropped, so switching into shim mode keeps
+    /// network IDs clear of the upstream's. Must be called on a Tokio
+    /// runtime if there's a `path`.
+    pub fn new(base: u64, path: Option<PathBuf>) -> Self {
+        let mut ids = HashMap::new();
+        if let Some(path) = &path {
+            match load(path) {
+                Ok(saved) => ids = saved,
+                Err(e) => warn!("Not using saved device IDs from {}: {e}", path.display()),
+            }
+        }
+        ids.retain(|_, id| *id >= base);
+        let next = ids.values().max().map_or(base, |max| max + 1);
+        let saved = path.map(|path| {
+            let (tx, rx) = watch::channel(ids.clone());
+            tokio::spawn(write_loop(path, rx));
+            tx
+        });
+        Self { ids, next, saved }
+    }
+
+    /// The ID for `udid` over `connection_type`, allocating (and saving) one
+    /// the first time the pair is seen.
+    pub fn get(&mut self, udid: &str, connection_type: &str) -> u64 {
+        let key = (udid.to_string(), connection_type.to_string());
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+        let id = self.next;
+        self.next += 1;
+        debug!("Assigning device ID {id} to {udid} over {connection_type}");
+        self.ids.insert(key, id);
+        if let Some(saved) = &self.saved {
+            saved.send_replace(self.ids.clone());
+        }
+        id
+    }
+}
+
+/// Save each new set of IDs. Ones allocated while a save is running are
+/// written together after it.
+async fn write_loop(path: PathBuf, mut rx: watch::Receiver<Ids>) {
+    while rx.changed().await.is_ok() {
+        let ids = rx.borrow_and_update().clone();
+        let target = path.clone();
+        match tokio::task::spawn_blocking(move || save(&target, &ids)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to save device IDs to {}: {e}", path.display()),
+            Err(e) => warn!("Saving device IDs panicked: {e}"),
+        }
+    }
+}
+
+/// The store is `{ "DeviceIDs": { udid: { connection type: id } } }`.
+fn load(path: &Path) -> Result<Ids, String> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let root: plist::Dictionary = plist::from_file(path).map_err(|e| e.to_string())?;
+    let devices = root
+        .get("DeviceIDs")
+        .and_then(|d| d.as_dictionary())
+        .ok_or("missing DeviceIDs dictionary")?;
+    let mut ids = HashMap::new();
+    for (udid, types) in devices {
+        let Some(types) = types.as_dictionary() else {
+            continue;
+        };
+        for (connection_type, id) in types {
+            if let Some(id) = id.as_unsigned_integer() {
+                ids.insert((udid.clone(), connection_type.clone()), id);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+fn save(path: &Path, ids: &Ids) -> std::io::Result<()> {
+    let mut by_udid: HashMap<&str, plist::Dictionary> = HashMap::new();
+    for ((udid, connection_type), id) in ids {
+        by_udid
+            .entry(udid)
+            .or_default()
+            .insert(connection_type.clone(), (*id).into());
+    }
+    let mut devices = plist::Dictionary::new();
+    for (udid, types) in by_udid {
+        devices.insert(udid.to_string(), types.into());
+    }
+    let mut root = plist::Dictionary::new();
+    root.insert("DeviceIDs".into(), devices.into());
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Write then rename, so a crash can't leave a truncated store behind.
+    let tmp = path.with_extension("tmp");
+    plist::to_file_xml(&tmp, &root).map_err(std::io::Error::other)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const BASE: u64 = crate::manager::SHIM_NETWORK_ID_BASE;
+
+    /// A store path in a directory of its own, removed afterwards.
+    struct TempStore(PathBuf);
+
+    impl TempStore {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("netmuxd-ids-test-{}", uuid::Uuid::new_v4())))
+        }
+
+        fn path(&self) -> PathBuf {
+            self.0.join("state").join("device_ids.plist")
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Wait for the writer task to save `count` IDs.
+    async fn saved(path: &Path, count: usize) -> Ids {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(ids) = load(path)
+                    && ids.len() == count
+                {
+                    return ids;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("IDs saved in time")
+    }
+
+    #[test]
+    fn same_device_and_type_keep_their_id() {
+        let mut ids = DeviceIds::new(1, None);
+        assert_eq!(ids.get("a", "USB"), 1);
+        assert_eq!(ids.get("a", "Network"), 2);
+        assert_eq!(ids.get("b", "USB"), 3);
+        assert_eq!(ids.get("a", "USB"), 1);
+        assert_eq!(ids.get("a", "Network"), 2);
+    }
+
+    #[tokio::test]
+    async fn ids_survive_a_restart() {
+        let store = TempStore::new();
+        let mut ids = DeviceIds::new(1, Some(store.path()));
+        assert_eq!(ids.get("a", "USB"), 1);
+        assert_eq!(ids.get("a", "Network"), 2);
+        saved(&store.path(), 2).await;
+        drop(ids);
+
+        let mut ids = DeviceIds::new(1, Some(store.path()));
+        assert_eq!(ids.get("a", "Network"), 2);
+        assert_eq!(ids.get("a", "USB"), 1);
+        assert_eq!(ids.get("b", "USB"), 3);
+    }
+
+    #[tokio::test]
+    async fn saved_ids_below_base_are_dropped() {
+        let store = TempStore::new();
+        let saved_ids = Ids::from([
+            (("a".to_string(), "Network".to_string()), 5),
+            (("b".to_string(), "Network".to_string()), BASE + 7),
+        ]);
+        save(&store.path(), &saved_ids).unwrap();
+
+        let mut ids = DeviceIds::new(BASE, Some(store.path()));
+        assert_eq!(ids.get("b", "Network"), BASE + 7);
+        // Allocated again, above everything that was kept.
+        assert_eq!(ids.get("a", "Network"), BASE + 8);
+    }
+
+    #[tokio::test]
+    async fn corrupt_store_is_ignored() {
+        let store = TempStore::new();
+        std::fs::create_dir_all(store.path().parent().unwrap()).unwrap();
+        std::fs::write(store.path(), "not a plist").unwrap();
+
+        let mut ids = DeviceIds::new(1, Some(store.path()));
+        assert_eq!(ids.get("a", "USB"), 1);
+        // The next save replaces it.
+        assert_eq!(
+            saved(&store.path(), 1).await[&("a".to_string(), "USB".to_string())],
+            1
+        );
+    }
+}
@@ -1,6 +1,9 @@
 // jkcoxson
 
-use std::net::IpAddr;
+use std::{
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+    time::SystemTime,
+};
 
 #[derive(Debug, Clone)]
 pub struct MuxerDevice {
@@ -11,13 +14,174 @@ pub struct MuxerDevice {
     pub serial_number: String,
 
     // Network types
-    pub network_address: Option<IpAddr>,
+    pub network_address: Option<DeviceAddr>,
     pub service_name: Option<String>,
+    pub heartbeat: Option<HeartbeatStatus>,
+    /// lockdownd's port when it isn't the standard one, e.g. a static device
+    /// reached through a port forward.
+    pub lockdown_port: Option<u16>,
 
     // USB types
     pub connection_speed: Option<u64>,
     pub location_id: Option<u64>,
     pub product_id: Option<u64>,
+    pub usb_health: Option<UsbHealth>,
+    /// Why a USB device can't be used normally yet. `None` once it's paired.
+    pub usb_status: Option<UsbStatus>,
+}
+
+/// A network device's IP address, plus the interface it was found on for
+/// IPv6 addresses that need one (link-local). Written `ip` or `ip%scope`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeviceAddr {
+    pub ip: IpAddr,
+    /// Interface index; 0 when the address isn't scoped.
+    pub scope_id: u32,
+}
+
+impl DeviceAddr {
+    pub fn new(ip: IpAddr, scope_id: u32) -> Self {
+        // Only IPv6 has zones.
+        let scope_id = if ip.is_ipv6() { scope_id } else { 0 };
+        Self { ip, scope_id }
+    }
+
+    /// The address to connect to for `port`, keeping the scope.
+    pub fn socket_addr(self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V6(ip) => SocketAddrV6::new(ip, port, 0, self.scope_id).into(),
+            ip => SocketAddr::new(ip, port),
+        }
+    }
+}
+
+impl From<IpAddr> for DeviceAddr {
+    fn from(ip: IpAddr) -> Self {
+        Self { ip, scope_id: 0 }
+    }
+}
+
+impl std::fmt::Display for DeviceAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.scope_id {
+            0 => write!(f, "{}", self.ip),
+            scope => write!(f, "{}%{scope}", self.ip),
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, scope) = match s.split_once('%') {
+            Some((ip, scope)) => (ip, Some(scope)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| format!("bad IP address {ip:?}"))?;
+        let scope_id = match scope {
+            None => 0,
+            Some(_) if ip.is_ipv4() => return Err("IPv4 addresses have no scope".to_string()),
+            Some(scope) => match scope.parse() {
+                Ok(index) => index,
+                Err(_) => {
+                    interface_index(scope).ok_or_else(|| format!("no interface named {scope:?}"))?
+                }
+            },
+        };
+        Ok(Self::new(ip, scope_id))
+    }
+}
+
+/// Index of the interface called `name`.
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> Option<u32> {
+    if name.contains('/') {
+        return None;
+    }
+    std::fs::read_to_string(format!("/sys/class/net/{name}/ifindex"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Interface names can only be resolved on Linux; elsewhere give the index.
+#[cfg(not(target_os = "linux"))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Heartbeat state of a network device, reported under `Heartbeat` in its
+/// properties.
+#[derive(Debug, Clone)]
+pub struct HeartbeatStatus {
+    /// `"Alive"`, or `"Retrying"` while reconnecting after a failure.
+    pub state: &'static str,
+    /// Seconds between Marcos, as last requested by the device.
+    pub interval: u64,
+    pub last_marco: Option<SystemTime>,
+    /// Failures since the last successful exchange.
+    pub failures: u32,
+}
+
+impl From<&HeartbeatStatus> for plist::Dictionary {
+    fn from(status: &HeartbeatStatus) -> Self {
+        let mut p = plist::Dictionary::new();
+        p.insert("State".into(), status.state.into());
+        p.insert("Interval".into(), status.interval.into());
+        if let Some(t) = status.last_marco {
+            p.insert("LastMarco".into(), plist::Value::Date(t.into()));
+        }
+        p.insert("Failures".into(), u64::from(status.failures).into());
+        p
+    }
+}
+
+/// Result of the lockdownd liveness checks on a USB device, reported under
+/// `Liveness` in its properties.
+#[derive(Debug, Clone)]
+pub struct UsbHealth {
+    /// `"Alive"`, `"Degraded"` after a missed check, or `"Unresponsive"`
+    /// once the mux is being reset.
+    pub state: &'static str,
+    /// Checks missed in a row.
+    pub failures: u32,
+    pub last_check: Option<SystemTime>,
+}
+
+impl From<&UsbHealth> for plist::Dictionary {
+    fn from(health: &UsbHealth) -> Self {
+        let mut p = plist::Dictionary::new();
+        p.insert("State".into(), health.state.into());
+        p.insert("Failures".into(), u64::from(health.failures).into());
+        if let Some(t) = health.last_check {
+            p.insert("LastCheck".into(), plist::Value::Date(t.into()));
+        }
+        p
+    }
+}
+
+/// A USB device that's listed before it can be used normally, reported as
+/// `Status` in its properties so clients can pair it themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbStatus {
+    /// No pairing record, and not currently showing a Trust prompt.
+    Unpaired,
+    /// Showing a Trust prompt for netmuxd.
+    Pairing,
+    /// Running something other than lockdownd, e.g. restore mode.
+    Restore,
+}
+
+impl UsbStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unpaired => "Unpaired",
+            Self::Pairing => "Pairing",
+            Self::Restore => "Restore",
+        }
+    }
 }
 
 impl From<&MuxerDevice> for plist::Dictionary {
@@ -52,10 +216,10 @@ impl From<&MuxerDevice> for plist::Dictionary {
                 ));
 
                 let mut data = [0u8; 128];
-                match device
+                let address = device
                     .network_address
-                    .expect("Network device, but no address")
-                {
+                    .expect("Network device, but no address");
+                match address.ip {
                     IpAddr::V4(ip_addr) => {
                         if bsd_sockaddr {
                             data[0] = 0x10; // sa_len = sizeof(sockaddr_in) = 16
@@ -79,10 +243,14 @@ impl From<&MuxerDevice> for plist::Dictionary {
                         }
                         // bytes 2..4 = port, 4..8 = flowinfo, all zero
                         data[8..24].copy_from_slice(&ip_addr.octets());
-                        // bytes 24..28 = scope_id, left zero
+                        // bytes 24..28 = scope_id, in host byte order
+                        data[24..28].copy_from_slice(&address.scope_id.to_ne_bytes());
                     }
                 }
                 p.insert("NetworkAddress".into(), plist::Value::Data(data.to_vec()));
+                if let Some(status) = &device.heartbeat {
+                    p.insert("Heartbeat".into(), plist::Value::Dictionary(status.into()));
+                }
             }
             "USB" => {
                 if let Some(speed) = device.connection_speed {
@@ -96,6 +264,12 @@ impl From<&MuxerDevice> for plist::Dictionary {
                 if let Some(pid) = device.product_id {
                     p.insert("ProductID".into(), pid.into());
                 }
+                if let Some(health) = &device.usb_health {
+                    p.insert("Liveness".into(), plist::Value::Dictionary(health.into()));
+                }
+                if let Some(status) = device.usb_status {
+                    p.insert("Status".into(), status.name().into());
+                }
             }
             _ => {}
         }
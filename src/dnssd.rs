@@ -0,0 +1,800 @@
+// Jackson Coxson
+//
+// Wide-area Bonjour: DNS-SD browsing over unicast DNS, for devices on routed
+// subnets that multicast never reaches. Something has to publish the
+// `_apple-mobdev2._tcp` records into the configured domain (a Bonjour
+// gateway or sleep proxy, or an operator by hand); netmuxd polls the server
+// for them and matches instances to pairing records the same way `mdns`
+// does. The DNS client is a small hand-rolled one: a query per record type
+// over UDP, retried once, with a TCP retry for truncated answers.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    config::NetmuxdConfig,
+    manager::{ManagerRequest, ManagerRequestType, ManagerSender},
+    pairing_file::PairingFileFinder,
+};
+
+const SERVICE_LABELS: [&str; 2] = ["_apple-mobdev2", "_tcp"];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const QUERY_ATTEMPTS: u32 = 2;
+/// UDP payload size advertised through EDNS(0).
+const UDP_PAYLOAD: u16 = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// A domain name as its labels. Instance names routinely contain dots and
+/// spaces, so they're never flattened to text except for logging.
+type Name = Vec<Vec<u8>>;
+
+/// Browse `dnssd_domain` on `dnssd_server` every `dnssd_interval`.
+pub async fn discover(
+    sender: ManagerSender,
+    config: NetmuxdConfig,
+    pairing_file_finder: PairingFileFinder,
+) {
+    let (Some(server), Some(domain)) = (config.dnssd_server, config.dnssd_domain.clone()) else {
+        return;
+    };
+    let service: Name = SERVICE_LABELS
+        .iter()
+        .map(|l| l.as_bytes().to_vec())
+        .chain(
+            domain
+                .split('.')
+                .filter(|l| !l.is_empty())
+                .map(|l| l.as_bytes().to_vec()),
+        )
+        .collect();
+    let service_name = display(&service);
+    info!("Starting DNS-SD discovery for {service_name} on {server}");
+
+    let mut client = Client::new(server);
+
+    // Instance name -> UDID, as of the last successful browse.
+    let mut services: HashMap<Name, String> = HashMap::new();
+    let mut interval = tokio::time::interval(config.dnssd_interval);
+    loop {
+        interval.tick().await;
+        let found = match browse(&mut client, &service, &pairing_file_finder).await {
+            Ok(f) => f,
+            Err(e) => {
+                // Keep what we have; the heartbeat drops devices that are
+                // really gone.
+                warn!("DNS-SD browse of {service_name} failed: {e}");
+                continue;
+            }
+        };
+
+        for (instance, udid) in &services {
+            if found.contains_key(instance) || found.values().any(|(u, _)| u == udid) {
+                continue;
+            }
+            info!("DNS-SD service for {udid} is gone, removing device");
+            if sender
+                .send(ManagerRequest {
+                    request_type: ManagerRequestType::RemoveNetworkService {
+                        udid: udid.clone(),
+                        service_name: service_name.clone(),
+                    },
+                    response: None,
+                })
+                .await
+                .is_err()
+            {
+                debug!("Failed to send device removal to manager, closing");
+                return;
+            }
+        }
+
+        services.clear();
+        for (instance, (udid, addr)) in found {
+            services.insert(instance, udid.clone());
+            if sender
+                .send(ManagerRequest::discovered_device(
+                    udid,
+                    addr.into(),
+                    service_name.clone(),
+                    "Network".to_string(),
+                ))
+                .await
+                .is_err()
+            {
+                debug!("Failed to send discovered device to manager, closing");
+                return;
+            }
+        }
+    }
+}
+
+/// Every instance of `service` that resolves and matches a pairing record,
+/// with its UDID and address.
+async fn browse(
+    client: &mut Client,
+    service: &Name,
+    pairing_file_finder: &PairingFileFinder,
+) -> Result<HashMap<Name, (String, IpAddr)>, String> {
+    // Servers often put the SRV/TXT/A records in the additional section, so
+    // every answer is kept around to save queries.
+    let mut cache = Vec::new();
+    let instances: Vec<Name> = lookup(client, &mut cache, service, TYPE_PTR)
+        .await?
+        .into_iter()
+        .filter_map(|d| match d {
+            RData::Ptr(n) => Some(n),
+            _ => None,
+        })
+        .collect();
+    debug!("DNS-SD found {} instance(s)", instances.len());
+
+    let mut found = HashMap::new();
+    for instance in instances {
+        let (txt, addrs) = match resolve(client, &mut cache, &instance).await {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(
+                    "Failed to resolve DNS-SD instance {}: {e}",
+                    display(&instance)
+                );
+                continue;
+            }
+        };
+        // Prefer IPv4, like mDNS.
+        let Some(addr) = addrs
+            .iter()
+            .find(|a| a.is_ipv4())
+            .or_else(|| addrs.first())
+            .copied()
+        else {
+            warn!(
+                "DNS-SD instance has no usable address: {}",
+                display(&instance)
+            );
+            continue;
+        };
+
+        let mut identifier = None;
+        let mut auth_tags: Vec<&[u8]> = Vec::new();
+        for entry in &txt {
+            let (key, value) = match entry.iter().position(|b| *b == b'=') {
+                Some(i) => (&entry[..i], &entry[i + 1..]),
+                None => (&entry[..], &[][..]),
+            };
+            if key == b"identifier" {
+                identifier = Some(value);
+            } else if key == b"authTag" || key.starts_with(b"authTag#") {
+                auth_tags.push(value);
+            }
+        }
+
+        let mut udid = None;
+        if let Some(ident) = identifier
+            && !auth_tags.is_empty()
+        {
+            udid = pairing_file_finder
+                .find_udid_from_txt(ident, &auth_tags)
+                .await;
+        }
+        // Older iOS: the instance name is `<MAC>@<id>`.
+        let label = instance
+            .first()
+            .map(|l| String::from_utf8_lossy(l).into_owned())
+            .unwrap_or_default();
+        if udid.is_none()
+            && let Some((mac_addr, _)) = label.split_once('@')
+            && let Ok(u) = pairing_file_finder
+                .get_udid_from_mac(mac_addr.to_string())
+                .await
+        {
+            udid = Some(u);
+        }
+
+        match udid {
+            Some(udid) => {
+                found.insert(instance, (udid, addr));
+            }
+            None => debug!(
+                "No paired device matched DNS-SD instance {}",
+                display(&instance)
+            ),
+        }
+    }
+    Ok(found)
+}
+
+/// The TXT strings and addresses of an instance.
+async fn resolve(
+    client: &mut Client,
+    cache: &mut Vec<Record>,
+    instance: &Name,
+) -> Result<(Vec<Vec<u8>>, Vec<IpAddr>), String> {
+    let target = lookup(client, cache, instance, TYPE_SRV)
+        .await?
+        .into_iter()
+        .find_map(|d| match d {
+            RData::Srv { target } => Some(target),
+            _ => None,
+        })
+        .ok_or("no SRV record")?;
+
+    // A missing TXT record just means matching falls back to the MAC.
+    let txt = lookup(client, cache, instance, TYPE_TXT)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|d| match d {
+            RData::Txt(strings) => Some(strings),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let mut addrs = Vec::new();
+    for rtype in [TYPE_A, TYPE_AAAA] {
+        match lookup(client, cache, &target, rtype).await {
+            Ok(data) => addrs.extend(data.into_iter().filter_map(|d| match d {
+                RData::A(a) => Some(IpAddr::V4(a)),
+                RData::Aaaa(a) => Some(IpAddr::V6(a)),
+                _ => None,
+            })),
+            Err(e) => debug!("Address lookup for {} failed: {e}", display(&target)),
+        }
+    }
+    Ok((txt, addrs))
+}
+
+/// Records of `rtype` for `name`, from `cache` if an earlier answer carried
+/// them, else from the server.
+async fn lookup(
+    client: &mut Client,
+    cache: &mut Vec<Record>,
+    name: &Name,
+    rtype: u16,
+) -> Result<Vec<RData>, String> {
+    let cached = |cache: &[Record]| -> Vec<RData> {
+        cache
+            .iter()
+            .filter(|r| r.data.rtype() == rtype && same_name(&r.name, name))
+            .map(|r| r.data.clone())
+            .collect()
+    };
+    let hits = cached(cache);
+    if !hits.is_empty() {
+        return Ok(hits);
+    }
+    cache.extend(client.query(name, rtype).await?);
+    Ok(cached(cache))
+}
+
+struct Client {
+    server: SocketAddr,
+    next_id: u16,
+}
+
+impl Client {
+    fn new(server: SocketAddr) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        Self {
+            server,
+            next_id: (seed ^ std::process::id()) as u16,
+        }
+    }
+
+    /// Ask the server for `rtype` records of `name`. Returns every record
+    /// in the answer, including the authority and additional sections.
+    async fn query(&mut self, name: &Name, rtype: u16) -> Result<Vec<Record>, String> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        let packet = encode_query(id, name, rtype)?;
+
+        let mut error = String::new();
+        for _ in 0..QUERY_ATTEMPTS {
+            let message = match tokio::time::timeout(QUERY_TIMEOUT, self.udp(&packet, id)).await {
+                Ok(Ok(m)) if m.truncated => {
+                    match tokio::time::timeout(QUERY_TIMEOUT, self.tcp(&packet, id)).await {
+                        Ok(r) => r?,
+                        Err(_) => return Err("TCP query timed out".to_string()),
+                    }
+                }
+                Ok(Ok(m)) => m,
+                Ok(Err(e)) => {
+                    error = e;
+                    continue;
+                }
+                Err(_) => {
+                    error = "timed out".to_string();
+                    continue;
+                }
+            };
+            return match message.rcode {
+                0 => Ok(message.records),
+                // Nothing registered under that name.
+                RCODE_NXDOMAIN => Ok(Vec::new()),
+                rcode => Err(format!("server answered with rcode {rcode}")),
+            };
+        }
+        Err(error)
+    }
+
+    async fn udp(&self, packet: &[u8], id: u16) -> Result<Message, String> {
+        let bind: SocketAddr = match self.server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind)
+            .await
+            .map_err(|e| format!("bind: {e}"))?;
+        socket
+            .connect(self.server)
+            .await
+            .map_err(|e| format!("connect: {e}"))?;
+        socket
+            .send(packet)
+            .await
+            .map_err(|e| format!("send: {e}"))?;
+        let mut buf = vec![0; UDP_PAYLOAD as usize];
+        loop {
+            let len = socket
+                .recv(&mut buf)
+                .await
+                .map_err(|e| format!("recv: {e}"))?;
+            match decode(&buf[..len]) {
+                Ok(m) if m.id == id => return Ok(m),
+                Ok(_) => debug!("Ignoring DNS answer with a stale ID"),
+                Err(e) => debug!("Ignoring malformed DNS answer: {e}"),
+            }
+        }
+    }
+
+    async fn tcp(&self, packet: &[u8], id: u16) -> Result<Message, String> {
+        let mut stream = TcpStream::connect(self.server)
+            .await
+            .map_err(|e| format!("TCP connect: {e}"))?;
+        let mut framed = (packet.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(packet);
+        stream
+            .write_all(&framed)
+            .await
+            .map_err(|e| format!("TCP send: {e}"))?;
+        let len = stream
+            .read_u16()
+            .await
+            .map_err(|e| format!("TCP recv: {e}"))?;
+        let mut buf = vec![0; len as usize];
+        stream
+            .read_exact(&mut buf)
+            .await
+            .map_err(|e| format!("TCP recv: {e}"))?;
+        let message = decode(&buf)?;
+        if message.id != id {
+            return Err("TCP answer has the wrong ID".to_string());
+        }
+        Ok(message)
+    }
+}
+
+struct Message {
+    id: u16,
+    truncated: bool,
+    rcode: u8,
+    records: Vec<Record>,
+}
+
+struct Record {
+    name: Name,
+    data: RData,
+}
+
+#[derive(Debug, Clone)]
+enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(Name),
+    Srv { target: Name },
+    Txt(Vec<Vec<u8>>),
+    Other(u16),
+}
+
+impl RData {
+    fn rtype(&self) -> u16 {
+        match self {
+            Self::A(_) => TYPE_A,
+            Self::Aaaa(_) => TYPE_AAAA,
+            Self::Ptr(_) => TYPE_PTR,
+            Self::Srv { .. } => TYPE_SRV,
+            Self::Txt(_) => TYPE_TXT,
+            Self::Other(t) => *t,
+        }
+    }
+}
+
+fn encode_query(id: u16, name: &Name, rtype: u16) -> Result<Vec<u8>, String> {
+    let mut p = Vec::with_capacity(64);
+    p.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired.
+    p.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no answers or authority, one additional (the OPT).
+    for count in [1u16, 0, 0, 1] {
+        p.extend_from_slice(&count.to_be_bytes());
+    }
+    for label in name {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("bad label in {}", display(name)));
+        }
+        p.push(label.len() as u8);
+        p.extend_from_slice(label);
+    }
+    p.push(0);
+    p.extend_from_slice(&rtype.to_be_bytes());
+    p.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    // EDNS(0), so answers with a few TXT records don't get truncated.
+    p.push(0);
+    p.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    p.extend_from_slice(&UDP_PAYLOAD.to_be_bytes());
+    p.extend_from_slice(&0u32.to_be_bytes());
+    p.extend_from_slice(&0u16.to_be_bytes());
+    Ok(p)
+}
+
+fn decode(buf: &[u8]) -> Result<Message, String> {
+    let mut r = Reader { buf, pos: 0 };
+    let id = r.u16()?;
+    let flags = r.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err("not a response".to_string());
+    }
+    let questions = r.u16()?;
+    let records = r.u16()? as usize + r.u16()? as usize + r.u16()? as usize;
+    for _ in 0..questions {
+        r.name()?;
+        r.bytes(4)?;
+    }
+
+    let mut out = Vec::with_capacity(records);
+    for _ in 0..records {
+        let name = r.name()?;
+        let rtype = r.u16()?;
+        let _class = r.u16()?;
+        let _ttl = r.bytes(4)?;
+        let len = r.u16()? as usize;
+        let end = r.pos + len;
+        if end > buf.len() {
+            return Err("record runs past the end of the message".to_string());
+        }
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let b = r.bytes(4)?;
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_AAAA if len == 16 => {
+                let b: [u8; 16] = r.bytes(16)?.try_into().map_err(|_| TRUNCATED)?;
+                RData::Aaaa(Ipv6Addr::from(b))
+            }
+            TYPE_PTR => RData::Ptr(r.name()?),
+            TYPE_SRV => {
+                // Priority, weight, and port; the port is the service's, not
+                // lockdownd's, so none of them matter here.
+                r.bytes(6)?;
+                RData::Srv { target: r.name()? }
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while r.pos < end {
+                    let n = r.u8()? as usize;
+                    strings.push(r.bytes(n)?.to_vec());
+                }
+                RData::Txt(strings)
+            }
+            other => RData::Other(other),
+        };
+        r.pos = end;
+        out.push(Record { name, data });
+    }
+
+    Ok(Message {
+        id,
+        truncated: flags & 0x0200 != 0,
+        rcode: (flags & 0x000f) as u8,
+        records: out,
+    })
+}
+
+const TRUNCATED: &str = "message is truncated";
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| TRUNCATED.to_string())?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A possibly compressed name.
+    fn name(&mut self) -> Result<Name, String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        // Where reading continues once the name is done, if it jumped.
+        let mut resume = None;
+        let mut jumps = 0;
+        loop {
+            let len = *self.buf.get(pos).ok_or(TRUNCATED)? as usize;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self.buf.get(pos + 1..pos + 1 + len).ok_or(TRUNCATED)?;
+                    labels.push(label.to_vec());
+                    pos += 1 + len;
+                }
+                0xc0 => {
+                    let low = *self.buf.get(pos + 1).ok_or(TRUNCATED)? as usize;
+                    resume.get_or_insert(pos + 2);
+                    jumps += 1;
+                    if jumps > 32 {
+                        return Err("name compression loop".to_string());
+                    }
+                    pos = ((len & 0x3f) << 8) | low;
+                }
+                _ => return Err("unsupported label type".to_string()),
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Ok(labels)
+    }
+}
+
+fn same_name(a: &Name, b: &Name) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.eq_ignore_ascii_case(y))
+}
+
+/// `name` as text, for logs.
+fn display(name: &Name) -> String {
+    name.iter()
+        .map(|l| String::from_utf8_lossy(l).replace('.', "\\."))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::pairing_store::{MemoryStore, PairingStore};
+
+    const SERVICE: &str = "_apple-mobdev2._tcp.example.com";
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+    const UDID: &str = "00008030-001A2B3C4D5E6F70";
+
+    fn name(text: &str) -> Name {
+        text.split('.').map(|l| l.as_bytes().to_vec()).collect()
+    }
+
+    /// The PTR, SRV, TXT and A records a gateway publishes for a device.
+    fn instance(label: &str, host: &str, addr: Ipv4Addr) -> Vec<(Name, RData)> {
+        let instance = name(&format!("{label}.{SERVICE}"));
+        vec![
+            (name(SERVICE), RData::Ptr(instance.clone())),
+            (instance.clone(), RData::Srv { target: name(host) }),
+            (instance, RData::Txt(vec![b"rpVr=600.1".to_vec()])),
+            (name(host), RData::A(addr)),
+        ]
+    }
+
+    fn encode_name(out: &mut Vec<u8>, name: &Name) {
+        for label in name {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label);
+        }
+        out.push(0);
+    }
+
+    /// The stand-in's answer to `query`. Names it has no records for are
+    /// NXDOMAIN; `truncate` sets TC and leaves the answer out.
+    fn answer(query: &[u8], zone: &[(Name, RData)], truncate: bool) -> Vec<u8> {
+        let mut r = Reader {
+            buf: query,
+            pos: 12,
+        };
+        let qname = r.name().unwrap();
+        let qtype = r.u16().unwrap();
+        let question = &query[12..r.pos + 2];
+
+        let known = zone.iter().any(|(n, _)| same_name(n, &qname));
+        let records: Vec<&RData> = zone
+            .iter()
+            .filter(|(n, d)| !truncate && d.rtype() == qtype && same_name(n, &qname))
+            .map(|(_, d)| d)
+            .collect();
+        let mut flags = 0x8180u16;
+        if truncate {
+            flags |= 0x0200;
+        } else if !known {
+            flags |= RCODE_NXDOMAIN as u16;
+        }
+
+        let mut p = query[..2].to_vec();
+        p.extend_from_slice(&flags.to_be_bytes());
+        for count in [1u16, records.len() as u16, 0, 0] {
+            p.extend_from_slice(&count.to_be_bytes());
+        }
+        p.extend_from_slice(question);
+        for data in records {
+            // Compressed: the question's name.
+            p.extend_from_slice(&[0xc0, 12]);
+            p.extend_from_slice(&data.rtype().to_be_bytes());
+            p.extend_from_slice(&CLASS_IN.to_be_bytes());
+            p.extend_from_slice(&120u32.to_be_bytes());
+            let mut rdata = Vec::new();
+            match data {
+                RData::A(a) => rdata.extend_from_slice(&a.octets()),
+                RData::Aaaa(a) => rdata.extend_from_slice(&a.octets()),
+                RData::Ptr(n) => encode_name(&mut rdata, n),
+                RData::Srv { target } => {
+                    rdata.extend_from_slice(&[0, 0, 0, 0]);
+                    rdata.extend_from_slice(&32498u16.to_be_bytes());
+                    encode_name(&mut rdata, target);
+                }
+                RData::Txt(strings) => {
+                    for s in strings {
+                        rdata.push(s.len() as u8);
+                        rdata.extend_from_slice(s);
+                    }
+                }
+                RData::Other(_) => {}
+            }
+            p.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            p.extend_from_slice(&rdata);
+        }
+        p
+    }
+
+    /// Serve `zone` on a local port over UDP and TCP. With `truncate`, every
+    /// UDP answer is truncated, so the client has to retry over TCP.
+    async fn serve(zone: Vec<(Name, RData)>, truncate: bool) -> SocketAddr {
+        let zone = Arc::new(zone);
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(addr).await.unwrap();
+
+        let udp_zone = zone.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = udp.recv_from(&mut buf).await {
+                let reply = answer(&buf[..len], &udp_zone, truncate);
+                let _ = udp.send_to(&reply, peer).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = tcp.accept().await {
+                let len = stream.read_u16().await.unwrap();
+                let mut query = vec![0; len as usize];
+                stream.read_exact(&mut query).await.unwrap();
+                let reply = answer(&query, &zone, false);
+                let mut framed = (reply.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&reply);
+                stream.write_all(&framed).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// A finder holding one record, matched by its Wi-Fi address.
+    async fn finder() -> PairingFileFinder {
+        let store = Arc::new(MemoryStore::new());
+        let mut record = plist::Dictionary::new();
+        record.insert("WiFiMACAddress".into(), MAC.into());
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, &record).unwrap();
+        store.save(UDID, &bytes).await.unwrap();
+        PairingFileFinder::new(store)
+    }
+
+    fn paired_and_unpaired() -> Vec<(Name, RData)> {
+        let mut zone = instance(
+            &format!("{MAC}@fe80::1"),
+            "iPhone.example.com",
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        zone.extend(instance(
+            "11:22:33:44:55:66@fe80::2",
+            "iPad.example.com",
+            Ipv4Addr::new(192, 168, 1, 21),
+        ));
+        zone
+    }
+
+    #[tokio::test]
+    async fn browse_resolves_paired_instances() {
+        let server = serve(paired_and_unpaired(), false).await;
+        let found = browse(&mut Client::new(server), &name(SERVICE), &finder().await)
+            .await
+            .unwrap();
+
+        // The iPad has no pairing record, so it's left out.
+        assert_eq!(found.len(), 1);
+        let instance = name(&format!("{MAC}@fe80::1.{SERVICE}"));
+        let (udid, addr) = &found[&instance];
+        assert_eq!(udid, UDID);
+        assert_eq!(*addr, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)));
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_retried_over_tcp() {
+        let server = serve(paired_and_unpaired(), true).await;
+        let found = browse(&mut Client::new(server), &name(SERVICE), &finder().await)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_domain_browses_to_nothing() {
+        let server = serve(Vec::new(), false).await;
+        let found = browse(&mut Client::new(server), &name(SERVICE), &finder().await)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_errors_fail_the_browse() {
+        // A stand-in that answers everything with SERVFAIL.
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut reply = answer(&buf[..len], &[], false);
+                reply[3] = (reply[3] & 0xf0) | 2;
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+        let err = browse(&mut Client::new(server), &name(SERVICE), &finder().await)
+            .await
+            .unwrap_err();
+        assert!(err.contains("rcode 2"), "{err}");
+    }
+}
@@ -2,148 +2,265 @@
 
 use idevice::{Idevice, heartbeat::HeartbeatClient, lockdown::LockdownClient};
 use log::{debug, info, warn};
-use std::net::SocketAddr;
-use tokio::sync::oneshot::Sender;
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::oneshot, task::AbortHandle};
 
 use crate::{
-    devices::MuxerDevice,
-    manager::{ManagerRequest, ManagerSender},
+    devices::{HeartbeatStatus, MuxerDevice},
+    manager::{ManagerRequest, ManagerRequestType, ManagerSender},
 };
 
-pub async fn heartbeat(
-    device: MuxerDevice,
-    response: Option<Sender<plist::Dictionary>>,
+/// Longest wait between reconnect attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How hard to try before declaring a network device gone.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatRetry {
+    /// Reconnect attempts after a failed Marco/Polo exchange.
+    pub attempts: u32,
+    /// Wait before the first attempt; doubles after each one.
+    pub backoff: Duration,
+}
+
+/// Spawn `device`'s heartbeat. Its reports to the manager carry
+/// `generation`, so they're ignored once this registration is replaced; the
+/// returned handle stops it when the entry is dropped.
+pub fn heartbeat(
+    mut device: MuxerDevice,
+    generation: u64,
     pairing_file: idevice::pairing_file::PairingFile,
     sender: ManagerSender,
-) {
+    retry: HeartbeatRetry,
+) -> AbortHandle {
     debug!("Spawning heartbeat for {device:?}");
     tokio::spawn(async move {
         let udid = device.serial_number.clone();
-        let socket = SocketAddr::new(
-            device.network_address.unwrap(),
-            LockdownClient::LOCKDOWND_PORT,
+        let device_id = device.device_id;
+        let address = device.network_address.unwrap().socket_addr(
+            device
+                .lockdown_port
+                .unwrap_or(LockdownClient::LOCKDOWND_PORT),
         );
 
-        let socket = match tokio::net::TcpStream::connect(socket).await {
-            Ok(s) => s,
+        let mut heartbeat_client = match connect(address, &pairing_file).await {
+            Ok(c) => c,
             Err(e) => {
-                warn!("Failed to connect to lockdown port: {e:?}");
-                if let Some(response) = response {
-                    response
-                        .send(plist_macro::plist!(dict {
-                        "Result": 0,
-                        }))
-                        .ok();
-                }
-                return;
-            }
-        };
-
-        let socket = Box::new(socket);
-        let idevice = Idevice::new(socket, "netmuxd");
-
-        let mut lockdown_client = LockdownClient { idevice };
-        if let Err(e) = lockdown_client.start_session(&pairing_file).await {
-            warn!("Failed to start lockdown session: {e:?}");
-            if let Some(response) = response {
-                response
-                    .send(plist_macro::plist!(dict {
-                    "Result": 0,
-                    }))
+                warn!("{e}");
+                // Clear the pending registration and answer its offers.
+                sender
+                    .send(ManagerRequest::heartbeat_failed(device_id, generation))
+                    .await
                     .ok();
-            }
-            return;
-        }
-
-        let (port, _) = match lockdown_client
-            .start_service("com.apple.mobile.heartbeat")
-            .await
-        {
-            Ok(p) => p,
-            Err(e) => {
-                warn!("Failed to start heartbeat service: {e:?}");
-                if let Some(response) = response {
-                    response
-                        .send(plist_macro::plist!(dict {
-                        "Result": 0,
-                        }))
-                        .ok();
-                }
                 return;
             }
         };
 
-        let socket = SocketAddr::new(device.network_address.unwrap(), port);
-        let socket = match tokio::net::TcpStream::connect(socket).await {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("Failed to connect to heartbeat port: {e:?}");
-                if let Some(response) = response {
-                    response
-                        .send(plist_macro::plist!(dict {
-                        "Result": 0,
-                        }))
-                        .ok();
-                }
-                return;
-            }
+        let mut status = HeartbeatStatus {
+            state: "Alive",
+            interval: 10,
+            last_marco: None,
+            failures: 0,
         };
-
-        let socket = Box::new(socket);
-        let mut idevice = Idevice::new(socket, "heartbeat_client");
-
-        if let Err(e) = idevice.start_session(&pairing_file, false).await {
-            warn!("Failed to wrap heartbeat client in TLS: {e:?}");
-            if let Some(response) = response {
-                response
-                    .send(plist_macro::plist!(dict {
-                    "Result": 0,
-                    }))
-                    .ok();
-            }
-            return;
-        }
-
-        let mut interval = 10;
-        let mut heartbeat_client = HeartbeatClient { idevice };
+        device.heartbeat = Some(status.clone());
 
         // now that we successfully created the heartbeat client, we can send the deferred add
         sender
             .send(ManagerRequest {
-                request_type: crate::manager::ManagerRequestType::DeferredMuxerAdd {
-                    device,
-                    response,
-                },
+                request_type: ManagerRequestType::DeferredMuxerAdd { device, generation },
                 response: None,
             })
             .await
             .ok();
 
         loop {
-            match heartbeat_client.get_marco(interval + 5).await {
+            let exchange = match heartbeat_client.get_marco(status.interval + 5).await {
                 Ok(i) => {
-                    interval = i;
-                }
-                Err(e) => {
-                    info!("Heartbeat recv failed: {:?}", e);
-                    sender
-                        .send(ManagerRequest::heartbeat_failed(udid.clone()))
+                    status.interval = i;
+                    status.last_marco = Some(SystemTime::now());
+                    heartbeat_client
+                        .send_polo()
                         .await
-                        .ok();
+                        .map_err(|e| format!("Heartbeat send failed: {e:?}"))
+                }
+                Err(e) => Err(format!("Heartbeat recv failed: {e:?}")),
+            };
+            if let Err(e) = exchange {
+                info!("{e}");
+                status.state = "Retrying";
+                status.failures += 1;
+                if !update(&sender, device_id, generation, &status).await {
                     break;
                 }
+                match reconnect(
+                    address,
+                    &pairing_file,
+                    &sender,
+                    device_id,
+                    generation,
+                    &mut status,
+                    retry,
+                )
+                .await
+                {
+                    Some(c) => heartbeat_client = c,
+                    None => {
+                        info!(
+                            "Heartbeat for {udid} gave up after {} failures",
+                            status.failures
+                        );
+                        sender
+                            .send(ManagerRequest::heartbeat_failed(device_id, generation))
+                            .await
+                            .ok();
+                        break;
+                    }
+                }
+                status.state = "Alive";
+                status.failures = 0;
             }
-            if let Err(e) = heartbeat_client.send_polo().await {
-                info!("Heartbeat send failed: {:?}", e);
-                sender
-                    .send(ManagerRequest::heartbeat_failed(udid.clone()))
-                    .await
-                    .ok();
+            if !update(&sender, device_id, generation, &status).await {
+                debug!("Device {device_id} is gone, stopping its heartbeat");
                 break;
             }
-            if sender.is_disconnected() {
-                break;
+        }
+    })
+    .abort_handle()
+}
+
+/// Re-probe the device's last known address with backoff. `None` once every
+/// attempt has failed or the device was removed in the meantime.
+async fn reconnect(
+    address: SocketAddr,
+    pairing_file: &idevice::pairing_file::PairingFile,
+    sender: &ManagerSender,
+    device_id: u64,
+    generation: u64,
+    status: &mut HeartbeatStatus,
+    retry: HeartbeatRetry,
+) -> Option<HeartbeatClient> {
+    for (attempt, backoff) in (1..=retry.attempts).zip(backoffs(retry.backoff)) {
+        tokio::time::sleep(backoff).await;
+        match connect(address, pairing_file).await {
+            Ok(c) => {
+                info!("Heartbeat for device {device_id} reconnected on attempt {attempt}");
+                return Some(c);
+            }
+            Err(e) => {
+                info!(
+                    "Heartbeat reconnect {attempt}/{} failed: {e}",
+                    retry.attempts
+                );
+                status.failures += 1;
+                if !update(sender, device_id, generation, status).await {
+                    return None;
+                }
             }
         }
-    });
+    }
+    None
+}
+
+/// Waits before each reconnect attempt: `first`, then doubling up to
+/// [`MAX_BACKOFF`].
+fn backoffs(first: Duration) -> impl Iterator<Item = Duration> {
+    std::iter::successors(Some(first), |b| Some((*b * 2).min(MAX_BACKOFF)))
+}
+
+/// Report `status` to the manager. Returns `false` if the device is no longer
+/// listed under this heartbeat (or the manager is gone), meaning it should
+/// stop.
+async fn update(
+    sender: &ManagerSender,
+    device_id: u64,
+    generation: u64,
+    status: &HeartbeatStatus,
+) -> bool {
+    let (tx, rx) = oneshot::channel();
+    if sender
+        .send(ManagerRequest {
+            request_type: ManagerRequestType::HeartbeatUpdate {
+                device_id,
+                generation,
+                status: status.clone(),
+                known: tx,
+            },
+            response: None,
+        })
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    rx.await.unwrap_or(false)
+}
+
+/// Start a lockdown session at `address` and open the heartbeat service.
+async fn connect(
+    address: SocketAddr,
+    pairing_file: &idevice::pairing_file::PairingFile,
+) -> Result<HeartbeatClient, String> {
+    let socket = tokio::net::TcpStream::connect(address)
+        .await
+        .map_err(|e| format!("Failed to connect to lockdown port: {e:?}"))?;
+
+    let socket = Box::new(socket);
+    let idevice = Idevice::new(socket, "netmuxd");
+
+    let mut lockdown_client = LockdownClient { idevice };
+    lockdown_client
+        .start_session(pairing_file)
+        .await
+        .map_err(|e| format!("Failed to start lockdown session: {e:?}"))?;
+
+    let (port, _) = lockdown_client
+        .start_service("com.apple.mobile.heartbeat")
+        .await
+        .map_err(|e| format!("Failed to start heartbeat service: {e:?}"))?;
+
+    // Same host (and IPv6 scope) as lockdownd.
+    let mut socket = address;
+    socket.set_port(port);
+    let socket = tokio::net::TcpStream::connect(socket)
+        .await
+        .map_err(|e| format!("Failed to connect to heartbeat port: {e:?}"))?;
+
+    let socket = Box::new(socket);
+    let mut idevice = Idevice::new(socket, "heartbeat_client");
+    idevice
+        .start_session(pairing_file, false)
+        .await
+        .map_err(|e| format!("Failed to wrap heartbeat client in TLS: {e:?}"))?;
+
+    Ok(HeartbeatClient { idevice })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let secs: Vec<u64> = backoffs(Duration::from_secs(1))
+            .take(8)
+            .map(|b| b.as_secs())
+            .collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn backoff_keeps_a_short_first_wait() {
+        let waits: Vec<Duration> = backoffs(Duration::from_millis(300)).take(3).collect();
+        assert_eq!(
+            waits,
+            [
+                Duration::from_millis(300),
+                Duration::from_millis(600),
+                Duration::from_millis(1200)
+            ]
+        );
+        assert!(backoffs(Duration::ZERO).take(4).all(|b| b.is_zero()));
+    }
 }
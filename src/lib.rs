@@ -13,7 +13,9 @@
 //!
 //! The bundled `netmuxd`, `passthrough`, and `add_device` binaries
 //! consume this library plus the native-only [`daemon`] orchestration
-//! (USB enumeration / hotplug / pair / manager).
+//! (USB enumeration / hotplug / pair / manager). [`server`] wraps all of
+//! that behind an embeddable `NetmuxdServer`, so the daemon can also run
+//! in-process.
 
 pub mod devices;
 pub mod usb;
@@ -23,15 +25,39 @@ pub mod heartbeat;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod manager;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod access;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod config;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod daemon;
 #[cfg(not(target_arch = "wasm32"))]
+pub mod device_ids;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dnssd;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod liveness;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod mdns;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod pairing_file;
 #[cfg(not(target_arch = "wasm32"))]
+pub mod pairing_policy;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pairing_store;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pairing_watch;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod policy;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod static_devices;
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+pub mod systemd;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tls;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod upstream;
 
 #[cfg(all(target_os = "windows", not(target_arch = "wasm32")))]
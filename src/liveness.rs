@@ -0,0 +1,153 @@
+// Jackson Coxson
+//
+// Liveness checks for USB devices. The mux task only notices a device that
+// drops off the bus; one whose mux is still up but whose lockdownd has
+// wedged would stay listed forever. This periodically asks lockdownd for
+// QueryType and, after enough misses, resets the mux so the backend reopens
+// and re-registers the device.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use idevice::{Idevice, lockdown::LockdownClient};
+use log::{debug, info, warn};
+use tokio::sync::oneshot;
+
+use crate::{
+    devices::UsbHealth,
+    manager::{ManagerRequest, ManagerRequestType, ManagerSender},
+    usb::mux::WeakUsbMuxHandle,
+};
+
+/// When and how hard to check USB devices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbLiveness {
+    /// Time between checks. `None` turns checks off.
+    pub interval: Option<Duration>,
+    /// How long lockdownd gets to answer.
+    pub timeout: Duration,
+    /// Missed checks in a row before the mux is reset.
+    pub failures: u32,
+    /// Per-UDID interval overrides; `None` turns checks off for the device.
+    pub devices: HashMap<String, Option<Duration>>,
+}
+
+impl Default for UsbLiveness {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            timeout: Duration::from_secs(5),
+            failures: 3,
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl UsbLiveness {
+    pub fn interval_for(&self, udid: &str) -> Option<Duration> {
+        match self.devices.get(udid) {
+            Some(interval) => *interval,
+            None => self.interval,
+        }
+    }
+}
+
+/// Check `udid` every `interval` until its mux task goes away. Skipped if
+/// the device's backend can't reopen it after a reset.
+pub fn spawn(
+    handle: WeakUsbMuxHandle,
+    device_id: u64,
+    udid: String,
+    interval: Duration,
+    policy: &UsbLiveness,
+    sender: ManagerSender,
+) {
+    if !handle.can_reset() {
+        debug!("Not checking liveness of USB device {udid}: its backend can't reopen it");
+        return;
+    }
+    let timeout = policy.timeout;
+    let threshold = policy.failures.max(1);
+    debug!("Checking liveness of USB device {udid} every {interval:?}");
+    tokio::spawn(async move {
+        let mut health = UsbHealth {
+            state: "Alive",
+            failures: 0,
+            last_check: None,
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(mux) = handle.upgrade() else {
+                return;
+            };
+            let result = tokio::time::timeout(timeout, query_type(&mux)).await;
+            health.last_check = Some(SystemTime::now());
+            match result {
+                Ok(Ok(_)) => {
+                    health.state = "Alive";
+                    health.failures = 0;
+                }
+                Ok(Err(e)) => {
+                    info!("Liveness check for USB device {udid} failed: {e}");
+                    health.failures += 1;
+                }
+                Err(_) => {
+                    info!("Liveness check for USB device {udid} timed out");
+                    health.failures += 1;
+                }
+            }
+            if health.failures > 0 {
+                health.state = if health.failures >= threshold {
+                    "Unresponsive"
+                } else {
+                    "Degraded"
+                };
+            }
+            if !update(&sender, device_id, &health).await {
+                return;
+            }
+            if health.failures >= threshold {
+                warn!(
+                    "USB device {udid} missed {} liveness checks; resetting its mux",
+                    health.failures
+                );
+                mux.reset().await;
+                return;
+            }
+        }
+    });
+}
+
+async fn query_type(mux: &crate::usb::mux::UsbMuxHandle) -> Result<String, String> {
+    let stream = mux
+        .connect(LockdownClient::LOCKDOWND_PORT)
+        .await
+        .map_err(|e| format!("usb connect to lockdown: {e:?}"))?;
+    let mut idevice = Idevice::new(Box::new(stream), "netmuxd-liveness");
+    idevice
+        .get_type()
+        .await
+        .map_err(|e| format!("QueryType: {e:?}"))
+}
+
+/// Report `health` to the manager. `false` if the device is no longer listed.
+async fn update(sender: &ManagerSender, device_id: u64, health: &UsbHealth) -> bool {
+    let (tx, rx) = oneshot::channel();
+    if sender
+        .send(ManagerRequest {
+            request_type: ManagerRequestType::UsbHealthUpdate {
+                device_id,
+                health: health.clone(),
+                known: tx,
+            },
+            response: None,
+        })
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    rx.await.unwrap_or(false)
+}
@@ -3,15 +3,26 @@
 // and placed everything in an Arc<Muxtex<>>. While it has its uses,
 // I much prefer the channel-runner paradigm for multithreaded programs.
 
-use std::{collections::HashMap, net::IpAddr};
+use std::collections::{HashMap, HashSet};
 
 use crossfire::{AsyncRx, MAsyncTx, mpmc::unbounded_async};
-use log::debug;
-use tokio::sync::{mpsc::UnboundedSender, oneshot::Sender};
+use log::{debug, info};
+use tokio::{
+    sync::{mpsc::UnboundedSender, oneshot::Sender},
+    task::AbortHandle,
+};
 
 use crate::{
-    config::NetmuxdConfig, devices::MuxerDevice, heartbeat::heartbeat,
-    pairing_file::PairingFileFinder, usb::mux::UsbMuxHandle,
+    config::NetmuxdConfig,
+    device_ids::DeviceIds,
+    devices::{DeviceAddr, HeartbeatStatus, MuxerDevice, UsbHealth, UsbStatus},
+    heartbeat::{HeartbeatRetry, heartbeat},
+    liveness,
+    pairing_file::PairingFileFinder,
+    pairing_policy::PairingState,
+    policy::DevicePolicies,
+    server::MuxError,
+    usb::mux::UsbMuxHandle,
 };
 
 pub type ManagerSender = MAsyncTx<ManagerRequest>;
@@ -30,12 +41,26 @@ pub struct ManagerRequest {
     pub response: Option<Sender<plist::Dictionary>>,
 }
 
+/// The manager's answer to an offered network device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkAdd {
+    /// Its heartbeat connected (or heartbeats are off) and it's listed now.
+    Added,
+    /// It was listed already.
+    AlreadyListed,
+}
+
 pub enum ManagerRequestType {
+    /// Offer a network device. `response` hears how that went: `BadDevice`
+    /// without a pairing record, `ConnRefused` if its heartbeat couldn't
+    /// connect.
     DiscoveredNetworkDevice {
         udid: String,
-        network_address: IpAddr,
+        network_address: DeviceAddr,
         service_name: String,
         connection_type: String,
+        lockdown_port: Option<u16>,
+        response: Option<Sender<Result<NetworkAdd, MuxError>>>,
     },
     DiscoveredUsbDevice {
         udid: String,
@@ -43,50 +68,191 @@ pub enum ManagerRequestType {
         product_id: u64,
         speed: u64,
         handle: UsbMuxHandle,
+        /// Set for a device that isn't paired yet; it's listed all the same.
+        status: Option<UsbStatus>,
     },
+    /// A network device's heartbeat connected; list it. Ignored if
+    /// `generation` isn't the device's current heartbeat.
     DeferredMuxerAdd {
         device: MuxerDevice,
-        response: Option<Sender<plist::Dictionary>>,
+        generation: u64,
     },
     RemoveDevice {
         udid: String,
         connection_type: Option<String>,
     },
+    /// Drop `udid`'s network entry only if it was registered under
+    /// `service_name`, so a discovery source can't remove a device that
+    /// another source (or a client's AddDevice) listed.
+    RemoveNetworkService {
+        udid: String,
+        service_name: String,
+    },
     ListDevices,
     GetDeviceConnection {
         id: u64,
         response: tokio::sync::oneshot::Sender<Option<DeviceConnection>>,
     },
+    /// Where a `Connect` to `id` should go under the device's connection
+    /// policy: the best transport first, then fallbacks. Empty if the device
+    /// is gone or the policy allows none of its transports.
+    GetConnectRoute {
+        id: u64,
+        response: tokio::sync::oneshot::Sender<Vec<DeviceConnection>>,
+    },
+    /// A network device's heartbeat gave up. Only drops the device if
+    /// `generation` is still its current heartbeat, not one a re-add has
+    /// since replaced.
     HeartbeatFailed {
+        device_id: u64,
+        generation: u64,
+    },
+    /// `udid`'s pairing record was replaced or deleted. Its network entries
+    /// are dropped and offered again at the same address, so they're only
+    /// listed if the current record (if any) still works.
+    PairingRecordChanged {
+        udid: String,
+    },
+    /// `udid` has a pairing record now, e.g. from a client that paired a
+    /// listed `Unpaired` USB device itself.
+    PairingRecordAdded {
         udid: String,
     },
+    /// A network device's heartbeat state changed. `known` answers whether
+    /// the device is still listed with this heartbeat, so it can stop if not.
+    HeartbeatUpdate {
+        device_id: u64,
+        generation: u64,
+        status: HeartbeatStatus,
+        known: tokio::sync::oneshot::Sender<bool>,
+    },
+    /// Result of a USB liveness check, answered like `HeartbeatUpdate`.
+    UsbHealthUpdate {
+        device_id: u64,
+        health: UsbHealth,
+        known: tokio::sync::oneshot::Sender<bool>,
+    },
     OpenSocket {
         device_id: u64,
         kill: Sender<()>,
     },
     Subscribe {
         listener: UnboundedSender<ListenerEvent>,
+        /// The client's Listen request (ProgName, BundleID,
+        /// ClientVersionString, ...), reported back by `ListListeners`.
+        info: plist::Dictionary,
+    },
+    ListListeners,
+    /// Where an unpaired USB device (`serial` as reported over USB) is in
+    /// pairing. Kept until it's unplugged, and sent to listeners that asked
+    /// for pairing events.
+    SetPairingState {
+        serial: String,
+        udid: String,
+        state: PairingState,
+    },
+    /// Hold `serial` in `AwaitingApproval` until `DecidePairing` answers
+    /// `response`. It's dropped unanswered if the device goes away.
+    AwaitPairingApproval {
+        serial: String,
+        udid: String,
+        response: Sender<bool>,
+    },
+    /// An operator's answer for the device with serial number or UDID `id`.
+    /// `response` says whether it was waiting for one.
+    DecidePairing {
+        id: String,
+        approve: bool,
+        response: Sender<bool>,
+    },
+    ListPairings,
+    /// Adopt reloaded settings. Connection policy changes are applied to the
+    /// current devices; the rest only affect devices discovered afterwards.
+    Reconfigure(NetmuxdConfig),
+    /// Detach every device, end every Listen session and tunnel, and shut
+    /// down the USB mux tasks. Devices discovered afterwards are ignored.
+    /// Replies with the mux handles so the caller can wait for them to close.
+    Shutdown {
+        response: Sender<Vec<UsbMuxHandle>>,
     },
 }
 
+/// A `Listen` session subscribed to attach/detach events.
+struct Listener {
+    id: u64,
+    tx: UnboundedSender<ListenerEvent>,
+    info: plist::Dictionary,
+}
+
 #[derive(Clone)]
 pub enum ListenerEvent {
     Attached(plist::Dictionary),
     Detached(u64),
+    /// A `PairingState` message, only sent to listeners that asked for them.
+    Pairing(plist::Dictionary),
+    /// A listed USB device was paired, like usbmuxd's `Paired` message.
+    Paired(u64),
+}
+
+/// A network entry's heartbeat task. Each registration gets a new
+/// generation, so reports from a heartbeat that has since been replaced can
+/// be told apart.
+struct Heartbeat {
+    generation: u64,
+    task: AbortHandle,
+    /// Offers made while it's still connecting, answered once it has.
+    waiting: Vec<Sender<Result<NetworkAdd, MuxError>>>,
+}
+
+/// Whether `generation` is still `device_id`'s registered heartbeat.
+fn is_current(heartbeats: &HashMap<u64, Heartbeat>, device_id: u64, generation: u64) -> bool {
+    heartbeats
+        .get(&device_id)
+        .is_some_and(|h| h.generation == generation)
+}
+
+/// Record a heartbeat's `status` on its device. `false` if the device isn't
+/// listed under that heartbeat any more.
+fn heartbeat_update(
+    devices: &mut HashMap<u64, MuxerDevice>,
+    heartbeats: &HashMap<u64, Heartbeat>,
+    device_id: u64,
+    generation: u64,
+    status: HeartbeatStatus,
+) -> bool {
+    if !is_current(heartbeats, device_id, generation) {
+        return false;
+    }
+    match devices.get_mut(&device_id) {
+        Some(d) => {
+            d.heartbeat = Some(status);
+            true
+        }
+        None => false,
+    }
+}
+
+/// A USB device going through pairing.
+struct Pairing {
+    udid: String,
+    state: PairingState,
+    approval: Option<Sender<bool>>,
 }
 
 #[derive(Clone)]
 pub struct DeviceConnection {
+    pub device_id: u64,
     pub connection_type: String,
     pub serial_number: String,
-    pub network_address: Option<IpAddr>,
+    pub network_address: Option<DeviceAddr>,
+    pub lockdown_port: Option<u16>,
     pub usb: Option<UsbMuxHandle>,
 }
 
 impl ManagerRequest {
     pub fn discovered_device(
         udid: String,
-        network_address: IpAddr,
+        network_address: DeviceAddr,
         service_name: String,
         connection_type: String,
     ) -> Self {
@@ -96,13 +262,18 @@ impl ManagerRequest {
                 network_address,
                 service_name,
                 connection_type,
+                lockdown_port: None,
+                response: None,
             },
             response: None,
         }
     }
-    pub fn heartbeat_failed(udid: String) -> Self {
+    pub fn heartbeat_failed(device_id: u64, generation: u64) -> Self {
         Self {
-            request_type: ManagerRequestType::HeartbeatFailed { udid },
+            request_type: ManagerRequestType::HeartbeatFailed {
+                device_id,
+                generation,
+            },
             response: None,
         }
     }
@@ -125,8 +296,12 @@ fn drop_entry(
     devices: &mut HashMap<u64, MuxerDevice>,
     usb_handles: &mut HashMap<u64, UsbMuxHandle>,
     open_sockets: &mut HashMap<u64, Vec<Sender<()>>>,
+    heartbeats: &mut HashMap<u64, Heartbeat>,
 ) -> Option<UsbMuxHandle> {
     devices.remove(&id);
+    if let Some(h) = heartbeats.remove(&id) {
+        h.task.abort();
+    }
     if let Some(l) = open_sockets.remove(&id) {
         for s in l {
             let _ = s.send(());
@@ -135,6 +310,60 @@ fn drop_entry(
     usb_handles.remove(&id)
 }
 
+fn device_connection(
+    device: &MuxerDevice,
+    usb_handles: &HashMap<u64, UsbMuxHandle>,
+) -> DeviceConnection {
+    DeviceConnection {
+        device_id: device.device_id,
+        connection_type: device.connection_type.clone(),
+        serial_number: device.serial_number.clone(),
+        network_address: device.network_address,
+        lockdown_port: device.lockdown_port,
+        usb: usb_handles.get(&device.device_id).cloned(),
+    }
+}
+
+/// IDs clients should see under each device's connection policy.
+fn visible_ids(devices: &HashMap<u64, MuxerDevice>, policies: &DevicePolicies) -> HashSet<u64> {
+    let mut by_udid: HashMap<&str, Vec<(u64, &str)>> = HashMap::new();
+    for (id, d) in devices {
+        by_udid
+            .entry(&d.serial_number)
+            .or_default()
+            .push((*id, &d.connection_type));
+    }
+    by_udid
+        .into_iter()
+        .flat_map(|(udid, entries)| policies.for_udid(udid).visible(&entries))
+        .collect()
+}
+
+/// Tell listeners about every entry that the last change made visible or
+/// hid, e.g. hiding a device's network entry when it's plugged in over USB.
+fn sync_visible(
+    devices: &HashMap<u64, MuxerDevice>,
+    policies: &DevicePolicies,
+    shown: &mut HashSet<u64>,
+    listeners: &mut Vec<Listener>,
+) {
+    let visible = visible_ids(devices, policies);
+    let mut hidden: Vec<u64> = shown.difference(&visible).copied().collect();
+    hidden.sort_unstable();
+    for id in hidden {
+        broadcast(listeners, ListenerEvent::Detached(id));
+    }
+    let mut added: Vec<&MuxerDevice> = visible
+        .difference(shown)
+        .filter_map(|id| devices.get(id))
+        .collect();
+    added.sort_unstable_by_key(|d| d.device_id);
+    for d in added {
+        broadcast(listeners, ListenerEvent::Attached(attached_plist(d)));
+    }
+    *shown = visible;
+}
+
 fn attached_plist(device: &MuxerDevice) -> plist::Dictionary {
     plist_macro::plist!(dict {
         "DeviceID": device.device_id,
@@ -143,26 +372,120 @@ fn attached_plist(device: &MuxerDevice) -> plist::Dictionary {
     })
 }
 
-fn broadcast(listeners: &mut Vec<UnboundedSender<ListenerEvent>>, event: ListenerEvent) {
-    listeners.retain(|tx| tx.send(event.clone()).is_ok());
+fn broadcast(listeners: &mut Vec<Listener>, event: ListenerEvent) {
+    listeners.retain(|l| l.tx.send(event.clone()).is_ok());
+}
+
+/// Set a USB device's `Status`, and tell listeners once it's paired.
+fn set_usb_status(
+    devices: &mut HashMap<u64, MuxerDevice>,
+    shown: &HashSet<u64>,
+    listeners: &mut Vec<Listener>,
+    id: u64,
+    status: Option<UsbStatus>,
+) {
+    let Some(device) = devices.get_mut(&id) else {
+        return;
+    };
+    let old = std::mem::replace(&mut device.usb_status, status);
+    if old.is_some() && status.is_none() && shown.contains(&id) {
+        broadcast(listeners, ListenerEvent::Paired(id));
+    }
+}
+
+/// Whether the client's Listen request set `PairingEvents`.
+fn wants_pairing_events(listener: &Listener) -> bool {
+    listener
+        .info
+        .get("PairingEvents")
+        .and_then(|v| v.as_boolean())
+        .unwrap_or(false)
+}
+
+/// One `PairingList` entry.
+fn pairing_plist(serial: &str, pairing: &Pairing) -> plist::Dictionary {
+    plist_macro::plist!(dict {
+        "SerialNumber": serial,
+        "UDID": pairing.udid.as_str(),
+        "State": pairing.state.name(),
+    })
+}
+
+fn pairing_event(serial: &str, pairing: &Pairing) -> ListenerEvent {
+    let mut p = pairing_plist(serial, pairing);
+    p.insert("MessageType".into(), "PairingState".into());
+    ListenerEvent::Pairing(p)
+}
+
+fn broadcast_pairing(listeners: &mut Vec<Listener>, serial: &str, pairing: &Pairing) {
+    let event = pairing_event(serial, pairing);
+    listeners.retain(|l| !wants_pairing_events(l) || l.tx.send(event.clone()).is_ok());
+}
+
+/// One `ListenerList` entry, in the shape usbmuxd reports its clients.
+fn listener_plist(listener: &Listener) -> plist::Dictionary {
+    let string = |key: &str| {
+        listener
+            .info
+            .get(key)
+            .and_then(|v| v.as_string())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let prog_name = string("ProgName");
+    let mut p = plist::Dictionary::new();
+    p.insert("Blacklisted".into(), false.into());
+    p.insert("BundleID".into(), string("BundleID").into());
+    p.insert("ConnType".into(), 0u64.into());
+    p.insert(
+        "ID String".into(),
+        format!("{}-{}", listener.id, prog_name).into(),
+    );
+    p.insert("ProgName".into(), prog_name.into());
+    if let Some(v) = listener.info.get("kLibUSBMuxVersion") {
+        p.insert("kLibUSBMuxVersion".into(), v.clone());
+    }
+    p.insert(
+        "Version String".into(),
+        string("ClientVersionString").into(),
+    );
+    p
 }
 
-pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
+pub fn new_manager_thread(
+    config: &NetmuxdConfig,
+    pairing_file_finder: PairingFileFinder,
+) -> ManagerSender {
     let (manager_sender, manager_recv) = new_channel_pair();
     let to_return = manager_sender.clone();
-    let config = config.clone();
-    let pairing_file_finder = PairingFileFinder::new(&config);
+    let mut config = config.clone();
 
     let mut devices: HashMap<u64, MuxerDevice> = HashMap::new();
     let mut usb_handles: HashMap<u64, UsbMuxHandle> = HashMap::new();
     let mut open_sockets: HashMap<u64, Vec<Sender<()>>> = HashMap::new();
-    let mut listeners: Vec<UnboundedSender<ListenerEvent>> = Vec::new();
-    let mut last_index: u64 = if config.upstream.is_some() {
-        SHIM_NETWORK_ID_BASE
-    } else {
-        1
-    };
+    // Network entries' heartbeats, including ones still connecting.
+    let mut heartbeats: HashMap<u64, Heartbeat> = HashMap::new();
+    let mut last_heartbeat_generation: u64 = 0;
+    let mut listeners: Vec<Listener> = Vec::new();
+    // Unpaired USB devices by the serial number they reported over USB.
+    let mut pairings: HashMap<String, Pairing> = HashMap::new();
+    // Entries listeners have been told about; the rest are hidden by policy.
+    let mut shown: HashSet<u64> = HashSet::new();
+    // Every ID handed out, so a Connect to a transport that has since gone
+    // away can still fall back to the device's other one (unless the policy
+    // is `both`).
+    let mut known_udids: HashMap<u64, String> = HashMap::new();
+    let mut last_listener_id: u64 = 1;
+    let mut device_ids = DeviceIds::new(
+        if config.upstream.is_some() {
+            SHIM_NETWORK_ID_BASE
+        } else {
+            1
+        },
+        config.device_ids_file.clone(),
+    );
     let mut last_interface_index: u64 = 1;
+    let mut shutting_down = false;
 
     tokio::task::spawn(async move {
         loop {
@@ -173,59 +496,101 @@ pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
                     break;
                 }
             };
+            if shutting_down
+                && matches!(
+                    message.request_type,
+                    ManagerRequestType::DiscoveredNetworkDevice { .. }
+                        | ManagerRequestType::DiscoveredUsbDevice { .. }
+                        | ManagerRequestType::DeferredMuxerAdd { .. }
+                )
+            {
+                continue;
+            }
             match message.request_type {
                 ManagerRequestType::DiscoveredNetworkDevice {
                     udid,
                     network_address,
                     service_name,
                     connection_type,
+                    lockdown_port,
+                    response,
                 } => {
                     if find_device_id(&devices, &udid, &connection_type).is_some() {
+                        if let Some(response) = response {
+                            let _ = response.send(Ok(NetworkAdd::AlreadyListed));
+                        }
                         continue;
                     }
                     let pairing_file = match pairing_file_finder.get_pairing_record(&udid).await {
                         Ok(p) => p,
                         Err(e) => {
                             debug!("Failed to get pairing record: {e:?}");
+                            if let Some(response) = response {
+                                let _ = response.send(Err(MuxError::BadDevice));
+                            }
                             continue;
                         }
                     };
 
+                    let device_id = device_ids.get(&udid, &connection_type);
+                    if let Some(pending) = heartbeats.get_mut(&device_id) {
+                        // Its heartbeat is still connecting; that offer wins,
+                        // and this one hears how it went.
+                        pending.waiting.extend(response);
+                        continue;
+                    }
+
                     let device = MuxerDevice {
+                        device_id,
                         connection_type,
-                        device_id: last_index,
                         interface_index: last_interface_index,
                         serial_number: udid.clone(),
                         network_address: Some(network_address),
                         service_name: Some(service_name),
+                        heartbeat: None,
+                        lockdown_port,
                         connection_speed: None,
                         location_id: None,
                         product_id: None,
+                        usb_health: None,
+                        usb_status: None,
                     };
-                    last_index = last_index.wrapping_add(1);
                     last_interface_index = last_interface_index.wrapping_add(1);
 
                     if config.use_heartbeat {
-                        heartbeat(
+                        last_heartbeat_generation += 1;
+                        let generation = last_heartbeat_generation;
+                        let task = heartbeat(
                             device,
-                            message.response,
+                            generation,
                             pairing_file,
                             manager_sender.clone(),
-                        )
-                        .await;
-
+                            HeartbeatRetry {
+                                attempts: config.heartbeat_retries,
+                                backoff: config.heartbeat_backoff,
+                            },
+                        );
+                        heartbeats.insert(
+                            device_id,
+                            Heartbeat {
+                                generation,
+                                task,
+                                waiting: response.into_iter().collect(),
+                            },
+                        );
                         continue;
                     }
 
-                    let attached = attached_plist(&device);
+                    known_udids.insert(device.device_id, udid);
                     devices.insert(device.device_id, device);
-                    broadcast(&mut listeners, ListenerEvent::Attached(attached));
-                    if let Some(response) = message.response {
-                        response
-                            .send(plist_macro::plist!(dict {
-                            "Result": 1,
-                            }))
-                            .ok();
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
+                    if let Some(response) = response {
+                        let _ = response.send(Ok(NetworkAdd::Added));
                     }
                 }
                 ManagerRequestType::DiscoveredUsbDevice {
@@ -234,42 +599,84 @@ pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
                     product_id,
                     speed,
                     handle,
+                    status,
                 } => {
                     if let Some(id) = find_device_id(&devices, &udid, "USB") {
+                        set_usb_status(&mut devices, &shown, &mut listeners, id, status);
                         // Replace the handle but keep the device entry.
+                        if let Some(interval) = config.usb_liveness.interval_for(&udid) {
+                            liveness::spawn(
+                                handle.downgrade(),
+                                id,
+                                udid,
+                                interval,
+                                &config.usb_liveness,
+                                manager_sender.clone(),
+                            );
+                        }
                         usb_handles.insert(id, handle);
                         continue;
                     }
                     let device = MuxerDevice {
                         connection_type: "USB".into(),
-                        device_id: last_index,
+                        device_id: device_ids.get(&udid, "USB"),
                         interface_index: last_interface_index,
                         serial_number: udid.clone(),
                         network_address: None,
                         service_name: None,
+                        heartbeat: None,
+                        lockdown_port: None,
                         connection_speed: Some(speed),
                         location_id: Some(location_id),
                         product_id: Some(product_id),
+                        usb_health: None,
+                        usb_status: status,
                     };
-                    println!("Adding USB device {udid}");
-                    let attached = attached_plist(&device);
-                    devices.insert(last_index, device);
-                    usb_handles.insert(last_index, handle);
-                    broadcast(&mut listeners, ListenerEvent::Attached(attached));
-                    last_index = last_index.wrapping_add(1);
+                    info!("Adding USB device {udid}");
+                    let id = device.device_id;
+                    if let Some(interval) = config.usb_liveness.interval_for(&udid) {
+                        liveness::spawn(
+                            handle.downgrade(),
+                            id,
+                            udid.clone(),
+                            interval,
+                            &config.usb_liveness,
+                            manager_sender.clone(),
+                        );
+                    }
+                    known_udids.insert(id, udid);
+                    devices.insert(id, device);
+                    usb_handles.insert(id, handle);
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
                     last_interface_index = last_interface_index.wrapping_add(1);
                 }
-                ManagerRequestType::DeferredMuxerAdd { device, response } => {
-                    println!("Adding network device {}", device.serial_number);
-                    let attached = attached_plist(&device);
-                    devices.insert(device.device_id, device);
-                    broadcast(&mut listeners, ListenerEvent::Attached(attached));
-                    if let Some(response) = response {
-                        response
-                            .send(plist_macro::plist!(dict {
-                            "Result": 1,
-                            }))
-                            .ok();
+                ManagerRequestType::DeferredMuxerAdd { device, generation } => {
+                    if !is_current(&heartbeats, device.device_id, generation) {
+                        debug!(
+                            "Ignoring add of {} from a replaced heartbeat",
+                            device.serial_number
+                        );
+                        continue;
+                    }
+                    info!("Adding network device {}", device.serial_number);
+                    let device_id = device.device_id;
+                    known_udids.insert(device_id, device.serial_number.clone());
+                    devices.insert(device_id, device);
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
+                    if let Some(h) = heartbeats.get_mut(&device_id) {
+                        for response in h.waiting.drain(..) {
+                            let _ = response.send(Ok(NetworkAdd::Added));
+                        }
                     }
                 }
                 ManagerRequestType::RemoveDevice {
@@ -288,18 +695,60 @@ pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
                         .map(|(id, _)| *id)
                         .collect();
                     for id in ids {
-                        if let Some(h) =
-                            drop_entry(id, &mut devices, &mut usb_handles, &mut open_sockets)
-                        {
+                        if let Some(h) = drop_entry(
+                            id,
+                            &mut devices,
+                            &mut usb_handles,
+                            &mut open_sockets,
+                            &mut heartbeats,
+                        ) {
                             h.shutdown().await;
                         }
-                        broadcast(&mut listeners, ListenerEvent::Detached(id));
                     }
+                    if connection_type.as_deref().is_none_or(|ct| ct == "USB") {
+                        // Drops any pending approval along with it.
+                        pairings.retain(|serial, p| *serial != udid && p.udid != udid);
+                    }
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
+                }
+                ManagerRequestType::RemoveNetworkService { udid, service_name } => {
+                    let ids: Vec<u64> = devices
+                        .values()
+                        .filter(|d| {
+                            d.serial_number == udid
+                                && d.network_address.is_some()
+                                && d.service_name.as_deref() == Some(service_name.as_str())
+                        })
+                        .map(|d| d.device_id)
+                        .collect();
+                    if ids.is_empty() {
+                        continue;
+                    }
+                    for id in ids {
+                        drop_entry(
+                            id,
+                            &mut devices,
+                            &mut usb_handles,
+                            &mut open_sockets,
+                            &mut heartbeats,
+                        );
+                    }
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
                 }
                 ManagerRequestType::ListDevices => {
                     if let Some(response) = message.response {
                         let mut device_list = Vec::new();
-                        for d in devices.values() {
+                        for d in devices.values().filter(|d| shown.contains(&d.device_id)) {
                             device_list.push(plist::Value::Dictionary(attached_plist(d)));
                         }
                         response
@@ -310,27 +759,149 @@ pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
                     }
                 }
                 ManagerRequestType::GetDeviceConnection { id, response } => {
-                    let lookup = devices.get(&id).map(|d| DeviceConnection {
-                        connection_type: d.connection_type.clone(),
-                        serial_number: d.serial_number.clone(),
-                        network_address: d.network_address,
-                        usb: usb_handles.get(&id).cloned(),
-                    });
+                    let lookup = devices.get(&id).map(|d| device_connection(d, &usb_handles));
                     let _ = response.send(lookup);
                 }
-                ManagerRequestType::HeartbeatFailed { udid } => {
-                    if let Some(id) = find_device_id(&devices, &udid, "Network") {
-                        drop_entry(id, &mut devices, &mut usb_handles, &mut open_sockets);
-                        broadcast(&mut listeners, ListenerEvent::Detached(id));
+                ManagerRequestType::GetConnectRoute { id, response } => {
+                    let Some(udid) = known_udids.get(&id) else {
+                        let _ = response.send(Vec::new());
+                        continue;
+                    };
+                    let entries: Vec<(u64, &str)> = devices
+                        .values()
+                        .filter(|d| d.serial_number == *udid)
+                        .map(|d| (d.device_id, d.connection_type.as_str()))
+                        .collect();
+                    let route = config
+                        .connection_policy
+                        .for_udid(udid)
+                        .route(id, &entries)
+                        .into_iter()
+                        .filter_map(|id| devices.get(&id))
+                        .map(|d| device_connection(d, &usb_handles))
+                        .collect();
+                    let _ = response.send(route);
+                }
+                ManagerRequestType::HeartbeatFailed {
+                    device_id,
+                    generation,
+                } => {
+                    if !is_current(&heartbeats, device_id, generation) {
+                        // A re-add replaced this heartbeat; leave its entry be.
+                        continue;
+                    }
+                    // Offers still waiting on it never got listed.
+                    if let Some(h) = heartbeats.remove(&device_id) {
+                        for response in h.waiting {
+                            let _ = response.send(Err(MuxError::ConnRefused));
+                        }
+                    }
+                    if devices.contains_key(&device_id) {
+                        drop_entry(
+                            device_id,
+                            &mut devices,
+                            &mut usb_handles,
+                            &mut open_sockets,
+                            &mut heartbeats,
+                        );
+                        sync_visible(
+                            &devices,
+                            &config.connection_policy,
+                            &mut shown,
+                            &mut listeners,
+                        );
+                    }
+                }
+                ManagerRequestType::PairingRecordChanged { udid } => {
+                    let stale: Vec<MuxerDevice> = devices
+                        .values()
+                        .filter(|d| d.serial_number == udid && d.network_address.is_some())
+                        .cloned()
+                        .collect();
+                    if stale.is_empty() {
+                        continue;
+                    }
+                    for d in &stale {
+                        drop_entry(
+                            d.device_id,
+                            &mut devices,
+                            &mut usb_handles,
+                            &mut open_sockets,
+                            &mut heartbeats,
+                        );
+                    }
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
+                    for d in stale {
+                        let Some(network_address) = d.network_address else {
+                            continue;
+                        };
+                        debug!(
+                            "Re-checking {udid} at {network_address} with its new pairing record"
+                        );
+                        let _ = manager_sender
+                            .send(ManagerRequest {
+                                request_type: ManagerRequestType::DiscoveredNetworkDevice {
+                                    udid: udid.clone(),
+                                    network_address,
+                                    service_name: d.service_name.unwrap_or_default(),
+                                    connection_type: d.connection_type,
+                                    lockdown_port: d.lockdown_port,
+                                    response: None,
+                                },
+                                response: None,
+                            })
+                            .await;
+                    }
+                }
+                ManagerRequestType::PairingRecordAdded { udid } => {
+                    if let Some(id) = find_device_id(&devices, &udid, "USB")
+                        && devices
+                            .get(&id)
+                            .is_some_and(|d| d.usb_status != Some(UsbStatus::Restore))
+                    {
+                        set_usb_status(&mut devices, &shown, &mut listeners, id, None);
                     }
                 }
+                ManagerRequestType::HeartbeatUpdate {
+                    device_id,
+                    generation,
+                    status,
+                    known,
+                } => {
+                    let found =
+                        heartbeat_update(&mut devices, &heartbeats, device_id, generation, status);
+                    let _ = known.send(found);
+                }
+                ManagerRequestType::UsbHealthUpdate {
+                    device_id,
+                    health,
+                    known,
+                } => {
+                    let device = devices.get_mut(&device_id);
+                    let found = device.is_some();
+                    if let Some(d) = device {
+                        d.usb_health = Some(health);
+                    }
+                    let _ = known.send(found);
+                }
                 ManagerRequestType::OpenSocket { device_id, kill } => {
                     open_sockets.entry(device_id).or_default().push(kill);
                 }
-                ManagerRequestType::Subscribe { listener } => {
+                ManagerRequestType::Subscribe { listener, info } => {
+                    let listener = Listener {
+                        id: last_listener_id,
+                        tx: listener,
+                        info,
+                    };
                     let mut ok = true;
-                    for d in devices.values() {
+                    for d in devices.values().filter(|d| shown.contains(&d.device_id)) {
                         if listener
+                            .tx
                             .send(ListenerEvent::Attached(attached_plist(d)))
                             .is_err()
                         {
@@ -338,8 +909,134 @@ pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
                             break;
                         }
                     }
+                    if ok && wants_pairing_events(&listener) {
+                        ok = pairings
+                            .iter()
+                            .all(|(serial, p)| listener.tx.send(pairing_event(serial, p)).is_ok());
+                    }
                     if ok {
                         listeners.push(listener);
+                        last_listener_id = last_listener_id.wrapping_add(1);
+                    }
+                }
+                ManagerRequestType::SetPairingState {
+                    serial,
+                    udid,
+                    state,
+                } => {
+                    if let Some(id) = find_device_id(&devices, &udid, "USB") {
+                        let status = match state {
+                            PairingState::Paired => None,
+                            PairingState::AwaitingTrust => Some(UsbStatus::Pairing),
+                            _ => Some(UsbStatus::Unpaired),
+                        };
+                        set_usb_status(&mut devices, &shown, &mut listeners, id, status);
+                    }
+                    let pairing = Pairing {
+                        udid,
+                        state,
+                        approval: None,
+                    };
+                    broadcast_pairing(&mut listeners, &serial, &pairing);
+                    pairings.insert(serial, pairing);
+                }
+                ManagerRequestType::AwaitPairingApproval {
+                    serial,
+                    udid,
+                    response,
+                } => {
+                    let pairing = Pairing {
+                        udid,
+                        state: PairingState::AwaitingApproval,
+                        approval: Some(response),
+                    };
+                    broadcast_pairing(&mut listeners, &serial, &pairing);
+                    pairings.insert(serial, pairing);
+                }
+                ManagerRequestType::DecidePairing {
+                    id,
+                    approve,
+                    response,
+                } => {
+                    let approval = pairings
+                        .iter_mut()
+                        .find(|(serial, p)| {
+                            serial.eq_ignore_ascii_case(&id) || p.udid.eq_ignore_ascii_case(&id)
+                        })
+                        .and_then(|(_, p)| p.approval.take());
+                    let decided = match approval {
+                        Some(tx) => tx.send(approve).is_ok(),
+                        None => false,
+                    };
+                    let _ = response.send(decided);
+                }
+                ManagerRequestType::ListPairings => {
+                    if let Some(response) = message.response {
+                        let list: Vec<plist::Value> = pairings
+                            .iter()
+                            .map(|(serial, p)| plist::Value::Dictionary(pairing_plist(serial, p)))
+                            .collect();
+                        response
+                            .send(plist_macro::plist!(dict {
+                                "PairingList": list
+                            }))
+                            .ok();
+                    }
+                }
+                ManagerRequestType::Reconfigure(new) => {
+                    config = new;
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
+                }
+                ManagerRequestType::Shutdown { response } => {
+                    shutting_down = true;
+                    let ids: Vec<u64> = devices.keys().copied().collect();
+                    let mut handles = Vec::new();
+                    for id in ids {
+                        if let Some(h) = drop_entry(
+                            id,
+                            &mut devices,
+                            &mut usb_handles,
+                            &mut open_sockets,
+                            &mut heartbeats,
+                        ) {
+                            h.shutdown().await;
+                            handles.push(h);
+                        }
+                    }
+                    sync_visible(
+                        &devices,
+                        &config.connection_policy,
+                        &mut shown,
+                        &mut listeners,
+                    );
+                    // Dropping the senders ends each Listen session once it
+                    // has written the Detached events above.
+                    listeners.clear();
+                    pairings.clear();
+                    // Heartbeats still connecting have no entry to drop.
+                    for (_, h) in heartbeats.drain() {
+                        h.task.abort();
+                    }
+                    response.send(handles).ok();
+                }
+                ManagerRequestType::ListListeners => {
+                    // Drop sessions that have hung up so they aren't reported.
+                    listeners.retain(|l| !l.tx.is_closed());
+                    if let Some(response) = message.response {
+                        let list: Vec<plist::Value> = listeners
+                            .iter()
+                            .map(|l| plist::Value::Dictionary(listener_plist(l)))
+                            .collect();
+                        response
+                            .send(plist_macro::plist!(dict {
+                                "ListenerList": list
+                            }))
+                            .ok();
                     }
                 }
             }
@@ -349,7 +1046,128 @@ pub fn new_manager_thread(config: &NetmuxdConfig) -> ManagerSender {
     to_return
 }
 
-fn new_channel_pair() -> (ManagerSender, ManagerReceiver) {
+pub(crate) fn new_channel_pair() -> (ManagerSender, ManagerReceiver) {
     let (t, r) = unbounded_async();
     (t.into(), r.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_heartbeat(generation: u64) -> (Heartbeat, tokio::task::JoinHandle<()>) {
+        let task = tokio::spawn(std::future::pending());
+        let heartbeat = Heartbeat {
+            generation,
+            task: task.abort_handle(),
+            waiting: Vec::new(),
+        };
+        (heartbeat, task)
+    }
+
+    #[tokio::test]
+    async fn replaced_heartbeat_is_not_current() {
+        let mut heartbeats = HashMap::new();
+        let (first, _first_task) = spawn_heartbeat(1);
+        heartbeats.insert(7, first);
+        assert!(is_current(&heartbeats, 7, 1));
+
+        // A re-add registers a new generation for the same device ID.
+        let (second, _second_task) = spawn_heartbeat(2);
+        heartbeats.insert(7, second);
+        assert!(!is_current(&heartbeats, 7, 1));
+        assert!(is_current(&heartbeats, 7, 2));
+        assert!(!is_current(&heartbeats, 8, 2));
+    }
+
+    #[tokio::test]
+    async fn dropping_an_entry_stops_its_heartbeat() {
+        let mut heartbeats = HashMap::new();
+        let (heartbeat, task) = spawn_heartbeat(1);
+        heartbeats.insert(7, heartbeat);
+
+        drop_entry(
+            7,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &mut heartbeats,
+        );
+        assert!(heartbeats.is_empty());
+        assert!(task.await.unwrap_err().is_cancelled());
+    }
+
+    fn network_device(device_id: u64) -> MuxerDevice {
+        MuxerDevice {
+            connection_type: "Network".into(),
+            device_id,
+            interface_index: 1,
+            serial_number: "00008030-001A2B3C4D5E6F70".into(),
+            network_address: Some("192.168.1.20".parse().unwrap()),
+            service_name: Some("_apple-mobdev2._tcp.local".into()),
+            heartbeat: None,
+            lockdown_port: None,
+            connection_speed: None,
+            location_id: None,
+            product_id: None,
+            usb_health: None,
+            usb_status: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_status_shows_in_properties() {
+        let mut devices = HashMap::from([(7, network_device(7))]);
+        let (heartbeat, _task) = spawn_heartbeat(2);
+        let heartbeats = HashMap::from([(7, heartbeat)]);
+        let status = HeartbeatStatus {
+            state: "Retrying",
+            interval: 10,
+            last_marco: Some(std::time::SystemTime::UNIX_EPOCH),
+            failures: 2,
+        };
+
+        // A replaced heartbeat's report is turned away.
+        assert!(!heartbeat_update(
+            &mut devices,
+            &heartbeats,
+            7,
+            1,
+            status.clone()
+        ));
+        assert!(devices[&7].heartbeat.is_none());
+
+        assert!(heartbeat_update(&mut devices, &heartbeats, 7, 2, status));
+        let props = plist::Dictionary::from(&devices[&7]);
+        let heartbeat = props
+            .get("Heartbeat")
+            .and_then(|h| h.as_dictionary())
+            .expect("Heartbeat in properties");
+        assert_eq!(
+            heartbeat.get("State").and_then(|v| v.as_string()),
+            Some("Retrying")
+        );
+        assert_eq!(
+            heartbeat
+                .get("Failures")
+                .and_then(|v| v.as_unsigned_integer()),
+            Some(2)
+        );
+        assert!(
+            heartbeat
+                .get("LastMarco")
+                .and_then(|v| v.as_date())
+                .is_some()
+        );
+
+        // Gone from the list: the heartbeat should stop.
+        devices.clear();
+        let status = HeartbeatStatus {
+            state: "Alive",
+            interval: 10,
+            last_marco: None,
+            failures: 0,
+        };
+        assert!(!heartbeat_update(&mut devices, &heartbeats, 7, 2, status));
+    }
+}
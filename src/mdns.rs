@@ -1,16 +1,92 @@
 // Jackson Coxson
 
-use crate::manager::ManagerRequest;
-use crate::pairing_file::PairingFileFinder;
+use crate::devices::DeviceAddr;
+use crate::manager::{ManagerRequest, ManagerRequestType};
+use crate::pairing_file::{PairingFileFinder, RecordChange};
 use crate::{config::NetmuxdConfig, manager::ManagerSender};
-use log::{debug, warn};
-use mdns_sd::{ServiceDaemon, ServiceEvent};
+use futures_util::future::join_all;
+use idevice::lockdown::LockdownClient;
+use log::{debug, info, warn};
+use mdns_sd::{IfKind, ScopedIp, ServiceDaemon, ServiceEvent};
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
 
 const SERVICE_NAME: &str = "apple-mobdev2";
 const SERVICE_PROTOCOL: &str = "tcp";
 
-pub async fn discover(sender: ManagerSender, config: NetmuxdConfig) {
+/// How long a removed service may stay gone before its device is dropped.
+/// Devices routinely withdraw and re-announce when Wi-Fi roams or the screen
+/// locks; this keeps those blips from detaching and re-attaching them.
+const REMOVAL_GRACE: Duration = Duration::from_secs(15);
+
+/// How long a reachability probe waits for lockdownd to accept.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Which of a resolved service's addresses to hand to the manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressPolicy {
+    #[default]
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl AddressPolicy {
+    const ALL: [AddressPolicy; 4] = [
+        Self::PreferIpv4,
+        Self::PreferIpv6,
+        Self::Ipv4Only,
+        Self::Ipv6Only,
+    ];
+
+    /// Name used in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PreferIpv4 => "prefer-ipv4",
+            Self::PreferIpv6 => "prefer-ipv6",
+            Self::Ipv4Only => "ipv4-only",
+            Self::Ipv6Only => "ipv6-only",
+        }
+    }
+
+    /// The usable addresses in `addrs`, best first: the preferred family,
+    /// then routable before link-local.
+    fn order(self, mut addrs: Vec<DeviceAddr>) -> Vec<DeviceAddr> {
+        addrs.retain(|a| match self {
+            Self::Ipv4Only => a.ip.is_ipv4(),
+            Self::Ipv6Only => a.ip.is_ipv6(),
+            _ => true,
+        });
+        // A link-local IPv6 address is useless without its interface.
+        addrs.retain(|a| !(a.ip.is_ipv6() && is_link_local(a.ip) && a.scope_id == 0));
+        let v6_first = matches!(self, Self::PreferIpv6 | Self::Ipv6Only);
+        // mdns-sd hands addresses over in no particular order; sorting on the
+        // address too keeps the pick stable across announcements.
+        addrs.sort_by_key(|a| (a.ip.is_ipv6() != v6_first, is_link_local(a.ip), *a));
+        addrs
+    }
+}
+
+impl std::str::FromStr for AddressPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| "expected prefer-ipv4, prefer-ipv6, ipv4-only, or ipv6-only".to_string())
+    }
+}
+
+pub async fn discover(
+    sender: ManagerSender,
+    config: NetmuxdConfig,
+    pairing_file_finder: PairingFileFinder,
+) {
     // mdns-sd expects the fully-qualified service type with a trailing '.';
     // downstream consumers expect the form without it.
     let browse_type = format!("_{}._{}.local.", SERVICE_NAME, SERVICE_PROTOCOL);
@@ -18,13 +94,37 @@ pub async fn discover(sender: ManagerSender, config: NetmuxdConfig) {
     log::info!("Starting mDNS discovery for {browse_type} with mdns-sd");
 
     let daemon = match ServiceDaemon::new() {
-        Ok(d) => d,
+        Ok(d) => DaemonGuard(d),
         Err(e) => {
             log::error!("Failed to create mDNS daemon: {e}");
             return;
         }
     };
-    let receiver = match daemon.browse(&browse_type) {
+    if !config.mdns_interfaces.is_empty() {
+        let allowed: Vec<IfKind> = config
+            .mdns_interfaces
+            .iter()
+            .map(|name| IfKind::Name(name.clone()))
+            .collect();
+        if let Err(e) = daemon.0.disable_interface(IfKind::All) {
+            warn!("Failed to limit mDNS interfaces: {e}");
+        }
+        if let Err(e) = daemon.0.enable_interface(allowed) {
+            warn!("Failed to enable mDNS interfaces: {e}");
+        }
+    }
+    if !config.mdns_exclude_interfaces.is_empty() {
+        let denied: Vec<IfKind> = config
+            .mdns_exclude_interfaces
+            .iter()
+            .map(|name| IfKind::Name(name.clone()))
+            .collect();
+        if let Err(e) = daemon.0.disable_interface(denied) {
+            warn!("Failed to exclude mDNS interfaces: {e}");
+        }
+    }
+
+    let receiver = match daemon.0.browse(&browse_type) {
         Ok(r) => r,
         Err(e) => {
             log::error!("Failed to start mDNS browse: {e}");
@@ -32,11 +132,99 @@ pub async fn discover(sender: ManagerSender, config: NetmuxdConfig) {
         }
     };
 
-    let mut pairing_file_finder = PairingFileFinder::new(&config);
+    // Service instance fullname -> UDID it resolved to, for matching removals.
+    let mut services: HashMap<String, String> = HashMap::new();
+    // Resolved services no pairing record matched yet, tried again whenever
+    // a record is added or changed.
+    let mut unmatched: HashMap<String, Box<mdns_sd::ResolvedService>> = HashMap::new();
+    // UDID -> when to drop it, for devices whose services have all gone away.
+    let mut pending_removals: HashMap<String, Instant> = HashMap::new();
+    let mut changes = pairing_file_finder.subscribe();
+
+    loop {
+        let next_removal = pending_removals.values().min().copied();
+        let event = tokio::select! {
+            event = receiver.recv_async() => match event {
+                Ok(e) => e,
+                Err(_) => break,
+            },
+            change = changes.recv() => {
+                match change {
+                    Ok(c) if c.change == RecordChange::Removed => continue,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                let fullnames: Vec<String> = unmatched.keys().cloned().collect();
+                for fullname in fullnames {
+                    // The change already updated the cache; no need to re-scan.
+                    let Some(udid) =
+                        match_service(&pairing_file_finder, &unmatched[&fullname], false).await
+                    else {
+                        continue;
+                    };
+                    let Some(resolved) = unmatched.remove(&fullname) else {
+                        continue;
+                    };
+                    info!("Pairing record for {udid} now matches {fullname}");
+                    if !register(
+                        &sender,
+                        &config,
+                        &service_name,
+                        &resolved,
+                        udid,
+                        &mut services,
+                        &mut pending_removals,
+                    )
+                    .await
+                    {
+                        return;
+                    }
+                }
+                continue;
+            }
+            _ = sleep_until(next_removal) => {
+                let now = Instant::now();
+                let expired: Vec<String> = pending_removals
+                    .iter()
+                    .filter(|(_, at)| **at <= now)
+                    .map(|(udid, _)| udid.clone())
+                    .collect();
+                for udid in expired {
+                    pending_removals.remove(&udid);
+                    info!("mDNS service for {udid} is gone, removing device");
+                    if sender
+                        .send(ManagerRequest {
+                            request_type: ManagerRequestType::RemoveNetworkService {
+                                udid,
+                                service_name: service_name.clone(),
+                            },
+                            response: None,
+                        })
+                        .await
+                        .is_err()
+                    {
+                        debug!("Failed to send device removal to manager, closing");
+                        return;
+                    }
+                }
+                continue;
+            }
+        };
 
-    while let Ok(event) = receiver.recv_async().await {
         let resolved = match event {
             ServiceEvent::ServiceResolved(info) => info,
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                debug!("Removed service: fullname={fullname}");
+                unmatched.remove(&fullname);
+                if let Some(udid) = services.remove(&fullname)
+                    && !services.values().any(|u| *u == udid)
+                {
+                    pending_removals
+                        .entry(udid)
+                        .or_insert_with(|| Instant::now() + REMOVAL_GRACE);
+                }
+                continue;
+            }
             _ => continue,
         };
         debug!(
@@ -44,85 +232,277 @@ pub async fn discover(sender: ManagerSender, config: NetmuxdConfig) {
             resolved.fullname, resolved.addresses
         );
 
-        let addr = match pick_address(&resolved) {
-            Some(a) => a,
-            None => {
-                warn!(
-                    "Resolved mDNS service has no usable address: {}",
-                    resolved.fullname
-                );
-                continue;
-            }
-        };
-
-        // iOS 26.4+: match by Bonjour TXT record (identifier + authTag HMACs).
-        let identifier = resolved
-            .get_property_val("identifier")
-            .and_then(|v| v)
-            .map(|b| b.to_vec());
-        let auth_tags: Vec<Vec<u8>> = resolved
-            .get_properties()
-            .iter()
-            .filter(|p| {
-                let k = p.key();
-                k == "authTag" || k.starts_with("authTag#")
-            })
-            .filter_map(|p| p.val().map(|b| b.to_vec()))
-            .collect();
-
-        let mut udid: Option<String> = None;
-        if let Some(ident) = &identifier
-            && !auth_tags.is_empty()
+        if config
+            .mdns_address_policy
+            .order(service_addresses(&resolved))
+            .is_empty()
         {
-            let refs: Vec<&[u8]> = auth_tags.iter().map(|v| v.as_slice()).collect();
-            udid = pairing_file_finder.find_udid_from_txt(ident, &refs).await;
+            warn!(
+                "Resolved mDNS service has no usable address: {}",
+                resolved.fullname
+            );
+            continue;
         }
 
-        // iOS < 26.4 fallback: parse MAC out of the instance name (`<MAC>@<id>.…`).
-        if udid.is_none()
-            && let Some((mac_addr, _)) = resolved.fullname.split_once('@')
-            && let Ok(u) = pairing_file_finder
-                .get_udid_from_mac(mac_addr.to_string())
-                .await
+        let Some(udid) = match_service(&pairing_file_finder, &resolved, true).await else {
+            unmatched.insert(resolved.fullname.clone(), resolved);
+            continue;
+        };
+        unmatched.remove(&resolved.fullname);
+        if !register(
+            &sender,
+            &config,
+            &service_name,
+            &resolved,
+            udid,
+            &mut services,
+            &mut pending_removals,
+        )
+        .await
         {
-            udid = Some(u);
+            break;
         }
+    }
+}
 
-        let udid = match udid {
-            Some(u) => u,
-            None => {
-                debug!(
-                    "No paired device matched service {} (identifier={}, authTags={})",
-                    resolved.fullname,
-                    identifier.is_some(),
-                    auth_tags.len()
-                );
-                continue;
-            }
+/// The UDID of the paired device that advertised `resolved`. With `rescan`,
+/// a cache miss re-reads the pairing store before giving up.
+async fn match_service(
+    finder: &PairingFileFinder,
+    resolved: &mdns_sd::ResolvedService,
+    rescan: bool,
+) -> Option<String> {
+    // iOS 26.4+: match by Bonjour TXT record (identifier + authTag HMACs).
+    let identifier = resolved
+        .get_property_val("identifier")
+        .and_then(|v| v)
+        .map(|b| b.to_vec());
+    let auth_tags: Vec<Vec<u8>> = resolved
+        .get_properties()
+        .iter()
+        .filter(|p| {
+            let k = p.key();
+            k == "authTag" || k.starts_with("authTag#")
+        })
+        .filter_map(|p| p.val().map(|b| b.to_vec()))
+        .collect();
+
+    let mut udid: Option<String> = None;
+    if let Some(ident) = &identifier
+        && !auth_tags.is_empty()
+    {
+        let refs: Vec<&[u8]> = auth_tags.iter().map(|v| v.as_slice()).collect();
+        udid = if rescan {
+            finder.find_udid_from_txt(ident, &refs).await
+        } else {
+            finder.udid_for_txt(ident, &refs)
         };
+    }
 
-        if sender
-            .send(ManagerRequest::discovered_device(
-                udid,
-                addr,
-                service_name.clone(),
-                "Network".to_string(),
-            ))
-            .await
-            .is_err()
-        {
-            debug!("Failed to send discovered device to manager, closing");
-            break;
+    // iOS < 26.4 fallback: parse MAC out of the instance name (`<MAC>@<id>.…`).
+    if udid.is_none()
+        && let Some((mac_addr, _)) = resolved.fullname.split_once('@')
+    {
+        udid = if rescan {
+            finder.get_udid_from_mac(mac_addr.to_string()).await.ok()
+        } else {
+            finder.udid_for_mac(mac_addr)
+        };
+    }
+
+    if udid.is_none() {
+        debug!(
+            "No paired device matched service {} (identifier={}, authTags={})",
+            resolved.fullname,
+            identifier.is_some(),
+            auth_tags.len()
+        );
+    }
+    udid
+}
+
+/// Hand a matched service to the manager. `false` if the manager is gone.
+async fn register(
+    sender: &ManagerSender,
+    config: &NetmuxdConfig,
+    service_name: &str,
+    resolved: &mdns_sd::ResolvedService,
+    udid: String,
+    services: &mut HashMap<String, String>,
+    pending_removals: &mut HashMap<String, Instant>,
+) -> bool {
+    if pending_removals.remove(&udid).is_some() {
+        debug!("{udid} re-announced within the removal grace period");
+    }
+    services.insert(resolved.fullname.clone(), udid.clone());
+
+    let candidates = config
+        .mdns_address_policy
+        .order(service_addresses(resolved));
+    // Probe only once the service matched, so strangers' devices on the
+    // network don't get connected to. The probe can take up to
+    // PROBE_TIMEOUT, so it runs on its own task rather than stalling browsing.
+    if config.mdns_probe && candidates.len() > 1 {
+        let sender = sender.clone();
+        let service_name = service_name.to_string();
+        tokio::spawn(async move {
+            let addr = probe(&candidates).await;
+            if !send_discovered(&sender, udid, addr, service_name).await {
+                debug!("Failed to send probed device to manager");
+            }
+        });
+        return true;
+    }
+
+    if !send_discovered(sender, udid, candidates[0], service_name.to_string()).await {
+        debug!("Failed to send discovered device to manager, closing");
+        return false;
+    }
+    true
+}
+
+/// Tell the manager `udid` is reachable at `addr`. `false` if it is gone.
+async fn send_discovered(
+    sender: &ManagerSender,
+    udid: String,
+    addr: DeviceAddr,
+    service_name: String,
+) -> bool {
+    sender
+        .send(ManagerRequest::discovered_device(
+            udid,
+            addr,
+            service_name,
+            "Network".to_string(),
+        ))
+        .await
+        .is_ok()
+}
+
+/// Stops the mDNS daemon's thread when discovery ends, including when the
+/// discovery task is aborted because mDNS was turned off on reload.
+struct DaemonGuard(ServiceDaemon);
+
+impl Drop for DaemonGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            debug!("Failed to shut down mDNS daemon: {e}");
         }
     }
 }
 
-fn pick_address(resolved: &mdns_sd::ResolvedService) -> Option<IpAddr> {
-    // Prefer IPv4 to preserve the existing behaviour; fall back to any address.
+/// Sleep until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(d) => tokio::time::sleep_until(d).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The service's addresses, with the interface each IPv6 one was seen on.
+fn service_addresses(resolved: &mdns_sd::ResolvedService) -> Vec<DeviceAddr> {
     resolved
         .addresses
         .iter()
-        .find(|a| a.is_ipv4())
-        .or_else(|| resolved.addresses.iter().next())
-        .map(|a| a.to_ip_addr())
+        .map(|a| match a {
+            ScopedIp::V6(v6) => DeviceAddr::new(IpAddr::V6(*v6.addr()), v6.scope_id().index),
+            other => other.to_ip_addr().into(),
+        })
+        .collect()
+}
+
+/// The first of `candidates` (which must not be empty) whose lockdownd
+/// accepts a connection. Falls back to the first candidate if none do, so the
+/// heartbeat can decide.
+async fn probe(candidates: &[DeviceAddr]) -> DeviceAddr {
+    if candidates.len() < 2 {
+        return candidates[0];
+    }
+    let reachable = join_all(candidates.iter().map(|a| async move {
+        let connect = tokio::net::TcpStream::connect(a.socket_addr(LockdownClient::LOCKDOWND_PORT));
+        matches!(
+            tokio::time::timeout(PROBE_TIMEOUT, connect).await,
+            Ok(Ok(_))
+        )
+    }))
+    .await;
+    match candidates.iter().zip(reachable).find(|(_, ok)| *ok) {
+        Some((a, _)) => *a,
+        None => {
+            debug!("No address of {candidates:?} answered, using the first");
+            candidates[0]
+        }
+    }
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> DeviceAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn address_policy_orders_families_and_link_local() {
+        let resolved = vec![
+            addr("fe80::1%3"),
+            addr("169.254.1.2"),
+            addr("2001:db8::1"),
+            addr("192.168.1.20"),
+            addr("fe80::2"),
+        ];
+        let cases: [(AddressPolicy, &[&str]); 4] = [
+            (
+                AddressPolicy::PreferIpv4,
+                &["192.168.1.20", "169.254.1.2", "2001:db8::1", "fe80::1%3"],
+            ),
+            (
+                AddressPolicy::PreferIpv6,
+                &["2001:db8::1", "fe80::1%3", "192.168.1.20", "169.254.1.2"],
+            ),
+            (AddressPolicy::Ipv4Only, &["192.168.1.20", "169.254.1.2"]),
+            (AddressPolicy::Ipv6Only, &["2001:db8::1", "fe80::1%3"]),
+        ];
+        for (policy, want) in cases {
+            let want: Vec<DeviceAddr> = want.iter().map(|a| addr(a)).collect();
+            assert_eq!(policy.order(resolved.clone()), want, "{}", policy.name());
+        }
+    }
+
+    #[test]
+    fn address_policy_order_is_stable() {
+        let a = vec![addr("10.0.0.9"), addr("10.0.0.2"), addr("10.0.0.5")];
+        let mut b = a.clone();
+        b.reverse();
+        let ordered = AddressPolicy::PreferIpv4.order(a);
+        assert_eq!(ordered, AddressPolicy::PreferIpv4.order(b));
+        assert_eq!(ordered[0], addr("10.0.0.2"));
+    }
+
+    #[test]
+    fn address_policy_can_leave_no_address() {
+        let only = vec![addr("fe80::2")];
+        assert!(AddressPolicy::PreferIpv6.order(only).is_empty());
+        assert!(
+            AddressPolicy::Ipv6Only
+                .order(vec![addr("192.168.1.20")])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn address_policy_names_round_trip() {
+        for policy in AddressPolicy::ALL {
+            assert_eq!(policy.name().parse::<AddressPolicy>(), Ok(policy));
+        }
+        assert!("ipv4".parse::<AddressPolicy>().is_err());
+    }
 }
@@ -1,58 +1,136 @@
 // Jackson Coxson
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+};
 
 use idevice::{IdeviceError, pairing_file::PairingFile};
 use log::{debug, info, trace, warn};
-use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast;
 
-use crate::config::NetmuxdConfig;
+use crate::pairing_store::PairingStore;
 
-#[derive(Clone, Debug)]
-pub struct PairingFileFinder {
-    plist_storage: String,
+/// What happened to a pairing record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordChange {
+    Added,
+    Changed,
+    Removed,
+}
+
+/// Sent to [`PairingFileFinder::subscribe`]rs when the cache picks up a
+/// record that differs from the one it had.
+#[derive(Debug, Clone)]
+pub struct PairingChange {
+    pub udid: String,
+    pub change: RecordChange,
+}
+
+/// Shared by every clone of a finder, so a change one task notices is seen
+/// by all of them.
+#[derive(Debug, Default)]
+struct Cache {
+    // Raw records, to tell real changes from rewrites of the same bytes.
+    records: HashMap<String, Vec<u8>>,
     // Legacy MAC-based lookup (iOS < 26.4)
     known_mac_addresses: HashMap<String, String>,
     // TXT-based lookup
     host_ids: HashMap<String, Vec<u8>>,
-    paired_udids: Vec<String>,
+}
+
+impl Cache {
+    fn remove(&mut self, udid: &str) -> bool {
+        self.known_mac_addresses.retain(|_, u| u != udid);
+        self.host_ids.remove(udid);
+        self.records.remove(udid).is_some()
+    }
+
+    /// Index `record` under `udid`. `false` if it isn't a plist.
+    fn insert(&mut self, udid: &str, record: Vec<u8>) -> bool {
+        let plist: plist::Dictionary = match plist::from_bytes(&record) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Unable to parse pairing record for {udid}: {e:?}");
+                return false;
+            }
+        };
+
+        // Legacy: index by WiFiMACAddress if present (iOS < 26.4 devices).
+        if let Some(plist::Value::String(mac)) = plist.get("WiFiMACAddress") {
+            self.known_mac_addresses
+                .insert(mac.clone(), udid.to_string());
+        }
+
+        // iOS 26.4+: index by HostID for TXT-based matching.
+        if let Some(plist::Value::String(host_id)) = plist.get("HostID") {
+            self.host_ids
+                .insert(udid.to_string(), host_id.as_bytes().to_vec());
+        } else {
+            debug!("Record {udid:?} has no HostID; TXT-based lookup will skip it");
+        }
+        self.records.insert(udid.to_string(), record);
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct PairingFileFinder {
+    store: Arc<dyn PairingStore>,
+    cache: Arc<RwLock<Cache>>,
+    changes: broadcast::Sender<PairingChange>,
+}
+
+impl std::fmt::Debug for PairingFileFinder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PairingFileFinder")
+            .field("cache", &self.cache)
+            .finish_non_exhaustive()
+    }
 }
 
 impl PairingFileFinder {
-    pub fn new(config: &NetmuxdConfig) -> Self {
+    pub fn new(store: Arc<dyn PairingStore>) -> Self {
         Self {
-            plist_storage: config.plist_storage.clone().unwrap_or(
-                match std::env::consts::OS {
-                    "macos" => "/var/db/lockdown",
-                    "linux" => "/var/lib/lockdown",
-                    "windows" => "C:/ProgramData/Apple/Lockdown",
-                    _ => panic!("Unsupported OS, specify a path"),
-                }
-                .to_string(),
-            ),
-            known_mac_addresses: HashMap::new(),
-            host_ids: HashMap::new(),
-            paired_udids: Vec::new(),
+            store,
+            cache: Arc::new(RwLock::new(Cache::default())),
+            changes: broadcast::channel(64).0,
         }
     }
 
-    pub fn plist_storage(&self) -> &str {
-        &self.plist_storage
+    pub fn store(&self) -> &Arc<dyn PairingStore> {
+        &self.store
     }
 
-    pub async fn get_udid_from_mac(&mut self, mac: String) -> Result<String, ()> {
+    /// Records the cache has picked up since subscribing: on a re-scan, a
+    /// [`refresh_record`](Self::refresh_record), or a save or removal
+    /// through the finder.
+    pub fn subscribe(&self) -> broadcast::Receiver<PairingChange> {
+        self.changes.subscribe()
+    }
+
+    fn cache(&self) -> std::sync::RwLockReadGuard<'_, Cache> {
+        self.cache.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Cache-only MAC lookup.
+    pub fn udid_for_mac(&self, mac: &str) -> Option<String> {
+        self.cache().known_mac_addresses.get(mac).cloned()
+    }
+
+    pub async fn get_udid_from_mac(&self, mac: String) -> Result<String, ()> {
         debug!("Getting UDID for MAC: {:?}", mac);
-        if let Some(udid) = self.known_mac_addresses.get(&mac) {
+        if let Some(udid) = self.udid_for_mac(&mac) {
             debug!("Found UDID: {:?}", udid);
-            return Ok(udid.to_string());
+            return Ok(udid);
         } else {
             trace!("No UDID found for {:?} in cache, re-caching...", mac);
         }
         self.update_cache().await;
 
-        if let Some(udid) = self.known_mac_addresses.get(&mac) {
+        if let Some(udid) = self.udid_for_mac(&mac) {
             info!("Found UDID: {:?}", udid);
-            return Ok(udid.to_string());
+            return Ok(udid);
         }
         trace!("No UDID found after a re-cache");
         Err(())
@@ -69,23 +147,11 @@ impl PairingFileFinder {
     /// `auth_tags` are the raw TXT values for `authTag`, `authTag#0`, `authTag#1`, etc
     /// base64-encoded 8-byte tags; this function decodes them.
     pub async fn find_udid_from_txt(
-        &mut self,
+        &self,
         identifier: &[u8],
         auth_tags: &[&[u8]],
     ) -> Option<String> {
-        if auth_tags.is_empty() {
-            return None;
-        }
-        // Decode all tags up front (they're independent of the candidate HostID).
-        let decoded_tags: Vec<[u8; 8]> = auth_tags
-            .iter()
-            .filter_map(|t| idevice::mdns::decode_auth_tag(t))
-            .collect();
-        if decoded_tags.is_empty() {
-            debug!("TXT record had authTag(s) but none decoded to 8 bytes");
-            return None;
-        }
-
+        let decoded_tags = decode_tags(auth_tags)?;
         if let Some(udid) = self.match_txt(identifier, &decoded_tags) {
             return Some(udid);
         }
@@ -94,8 +160,13 @@ impl PairingFileFinder {
         self.match_txt(identifier, &decoded_tags)
     }
 
+    /// Cache-only [`find_udid_from_txt`](Self::find_udid_from_txt).
+    pub fn udid_for_txt(&self, identifier: &[u8], auth_tags: &[&[u8]]) -> Option<String> {
+        self.match_txt(identifier, &decode_tags(auth_tags)?)
+    }
+
     fn match_txt(&self, identifier: &[u8], decoded_tags: &[[u8; 8]]) -> Option<String> {
-        for (udid, host_id) in &self.host_ids {
+        for (udid, host_id) in &self.cache().host_ids {
             let expected = idevice::mdns::derive_auth_tag(host_id, identifier);
             if decoded_tags.contains(&expected) {
                 info!("TXT record matched UDID {}", udid);
@@ -105,122 +176,97 @@ impl PairingFileFinder {
         None
     }
 
-    pub async fn update_cache(&mut self) {
-        // Iterate through all files in the plist storage, loading them into memory
+    /// Re-read every record in the store, dropping ones that are gone.
+    pub async fn update_cache(&self) {
         trace!("Updating plist cache");
-        let path = PathBuf::from(self.plist_storage.clone());
-        for entry in std::fs::read_dir(path).expect("Plist storage is unreadable!!") {
-            let entry = match entry {
-                Ok(e) => e,
-                Err(e) => {
-                    warn!("Unable to read entry in plist storage: {e:?}");
-                    continue;
-                }
-            };
-            let path = entry.path();
-            trace!("Attempting to read {:?}", path);
-            if path.is_file() {
-                let mut file = match tokio::fs::File::open(&path).await {
-                    Ok(f) => f,
-                    Err(e) => {
-                        warn!("Unable to read plist storage entry to memory: {e:?}");
-                        continue;
-                    }
-                };
-                let mut contents = Vec::new();
-                let plist: plist::Dictionary = match file.read_to_end(&mut contents).await {
-                    Ok(_) => match plist::from_bytes(&contents) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            warn!("Unable to parse entry file to plist: {e:?}");
-                            continue;
-                        }
-                    },
-                    Err(e) => {
-                        trace!("Could not read plist to memory: {e:?}");
-                        continue;
-                    }
-                };
-                let udid = match plist.get("UDID") {
-                    Some(plist::Value::String(u)) => Some(u.clone()),
-                    _ => None,
-                };
-
-                let udid = if let Some(udid) = udid {
-                    udid
-                } else {
-                    match path.file_name() {
-                        Some(f) => match f.to_str() {
-                            Some(f) => f.split('.').collect::<Vec<&str>>()[0].to_string(),
-                            None => {
-                                warn!("Failed to get entry file name string");
-                                continue;
-                            }
-                        },
-                        None => {
-                            trace!("File had no name");
-                            continue;
-                        }
-                    }
-                };
-
-                let stem = match path.file_stem() {
-                    Some(s) => s.to_string_lossy().to_string(),
-                    None => {
-                        warn!("Failed to get file stem for entry");
-                        continue;
-                    }
-                };
-
-                // Legacy: index by WiFiMACAddress if present (iOS < 26.4 devices).
-                if let Some(plist::Value::String(mac)) = plist.get("WiFiMACAddress") {
-                    self.known_mac_addresses.insert(mac.clone(), stem.clone());
-                }
-
-                // iOS 26.4+: index by HostID for TXT-based matching.
-                if let Some(plist::Value::String(host_id)) = plist.get("HostID") {
-                    self.host_ids
-                        .insert(stem.clone(), host_id.as_bytes().to_vec());
-                } else {
-                    debug!("Plist {stem:?} has no HostID; TXT-based lookup will skip it");
-                }
-                if self.paired_udids.contains(&udid) {
-                    trace!("Cache already contained this UDID");
-                    continue;
-                }
-                trace!("Adding {} to plist cache", udid);
-                self.paired_udids.push(udid);
+        let udids = match self.store.list().await {
+            Ok(u) => u,
+            Err(e) => {
+                warn!("Unable to list pairing records: {e:?}");
+                return;
             }
+        };
+        for udid in &udids {
+            self.refresh_record(udid).await;
+        }
+        let gone: Vec<String> = self
+            .cache()
+            .records
+            .keys()
+            .filter(|u| !udids.contains(*u))
+            .cloned()
+            .collect();
+        for udid in gone {
+            self.apply(&udid, None);
+        }
+    }
+
+    /// Re-read `udid`'s record, e.g. after it was changed on disk.
+    pub async fn refresh_record(&self, udid: &str) {
+        trace!("Attempting to read pairing record for {udid}");
+        match self.store.get(udid).await {
+            Ok(record) => self.apply(udid, record),
+            Err(e) => warn!("Unable to read pairing record for {udid}: {e:?}"),
         }
     }
 
+    /// Put `record` (`None` if there isn't one) in the cache and tell
+    /// subscribers if that changed anything.
+    fn apply(&self, udid: &str, record: Option<Vec<u8>>) {
+        let change = {
+            let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+            if cache.records.get(udid) == record.as_ref() {
+                return;
+            }
+            let existed = cache.remove(udid);
+            let exists = match record {
+                Some(r) => cache.insert(udid, r),
+                None => false,
+            };
+            match (existed, exists) {
+                (false, true) => RecordChange::Added,
+                (true, true) => RecordChange::Changed,
+                (true, false) => RecordChange::Removed,
+                (false, false) => return,
+            }
+        };
+        debug!("Pairing record for {udid}: {change:?}");
+        // Nobody listening is fine.
+        let _ = self.changes.send(PairingChange {
+            udid: udid.to_string(),
+            change,
+        });
+    }
+
     pub async fn get_pairing_record(&self, udid: &String) -> Result<PairingFile, IdeviceError> {
-        let path = PathBuf::from(self.plist_storage.clone()).join(format!("{}.plist", udid));
-        info!("Attempting to read pairing file: {path:?}");
-        if !path.exists() {
-            warn!("No pairing record found for device: {:?}", udid);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "No pairing record for the device",
-            )
-            .into());
+        info!("Attempting to read pairing record for device: {udid:?}");
+        match self.store.get(udid).await? {
+            Some(contents) => Ok(PairingFile::from_bytes(&contents)?),
+            None => {
+                warn!("No pairing record found for device: {:?}", udid);
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "No pairing record for the device",
+                )
+                .into())
+            }
         }
-        // Read the file
-        info!("Reading pairing record for device: {:?}", udid);
-        let mut file = tokio::fs::File::open(path).await?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents).await?;
-        let p = PairingFile::from_bytes(&contents)?;
-        Ok(p)
+    }
+
+    /// Store `record` (plist bytes) as `udid`'s pairing record. Subscribers
+    /// hear about it right away rather than on the next cache miss, so
+    /// services from the newly paired device can be matched.
+    pub async fn save_pairing_record(&self, udid: &str, record: &[u8]) -> std::io::Result<()> {
+        info!("Saving pairing record for device: {udid:?}");
+        self.store.save(udid, record).await?;
+        self.apply(udid, Some(record.to_vec()));
+        Ok(())
     }
 
     pub async fn remove_pairing_record(&self, udid: &str) -> std::io::Result<()> {
-        let path = PathBuf::from(self.plist_storage.clone()).join(format!("{udid}.plist"));
-        match tokio::fs::remove_file(&path).await {
-            Ok(()) => Ok(()),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-            Err(e) => Err(e),
-        }
+        self.store.delete(udid).await?;
+        self.apply(udid, None);
+        Ok(())
     }
 
     pub async fn get_buid(&self) -> Result<String, std::io::Error> {
@@ -229,16 +275,11 @@ impl PairingFileFinder {
     }
 
     /// Returns the local (HostID, SystemBUID) used when pairing with
-    /// new devices. Reads `SystemConfiguration.plist` from
-    /// `plist_storage` and lazily creates either field if missing,
-    /// writing the file back so other muxers see the same identity.
+    /// new devices. Reads `SystemConfiguration` from the store and lazily
+    /// creates either field if missing, writing it back so other muxers
+    /// see the same identity.
     pub async fn get_host_identity(&self) -> Result<(String, String), std::io::Error> {
-        let path = PathBuf::from(self.plist_storage.clone()).join("SystemConfiguration.plist");
-
-        let mut plist = if path.exists() {
-            let mut file = tokio::fs::File::open(&path).await?;
-            let mut contents = Vec::new();
-            file.read_to_end(&mut contents).await?;
+        let mut plist = if let Some(contents) = self.store.system_configuration().await? {
             plist::from_bytes::<plist::Dictionary>(&contents).unwrap_or_else(|e| {
                 warn!("Failed to parse SystemConfiguration.plist ({e:?}), regenerating");
                 plist::Dictionary::new()
@@ -277,13 +318,10 @@ impl PairingFileFinder {
             debug!("Persisting SystemConfiguration.plist with new identity field(s)");
             // Best-effort write; if it fails the caller still gets
             // the in-memory identity for this session.
-            if let Some(parent) = path.parent() {
-                let _ = tokio::fs::create_dir_all(parent).await;
-            }
             let mut buf = Vec::new();
             if let Err(e) = plist::to_writer_xml(&mut buf, &plist) {
                 warn!("Failed to serialize SystemConfiguration.plist: {e:?}");
-            } else if let Err(e) = tokio::fs::write(&path, &buf).await {
+            } else if let Err(e) = self.store.save_system_configuration(&buf).await {
                 warn!("Failed to write SystemConfiguration.plist: {e:?}");
             }
         }
@@ -291,3 +329,19 @@ impl PairingFileFinder {
         Ok((host_id, system_buid))
     }
 }
+
+/// Decode all tags up front (they're independent of the candidate HostID).
+fn decode_tags(auth_tags: &[&[u8]]) -> Option<Vec<[u8; 8]>> {
+    if auth_tags.is_empty() {
+        return None;
+    }
+    let decoded_tags: Vec<[u8; 8]> = auth_tags
+        .iter()
+        .filter_map(|t| idevice::mdns::decode_auth_tag(t))
+        .collect();
+    if decoded_tags.is_empty() {
+        debug!("TXT record had authTag(s) but none decoded to 8 bytes");
+        return None;
+    }
+    Some(decoded_tags)
+}
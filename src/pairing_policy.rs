@@ -0,0 +1,105 @@
+// Jackson Coxson
+//
+// Which USB devices netmuxd pairs with on its own. Pairing puts a Trust
+// prompt on the device and, once accepted, leaves a record that grants full
+// lockdown access, so a shared host may want an operator to decide, or only
+// pair devices it was told about.
+
+use std::time::Duration;
+
+/// When to start pairing a USB device that has no pairing record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PairingMode {
+    /// Pair every device, like usbmuxd.
+    #[default]
+    Auto,
+    /// Pair only devices on the allowlist.
+    Allowlist,
+    /// Pair allowlisted devices; wait for `ApprovePairing` for the rest.
+    Manual,
+    /// Never pair; only devices that already have a record are used.
+    Never,
+}
+
+impl PairingMode {
+    const ALL: [PairingMode; 4] = [Self::Auto, Self::Allowlist, Self::Manual, Self::Never];
+
+    /// Name used in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Allowlist => "allowlist",
+            Self::Manual => "manual",
+            Self::Never => "never",
+        }
+    }
+}
+
+impl std::str::FromStr for PairingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|m| m.name() == s)
+            .ok_or_else(|| "expected auto, allowlist, manual, or never".to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingPolicy {
+    pub mode: PairingMode,
+    /// UDIDs or USB serial numbers that may pair without asking.
+    pub allowlist: Vec<String>,
+    /// How long the Trust prompt may go unanswered before trying again.
+    pub timeout: Duration,
+    /// Further attempts after a timeout or failure.
+    pub retries: u32,
+}
+
+impl Default for PairingPolicy {
+    fn default() -> Self {
+        Self {
+            mode: PairingMode::Auto,
+            allowlist: Vec::new(),
+            timeout: Duration::from_secs(120),
+            retries: 2,
+        }
+    }
+}
+
+impl PairingPolicy {
+    /// Whether the allowlist names the device by either of its identifiers.
+    pub fn allowlisted(&self, serial: &str, udid: &str) -> bool {
+        self.allowlist
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(serial) || a.eq_ignore_ascii_case(udid))
+    }
+}
+
+/// Where an unpaired USB device is in the pairing flow. Published to `Listen`
+/// clients that ask for pairing events, and listed by `ListPairings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingState {
+    /// Waiting for an operator to send `ApprovePairing` or `DenyPairing`.
+    AwaitingApproval,
+    /// The Trust prompt is up on the device.
+    AwaitingTrust,
+    /// Refused by policy or by an operator; nothing was asked of the device.
+    Denied,
+    /// Every attempt timed out or failed.
+    Failed,
+    Paired,
+}
+
+impl PairingState {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::AwaitingApproval => "AwaitingApproval",
+            Self::AwaitingTrust => "AwaitingTrust",
+            Self::Denied => "Denied",
+            Self::Failed => "Failed",
+            Self::Paired => "Paired",
+        }
+    }
+}
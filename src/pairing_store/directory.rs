@@ -0,0 +1,132 @@
+// Jackson Coxson
+//
+// The lockdown directory layout shared with usbmuxd and Apple's muxers:
+// `<UDID>.plist` per device plus `SystemConfiguration.plist`.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use super::{PairingStore, StoreFuture};
+
+pub(crate) const SYSTEM_CONFIGURATION: &str = "SystemConfiguration";
+
+#[derive(Debug, Clone)]
+pub struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Where this OS's usbmuxd keeps pairing records.
+    pub fn default_path() -> PathBuf {
+        match std::env::consts::OS {
+            "macos" => "/var/db/lockdown",
+            "linux" => "/var/lib/lockdown",
+            "windows" => "C:/ProgramData/Apple/Lockdown",
+            _ => panic!("Unsupported OS, specify a path"),
+        }
+        .into()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `<name>.plist` in the directory. UDIDs come from clients
+    /// (`SavePairRecord`), so anything that could leave it is refused.
+    fn file(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid pairing record name {name:?}"),
+            ));
+        }
+        Ok(self.path.join(format!("{name}.plist")))
+    }
+
+    /// The file holding `udid`'s record. `SystemConfiguration.plist` holds
+    /// the host's identity, so it can't be read or replaced as a device's.
+    fn record(&self, udid: &str) -> io::Result<PathBuf> {
+        if udid == SYSTEM_CONFIGURATION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{udid:?} is reserved"),
+            ));
+        }
+        self.file(udid)
+    }
+
+    async fn read(&self, path: PathBuf) -> io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn write(&self, path: PathBuf, contents: &[u8]) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.path).await?;
+        tokio::fs::write(path, contents).await
+    }
+}
+
+impl PairingStore for DirectoryStore {
+    fn get<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move { self.read(self.record(udid)?).await })
+    }
+
+    fn save<'a>(&'a self, udid: &'a str, record: &'a [u8]) -> StoreFuture<'a, ()> {
+        Box::pin(async move { self.write(self.record(udid)?, record).await })
+    }
+
+    fn delete<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.record(udid)?).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn list(&self) -> StoreFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut entries = match tokio::fs::read_dir(&self.path).await {
+                Ok(e) => e,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+            let mut udids = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if !path.is_file() || path.extension().is_none_or(|e| e != "plist") {
+                    continue;
+                }
+                let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                if stem != SYSTEM_CONFIGURATION {
+                    udids.push(stem.to_string());
+                }
+            }
+            Ok(udids)
+        })
+    }
+
+    fn system_configuration(&self) -> StoreFuture<'_, Option<Vec<u8>>> {
+        Box::pin(async move { self.read(self.file(SYSTEM_CONFIGURATION)?).await })
+    }
+
+    fn save_system_configuration<'a>(&'a self, config: &'a [u8]) -> StoreFuture<'a, ()> {
+        Box::pin(async move { self.write(self.file(SYSTEM_CONFIGURATION)?, config).await })
+    }
+
+    fn watch_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
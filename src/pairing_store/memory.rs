@@ -0,0 +1,79 @@
+// Jackson Coxson
+//
+// Records that only live as long as the process, for containers and
+// embedders that hand netmuxd its pairing records at startup.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use super::{PairingStore, StoreFuture};
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    records: Mutex<HashMap<String, Vec<u8>>>,
+    system_configuration: Mutex<Option<Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PairingStore for MemoryStore {
+    fn get<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, Option<Vec<u8>>> {
+        let record = self
+            .records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(udid)
+            .cloned();
+        Box::pin(async move { Ok(record) })
+    }
+
+    fn save<'a>(&'a self, udid: &'a str, record: &'a [u8]) -> StoreFuture<'a, ()> {
+        self.records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(udid.to_string(), record.to_vec());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, ()> {
+        self.records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(udid);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn list(&self) -> StoreFuture<'_, Vec<String>> {
+        let udids = self
+            .records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(udids) })
+    }
+
+    fn system_configuration(&self) -> StoreFuture<'_, Option<Vec<u8>>> {
+        let config = self
+            .system_configuration
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        Box::pin(async move { Ok(config) })
+    }
+
+    fn save_system_configuration<'a>(&'a self, config: &'a [u8]) -> StoreFuture<'a, ()> {
+        *self
+            .system_configuration
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(config.to_vec());
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -0,0 +1,237 @@
+// Jackson Coxson
+//
+// Where pairing records and the host identity are kept. `PairingFileFinder`
+// does the parsing, caching and matching on top; a store only moves bytes.
+// The default is the lockdown directory every other muxer reads, but a
+// container or a fleet of hosts can keep them in memory or in SQLite instead.
+
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use crate::config::NetmuxdConfig;
+
+mod directory;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use directory::DirectoryStore;
+pub(crate) use directory::SYSTEM_CONFIGURATION;
+pub use memory::MemoryStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// Storage for pairing records, keyed by UDID, and the host's
+/// `SystemConfiguration` (HostID and SystemBUID). Both are plist bytes.
+pub trait PairingStore: Send + Sync + 'static {
+    /// `udid`'s record, or `None` if it has none.
+    fn get<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, Option<Vec<u8>>>;
+
+    /// Create or replace `udid`'s record.
+    fn save<'a>(&'a self, udid: &'a str, record: &'a [u8]) -> StoreFuture<'a, ()>;
+
+    /// Remove `udid`'s record. Not an error if it has none.
+    fn delete<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, ()>;
+
+    /// Every UDID with a record.
+    fn list(&self) -> StoreFuture<'_, Vec<String>>;
+
+    /// The saved `SystemConfiguration`, if there is one.
+    fn system_configuration(&self) -> StoreFuture<'_, Option<Vec<u8>>>;
+
+    fn save_system_configuration<'a>(&'a self, config: &'a [u8]) -> StoreFuture<'a, ()>;
+
+    /// A directory other programs may change records in behind our back,
+    /// holding `<UDID>.plist` files. Watched for changes if there is one.
+    fn watch_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Which [`PairingStore`] the server opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreKind {
+    /// `*.plist` files in `plist_storage`.
+    #[default]
+    Directory,
+    /// Nothing is written to disk; records are lost on exit.
+    Memory,
+    /// A SQLite database at `pairing_database`.
+    Sqlite,
+}
+
+impl StoreKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Directory => "directory",
+            Self::Memory => "memory",
+            Self::Sqlite => "sqlite",
+        }
+    }
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "directory" => Ok(Self::Directory),
+            "memory" => Ok(Self::Memory),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err("expected directory, memory, or sqlite".to_string()),
+        }
+    }
+}
+
+/// Errors from [`open`].
+#[derive(Debug, thiserror::Error)]
+pub enum PairingStoreError {
+    #[error("failed to open pairing database {path:?}: {source}")]
+    Open { path: PathBuf, source: io::Error },
+    #[error("the sqlite pairing store needs a pairing_database path")]
+    NoDatabase,
+    #[error("this build has no SQLite support; rebuild with the `sqlite` feature")]
+    NoSqlite,
+}
+
+/// Open the store `config` asks for.
+pub fn open(config: &NetmuxdConfig) -> Result<Arc<dyn PairingStore>, PairingStoreError> {
+    match config.pairing_store {
+        StoreKind::Directory => Ok(Arc::new(DirectoryStore::new(
+            config
+                .plist_storage
+                .clone()
+                .map(PathBuf::from)
+                .unwrap_or_else(DirectoryStore::default_path),
+        ))),
+        StoreKind::Memory => Ok(Arc::new(MemoryStore::new())),
+        StoreKind::Sqlite => {
+            let path = config
+                .pairing_database
+                .clone()
+                .ok_or(PairingStoreError::NoDatabase)?;
+            #[cfg(feature = "sqlite")]
+            {
+                match SqliteStore::open(&path) {
+                    Ok(store) => Ok(Arc::new(store)),
+                    Err(source) => Err(PairingStoreError::Open { path, source }),
+                }
+            }
+            #[cfg(not(feature = "sqlite"))]
+            {
+                let _ = path;
+                Err(PairingStoreError::NoSqlite)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own, removed afterwards.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("netmuxd-store-test-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn sorted_list(store: &dyn PairingStore) -> Vec<String> {
+        let mut udids = store.list().await.unwrap();
+        udids.sort();
+        udids
+    }
+
+    /// What every store has to do the same way.
+    async fn check_store(store: &dyn PairingStore) {
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert!(store.list().await.unwrap().is_empty());
+        assert_eq!(store.system_configuration().await.unwrap(), None);
+
+        store.save("a", b"one").await.unwrap();
+        store.save("b", b"two").await.unwrap();
+        store.save("a", b"three").await.unwrap();
+        assert_eq!(
+            store.get("a").await.unwrap().as_deref(),
+            Some(&b"three"[..])
+        );
+        assert_eq!(sorted_list(store).await, ["a", "b"]);
+
+        // The host identity isn't a record.
+        store.save_system_configuration(b"host").await.unwrap();
+        assert_eq!(
+            store.system_configuration().await.unwrap().as_deref(),
+            Some(&b"host"[..])
+        );
+        assert_eq!(sorted_list(store).await, ["a", "b"]);
+
+        store.delete("a").await.unwrap();
+        // Deleting what isn't there is fine.
+        store.delete("a").await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(sorted_list(store).await, ["b"]);
+    }
+
+    #[tokio::test]
+    async fn memory_store() {
+        check_store(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn directory_store() {
+        let dir = TempDir::new();
+        check_store(&DirectoryStore::new(&dir.0)).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_store() {
+        let dir = TempDir::new();
+        check_store(&SqliteStore::open(&dir.0.join("pairing.db")).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn directory_store_refuses_names_outside_it() {
+        let dir = TempDir::new();
+        let store = DirectoryStore::new(dir.0.join("lockdown"));
+        for name in ["../x", ".x", "a/b", "a\\b", ""] {
+            let err = store.save(name, b"record").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{name:?}");
+            assert!(store.get(name).await.is_err(), "{name:?}");
+            assert!(store.delete(name).await.is_err(), "{name:?}");
+        }
+        assert!(!dir.0.join("x.plist").exists());
+    }
+
+    #[tokio::test]
+    async fn directory_store_keeps_records_off_the_host_identity() {
+        let dir = TempDir::new();
+        let store = DirectoryStore::new(&dir.0);
+        store.save_system_configuration(b"host").await.unwrap();
+
+        assert!(store.save(SYSTEM_CONFIGURATION, b"record").await.is_err());
+        assert!(store.get(SYSTEM_CONFIGURATION).await.is_err());
+        assert!(store.delete(SYSTEM_CONFIGURATION).await.is_err());
+        assert_eq!(
+            store.system_configuration().await.unwrap().as_deref(),
+            Some(&b"host"[..])
+        );
+        assert!(store.list().await.unwrap().is_empty());
+    }
+}
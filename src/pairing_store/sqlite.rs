@@ -0,0 +1,129 @@
+// Jackson Coxson
+//
+// Pairing records in a SQLite database, for hosts that share one file (or
+// back it up) instead of a lockdown directory. Queries run on the blocking
+// pool; the connection is shared behind a mutex.
+
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use super::{PairingStore, StoreFuture};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS pair_records (
+        udid TEXT PRIMARY KEY NOT NULL,
+        record BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS system_configuration (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        data BLOB NOT NULL
+    );
+";
+
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(path).map_err(io::Error::other)?;
+        conn.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` against the connection on the blocking pool.
+    fn with_conn<'a, T, F>(&self, f: F) -> StoreFuture<'a, T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+                f(&conn).map_err(io::Error::other)
+            })
+            .await
+            .map_err(io::Error::other)?
+        })
+    }
+}
+
+impl PairingStore for SqliteStore {
+    fn get<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, Option<Vec<u8>>> {
+        let udid = udid.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT record FROM pair_records WHERE udid = ?1",
+                params![udid],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+    }
+
+    fn save<'a>(&'a self, udid: &'a str, record: &'a [u8]) -> StoreFuture<'a, ()> {
+        let (udid, record) = (udid.to_string(), record.to_vec());
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO pair_records (udid, record) VALUES (?1, ?2)
+                 ON CONFLICT(udid) DO UPDATE SET record = excluded.record",
+                params![udid, record],
+            )
+            .map(|_| ())
+        })
+    }
+
+    fn delete<'a>(&'a self, udid: &'a str) -> StoreFuture<'a, ()> {
+        let udid = udid.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM pair_records WHERE udid = ?1", params![udid])
+                .map(|_| ())
+        })
+    }
+
+    fn list(&self) -> StoreFuture<'_, Vec<String>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT udid FROM pair_records ORDER BY udid")?;
+            let udids = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>();
+            udids
+        })
+    }
+
+    fn system_configuration(&self) -> StoreFuture<'_, Option<Vec<u8>>> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT data FROM system_configuration WHERE id = 0",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+    }
+
+    fn save_system_configuration<'a>(&'a self, config: &'a [u8]) -> StoreFuture<'a, ()> {
+        let config = config.to_vec();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO system_configuration (id, data) VALUES (0, ?1)
+                 ON CONFLICT(id) DO UPDATE SET data = excluded.data",
+                params![config],
+            )
+            .map(|_| ())
+        })
+    }
+}
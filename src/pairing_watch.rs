@@ -0,0 +1,210 @@
+// Jackson Coxson
+//
+// Keeps the pairing record cache in step with the lockdown directory when
+// another program (usbmuxd, idevicepair) changes it, instead of waiting for
+// a cache miss that never comes for a replaced or deleted record. Network
+// devices whose record changed or went away are re-checked by the manager,
+// since their lockdown session was made with the old one, and USB devices
+// listed as unpaired stop being so once they have one.
+
+use std::{collections::HashSet, path::Path, time::Duration};
+
+use log::{debug, info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    manager::{ManagerRequest, ManagerRequestType, ManagerSender},
+    pairing_file::{PairingFileFinder, RecordChange},
+    pairing_store::SYSTEM_CONFIGURATION,
+};
+
+/// Writers often touch a file several times in a row; wait for them to
+/// finish before reading it.
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// Watch the store's directory, if it has one, and pass record changes on
+/// to the manager until the manager goes away.
+pub async fn run(finder: PairingFileFinder, sender: ManagerSender) {
+    // A record that was never cached would look added when it changes and
+    // go unnoticed when it's deleted, so cache them all first.
+    finder.update_cache().await;
+    let mut changes = finder.subscribe();
+    let (tx, rx) = mpsc::unbounded_channel();
+    // Dropping the watcher stops it, so it lives as long as this task.
+    let _watcher = finder.store().watch_path().and_then(|path| watch(path, tx));
+    tokio::spawn(apply_events(finder, rx));
+
+    loop {
+        let change = match changes.recv().await {
+            Ok(c) => c,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Missed {n} pairing record changes");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let request_type = match change.change {
+            RecordChange::Added => ManagerRequestType::PairingRecordAdded { udid: change.udid },
+            _ => ManagerRequestType::PairingRecordChanged { udid: change.udid },
+        };
+        if sender
+            .send(ManagerRequest {
+                request_type,
+                response: None,
+            })
+            .await
+            .is_err()
+        {
+            debug!("Manager is gone, no longer watching pairing records");
+            return;
+        }
+    }
+}
+
+fn watch(
+    path: &Path,
+    tx: mpsc::UnboundedSender<notify::Result<notify::Event>>,
+) -> Option<RecommendedWatcher> {
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    }) {
+        Ok(w) => w,
+        Err(e) => {
+            warn!("Unable to watch pairing records: {e}");
+            return None;
+        }
+    };
+    if let Err(e) = watcher.watch(path, RecursiveMode::NonRecursive) {
+        warn!("Unable to watch {path:?} for pairing record changes: {e}");
+        return None;
+    }
+    info!("Watching {path:?} for pairing record changes");
+    Some(watcher)
+}
+
+/// Refresh the records named in filesystem events, once they settle.
+async fn apply_events(
+    finder: PairingFileFinder,
+    mut rx: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+) {
+    let mut pending: HashSet<String> = HashSet::new();
+    let mut rescan = false;
+    loop {
+        let event = if pending.is_empty() && !rescan {
+            rx.recv().await
+        } else {
+            match tokio::time::timeout(SETTLE, rx.recv()).await {
+                Ok(e) => e,
+                Err(_) => {
+                    if std::mem::take(&mut rescan) {
+                        pending.clear();
+                        finder.update_cache().await;
+                    }
+                    for udid in pending.drain() {
+                        finder.refresh_record(&udid).await;
+                    }
+                    continue;
+                }
+            }
+        };
+        let event = match event {
+            Some(Ok(e)) => e,
+            Some(Err(e)) => {
+                warn!("Pairing record watcher error: {e}");
+                continue;
+            }
+            None => return,
+        };
+        // Events were dropped; only a full re-scan is safe.
+        if event.need_rescan() {
+            rescan = true;
+            continue;
+        }
+        if matches!(event.kind, EventKind::Access(_)) {
+            continue;
+        }
+        pending.extend(event.paths.iter().filter_map(|p| record_name(p)));
+    }
+}
+
+/// The UDID a `<UDID>.plist` path holds the record for.
+fn record_name(path: &Path) -> Option<String> {
+    if path.extension()? != "plist" {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    (stem != SYSTEM_CONFIGURATION).then(|| stem.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{
+        manager::{ManagerReceiver, new_channel_pair},
+        pairing_store::DirectoryStore,
+    };
+
+    const UDID: &str = "00008030-001A2B3C4D5E6F70";
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn record(host_id: &str) -> Vec<u8> {
+        let mut dict = plist::Dictionary::new();
+        dict.insert("HostID".into(), host_id.into());
+        dict.insert("WiFiMACAddress".into(), MAC.into());
+        let mut out = Vec::new();
+        plist::to_writer_xml(&mut out, &dict).unwrap();
+        out
+    }
+
+    async fn next_request(rx: &ManagerReceiver) -> ManagerRequestType {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("a request in time")
+            .expect("watcher is running")
+            .request_type
+    }
+
+    #[tokio::test]
+    async fn uncached_records_report_changes_and_removals() {
+        let dir = TempDir(
+            std::env::temp_dir().join(format!("netmuxd-watch-test-{}", uuid::Uuid::new_v4())),
+        );
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let file = dir.0.join(format!("{UDID}.plist"));
+        std::fs::write(&file, record("old")).unwrap();
+
+        // Nothing has looked the record up, as with a static device.
+        let finder = PairingFileFinder::new(Arc::new(DirectoryStore::new(&dir.0)));
+        assert!(finder.udid_for_mac(MAC).is_none());
+        let (sender, rx) = new_channel_pair();
+        tokio::spawn(run(finder.clone(), sender));
+        // On this runtime, the watcher is up once the cache is.
+        while finder.udid_for_mac(MAC).is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        std::fs::write(&file, record("new")).unwrap();
+        match next_request(&rx).await {
+            ManagerRequestType::PairingRecordChanged { udid } => assert_eq!(udid, UDID),
+            _ => panic!("expected PairingRecordChanged"),
+        }
+
+        std::fs::remove_file(&file).unwrap();
+        match next_request(&rx).await {
+            ManagerRequestType::PairingRecordChanged { udid } => assert_eq!(udid, UDID),
+            _ => panic!("expected PairingRecordChanged"),
+        }
+        assert!(finder.udid_for_mac(MAC).is_none());
+    }
+}
@@ -0,0 +1,199 @@
+// Jackson Coxson
+//
+// Which transport to use for a device that netmuxd sees over both USB and the
+// network. Each transport gets its own DeviceID; the policy decides which of
+// them clients see and which one a `Connect` actually goes over.
+
+use std::collections::HashMap;
+
+/// How to treat a device reachable over more than one transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionPolicy {
+    /// List every transport separately and connect over the one asked for,
+    /// like usbmuxd.
+    #[default]
+    Both,
+    /// List only the USB entry while the device is plugged in.
+    PreferUsb,
+    /// List only the network entry while the device is on the network.
+    PreferNetwork,
+    /// Never list or connect over the network.
+    UsbOnly,
+    /// Never list or connect over USB.
+    NetworkOnly,
+}
+
+impl ConnectionPolicy {
+    const ALL: [ConnectionPolicy; 5] = [
+        Self::Both,
+        Self::PreferUsb,
+        Self::PreferNetwork,
+        Self::UsbOnly,
+        Self::NetworkOnly,
+    ];
+
+    /// Name used in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Both => "both",
+            Self::PreferUsb => "prefer-usb",
+            Self::PreferNetwork => "prefer-network",
+            Self::UsbOnly => "usb-only",
+            Self::NetworkOnly => "network-only",
+        }
+    }
+
+    /// Whether this policy ever uses `connection_type`.
+    pub fn allows(self, connection_type: &str) -> bool {
+        match self {
+            Self::UsbOnly => is_usb(connection_type),
+            Self::NetworkOnly => !is_usb(connection_type),
+            _ => true,
+        }
+    }
+
+    /// The transport to favour, if any.
+    fn preferred(self) -> Option<bool> {
+        match self {
+            Self::PreferUsb | Self::UsbOnly => Some(true),
+            Self::PreferNetwork | Self::NetworkOnly => Some(false),
+            Self::Both => None,
+        }
+    }
+
+    /// Which of a device's entries (given as `(id, connection_type)`) should
+    /// be listed to clients.
+    pub fn visible(self, entries: &[(u64, &str)]) -> Vec<u64> {
+        let allowed: Vec<(u64, &str)> = entries
+            .iter()
+            .copied()
+            .filter(|(_, ct)| self.allows(ct))
+            .collect();
+        match self.preferred() {
+            Some(usb) if allowed.iter().any(|(_, ct)| is_usb(ct) == usb) => allowed
+                .into_iter()
+                .filter(|(_, ct)| is_usb(ct) == usb)
+                .map(|(id, _)| id)
+                .collect(),
+            _ => allowed.into_iter().map(|(id, _)| id).collect(),
+        }
+    }
+
+    /// Order a device's entries for a `Connect` to `requested`: best first,
+    /// then the fallbacks. Entries the policy forbids are left out. Under
+    /// `Both` it's only `requested`, if it's still there.
+    pub fn route(self, requested: u64, entries: &[(u64, &str)]) -> Vec<u64> {
+        if self == Self::Both {
+            return entries
+                .iter()
+                .filter(|(id, _)| *id == requested)
+                .map(|(id, _)| *id)
+                .collect();
+        }
+        let mut allowed: Vec<(u64, &str)> = entries
+            .iter()
+            .copied()
+            .filter(|(_, ct)| self.allows(ct))
+            .collect();
+        // Stable sort: within a rank, the entry the client asked for wins.
+        allowed.sort_by_key(|(id, ct)| {
+            let rank = match self.preferred() {
+                Some(usb) => u8::from(is_usb(ct) != usb),
+                None => 0,
+            };
+            (rank, *id != requested)
+        });
+        allowed.into_iter().map(|(id, _)| id).collect()
+    }
+}
+
+impl std::str::FromStr for ConnectionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| {
+                "expected both, prefer-usb, prefer-network, usb-only, or network-only".to_string()
+            })
+    }
+}
+
+/// The default policy plus per-UDID overrides.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DevicePolicies {
+    pub default: ConnectionPolicy,
+    pub devices: HashMap<String, ConnectionPolicy>,
+}
+
+impl DevicePolicies {
+    pub fn for_udid(&self, udid: &str) -> ConnectionPolicy {
+        self.devices.get(udid).copied().unwrap_or(self.default)
+    }
+}
+
+fn is_usb(connection_type: &str) -> bool {
+    connection_type == "USB"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USB: (u64, &str) = (1, "USB");
+    const NET: (u64, &str) = (2, "Network");
+
+    #[test]
+    fn both_lists_everything_and_routes_only_the_request() {
+        let p = ConnectionPolicy::Both;
+        assert_eq!(p.visible(&[USB, NET]), [1, 2]);
+        assert_eq!(p.route(1, &[USB, NET]), [1]);
+        assert_eq!(p.route(2, &[USB, NET]), [2]);
+        // The transport asked for is gone; usbmuxd wouldn't switch either.
+        assert!(p.route(1, &[NET]).is_empty());
+    }
+
+    #[test]
+    fn prefer_lists_the_preferred_entry_while_it_is_there() {
+        let p = ConnectionPolicy::PreferUsb;
+        assert_eq!(p.visible(&[USB, NET]), [1]);
+        assert_eq!(p.visible(&[NET]), [2]);
+        assert_eq!(p.route(2, &[USB, NET]), [1, 2]);
+        assert_eq!(p.route(1, &[NET]), [2]);
+
+        let p = ConnectionPolicy::PreferNetwork;
+        assert_eq!(p.visible(&[USB, NET]), [2]);
+        assert_eq!(p.route(1, &[USB, NET]), [2, 1]);
+    }
+
+    #[test]
+    fn only_policies_never_use_the_other_transport() {
+        let p = ConnectionPolicy::UsbOnly;
+        assert_eq!(p.visible(&[USB, NET]), [1]);
+        assert!(p.visible(&[NET]).is_empty());
+        assert!(p.route(2, &[NET]).is_empty());
+
+        let p = ConnectionPolicy::NetworkOnly;
+        assert_eq!(p.visible(&[USB, NET]), [2]);
+        assert_eq!(p.route(1, &[USB, NET]), [2]);
+    }
+
+    #[test]
+    fn per_device_overrides() {
+        let policies = DevicePolicies {
+            default: ConnectionPolicy::PreferUsb,
+            devices: HashMap::from([("a".to_string(), ConnectionPolicy::NetworkOnly)]),
+        };
+        assert_eq!(policies.for_udid("a"), ConnectionPolicy::NetworkOnly);
+        assert_eq!(policies.for_udid("b"), ConnectionPolicy::PreferUsb);
+    }
+
+    #[test]
+    fn parses_config_names() {
+        for p in ConnectionPolicy::ALL {
+            assert_eq!(p.name().parse::<ConnectionPolicy>(), Ok(p));
+        }
+        assert!("usb".parse::<ConnectionPolicy>().is_err());
+    }
+}
@@ -0,0 +1,78 @@
+// Jackson Coxson
+//
+// Anything the server can pull client connections out of. The daemon binds
+// TCP and Unix listeners itself; embedders can hand in their own transport
+// (an in-process channel of duplex pipes, a TLS wrapper, ...) by implementing
+// `Acceptor`.
+
+use std::{future::Future, io, pin::Pin};
+
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::access::Peer;
+
+/// A bidirectional client stream the server can speak usbmuxd over.
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + ?Sized> AsyncReadWrite for T {}
+
+pub type BoxedStream = Box<dyn AsyncReadWrite>;
+
+pub type AcceptFuture<'a> =
+    Pin<Box<dyn Future<Output = io::Result<(BoxedStream, Peer)>> + Send + 'a>>;
+
+/// Source of client connections for [`super::NetmuxdServer`].
+pub trait Acceptor: Send + 'static {
+    /// Wait for the next client and say who it is, for access control. An
+    /// `Err` is logged and the accept loop keeps going; return
+    /// `ErrorKind::NotConnected` to end the loop for good.
+    fn accept(&mut self) -> AcceptFuture<'_>;
+}
+
+impl Acceptor for tokio::net::TcpListener {
+    fn accept(&mut self) -> AcceptFuture<'_> {
+        Box::pin(async move {
+            let (socket, addr) = tokio::net::TcpListener::accept(self).await?;
+            Ok((Box::new(socket) as BoxedStream, Peer::Tcp(addr)))
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for tokio::net::UnixListener {
+    fn accept(&mut self) -> AcceptFuture<'_> {
+        Box::pin(async move {
+            let (socket, _) = tokio::net::UnixListener::accept(self).await?;
+            let peer = match socket.peer_cred() {
+                Ok(cred) => Peer::Unix {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                },
+                Err(e) => {
+                    warn!("Failed to read Unix client credentials: {e:?}");
+                    Peer::Unknown
+                }
+            };
+            Ok((Box::new(socket) as BoxedStream, peer))
+        })
+    }
+}
+
+/// In-process clients: every stream sent on the channel is served as if it
+/// had connected over a socket. The loop ends once all senders are dropped.
+impl<S> Acceptor for tokio::sync::mpsc::UnboundedReceiver<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn accept(&mut self) -> AcceptFuture<'_> {
+        Box::pin(async move {
+            match self.recv().await {
+                Some(s) => Ok((Box::new(s) as BoxedStream, Peer::InProcess)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "all client senders dropped",
+                )),
+            }
+        })
+    }
+}
@@ -0,0 +1,269 @@
+// Jackson Coxson
+//
+// The legacy binary (version 0) usbmuxd protocol. Old libimobiledevice and a
+// few embedded tools still send packed structs instead of plists for Connect
+// and Listen, and expect Result / Attached / Detached back in the same form.
+// The 16-byte header is shared with the plist protocol; only the version
+// field and the body differ.
+
+use idevice::usbmuxd::server::UsbmuxdServerResponse;
+
+use super::error::MuxError;
+
+const MESSAGE_RESULT: u32 = 1;
+const MESSAGE_CONNECT: u32 = 2;
+const MESSAGE_LISTEN: u32 = 3;
+const MESSAGE_DEVICE_ADD: u32 = 4;
+const MESSAGE_DEVICE_REMOVE: u32 = 5;
+const MESSAGE_DEVICE_PAIRED: u32 = 6;
+
+/// `char serial_number[256]` in `struct usbmuxd_device_record`.
+const SERIAL_LEN: usize = 256;
+
+/// Wire version a client speaks, taken from the header of its first packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum PacketVersion {
+    Binary,
+    Plist,
+}
+
+impl PacketVersion {
+    pub fn from_header(version: u32) -> Option<Self> {
+        match version {
+            0 => Some(Self::Binary),
+            1 => Some(Self::Plist),
+            _ => None,
+        }
+    }
+
+    /// Encode a `Result` reply in this version's framing.
+    pub fn result(self, result: Result<(), MuxError>, tag: u32) -> Vec<u8> {
+        let code = result.err().map_or(0, MuxError::code);
+        match self {
+            Self::Binary => packet(MESSAGE_RESULT, tag, &code.to_le_bytes()),
+            Self::Plist => UsbmuxdServerResponse::Result(code).into_packet(tag).into(),
+        }
+    }
+}
+
+/// A decoded version-0 request.
+#[derive(Debug)]
+pub(super) enum BinaryRequest {
+    Connect { device_id: u32, port: u16 },
+    Listen,
+    Unknown(u32),
+}
+
+/// Decode the body of a version-0 request with the given message type.
+pub(super) fn decode(message: u32, body: &[u8]) -> BinaryRequest {
+    match message {
+        // struct { uint32_t device_id; uint16_t port; uint16_t reserved; }
+        // with the port in network byte order, same as the plist PortNumber.
+        MESSAGE_CONNECT if body.len() >= 6 => BinaryRequest::Connect {
+            device_id: u32::from_le_bytes(body[0..4].try_into().unwrap()),
+            port: u16::from_be_bytes(body[4..6].try_into().unwrap()),
+        },
+        MESSAGE_LISTEN => BinaryRequest::Listen,
+        other => BinaryRequest::Unknown(other),
+    }
+}
+
+/// Encode an `Attached` event from the manager's plist form. Returns `None`
+/// if the event has no usable DeviceID.
+pub(super) fn device_add(attached: &plist::Dictionary) -> Option<Vec<u8>> {
+    let device_id = attached
+        .get("DeviceID")
+        .and_then(|v| v.as_unsigned_integer())?;
+    let props = attached.get("Properties").and_then(|v| v.as_dictionary());
+    let prop_u64 = |key: &str| {
+        props
+            .and_then(|p| p.get(key))
+            .and_then(|v| v.as_unsigned_integer())
+            .unwrap_or(0)
+    };
+    let serial = props
+        .and_then(|p| p.get("SerialNumber"))
+        .and_then(|v| v.as_string())
+        .unwrap_or_default();
+
+    // struct usbmuxd_device_record {
+    //     uint32_t device_id; uint16_t product_id; char serial_number[256];
+    //     uint16_t padding; uint32_t location;
+    // }
+    let mut body = Vec::with_capacity(4 + 2 + SERIAL_LEN + 2 + 4);
+    body.extend_from_slice(&(device_id as u32).to_le_bytes());
+    body.extend_from_slice(&(prop_u64("ProductID") as u16).to_le_bytes());
+    let mut serial_buf = [0u8; SERIAL_LEN];
+    // Always leave room for the NUL terminator.
+    let n = serial.len().min(SERIAL_LEN - 1);
+    serial_buf[..n].copy_from_slice(&serial.as_bytes()[..n]);
+    body.extend_from_slice(&serial_buf);
+    body.extend_from_slice(&[0u8; 2]);
+    body.extend_from_slice(&(prop_u64("LocationID") as u32).to_le_bytes());
+
+    Some(packet(MESSAGE_DEVICE_ADD, 0, &body))
+}
+
+/// Encode a `Detached` event.
+pub(super) fn device_remove(device_id: u64) -> Vec<u8> {
+    packet(MESSAGE_DEVICE_REMOVE, 0, &(device_id as u32).to_le_bytes())
+}
+
+/// Encode a `Paired` event.
+pub(super) fn device_paired(device_id: u64) -> Vec<u8> {
+    packet(MESSAGE_DEVICE_PAIRED, 0, &(device_id as u32).to_le_bytes())
+}
+
+fn packet(message: u32, tag: u32, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + body.len());
+    buf.extend_from_slice(&((16 + body.len()) as u32).to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&message.to_le_bytes());
+    buf.extend_from_slice(&tag.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(packet: &[u8]) -> [u32; 4] {
+        std::array::from_fn(|i| u32::from_le_bytes(packet[i * 4..i * 4 + 4].try_into().unwrap()))
+    }
+
+    /// An `Attached` event as the manager builds it.
+    fn attached(device_id: Option<u64>, props: &[(&str, plist::Value)]) -> plist::Dictionary {
+        let mut p = plist::Dictionary::new();
+        if let Some(id) = device_id {
+            p.insert("DeviceID".into(), id.into());
+        }
+        p.insert("MessageType".into(), "Attached".into());
+        p.insert(
+            "Properties".into(),
+            plist::Value::Dictionary(
+                props
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+            ),
+        );
+        p
+    }
+
+    #[test]
+    fn device_add_matches_usbmuxd_device_record() {
+        let event = attached(
+            Some(7),
+            &[
+                ("SerialNumber", "00008030-001A2B3C4D5E6F70".into()),
+                ("ProductID", 0x12a8u64.into()),
+                ("LocationID", 0x1420000u64.into()),
+            ],
+        );
+        let packet = device_add(&event).unwrap();
+
+        // 16-byte header + sizeof(struct usbmuxd_device_record) == 268.
+        assert_eq!(packet.len(), 16 + 268);
+        assert_eq!(header(&packet), [284, 0, MESSAGE_DEVICE_ADD, 0]);
+        let body = &packet[16..];
+        assert_eq!(&body[0..4], &7u32.to_le_bytes());
+        assert_eq!(&body[4..6], &0x12a8u16.to_le_bytes());
+        let serial = &body[6..6 + SERIAL_LEN];
+        assert_eq!(&serial[..25], b"00008030-001A2B3C4D5E6F70");
+        assert!(serial[25..].iter().all(|b| *b == 0));
+        assert_eq!(&body[262..264], &[0, 0]);
+        assert_eq!(&body[264..268], &0x1420000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn device_add_keeps_serial_nul_terminated() {
+        let long = "A".repeat(300);
+        let event = attached(Some(1), &[("SerialNumber", long.into())]);
+        let packet = device_add(&event).unwrap();
+        let serial = &packet[16 + 6..16 + 6 + SERIAL_LEN];
+        assert!(serial[..SERIAL_LEN - 1].iter().all(|b| *b == b'A'));
+        assert_eq!(serial[SERIAL_LEN - 1], 0);
+        assert_eq!(packet.len(), 16 + 268);
+    }
+
+    #[test]
+    fn device_add_needs_a_device_id() {
+        let event = attached(None, &[("SerialNumber", "abc".into())]);
+        assert!(device_add(&event).is_none());
+    }
+
+    #[test]
+    fn device_remove_and_paired_carry_the_id() {
+        let removed = device_remove(9);
+        assert_eq!(header(&removed), [20, 0, MESSAGE_DEVICE_REMOVE, 0]);
+        assert_eq!(&removed[16..], &9u32.to_le_bytes());
+
+        let paired = device_paired(9);
+        assert_eq!(header(&paired), [20, 0, MESSAGE_DEVICE_PAIRED, 0]);
+        assert_eq!(&paired[16..], &9u32.to_le_bytes());
+    }
+
+    #[test]
+    fn binary_result_echoes_the_tag() {
+        let ok = PacketVersion::Binary.result(Ok(()), 42);
+        assert_eq!(header(&ok), [20, 0, MESSAGE_RESULT, 42]);
+        assert_eq!(&ok[16..], &0u32.to_le_bytes());
+
+        let refused = PacketVersion::Binary.result(Err(MuxError::ConnRefused), 3);
+        assert_eq!(header(&refused), [20, 0, MESSAGE_RESULT, 3]);
+        assert_eq!(&refused[16..], &3u32.to_le_bytes());
+    }
+
+    #[test]
+    fn plist_result_carries_the_number() {
+        let packet = PacketVersion::Plist.result(Err(MuxError::BadDevice), 5);
+        let [len, version, _, tag] = header(&packet);
+        assert_eq!((len as usize, version, tag), (packet.len(), 1, 5));
+        let body: plist::Dictionary = plist::from_bytes(&packet[16..]).unwrap();
+        assert_eq!(
+            body.get("MessageType").and_then(|v| v.as_string()),
+            Some("Result")
+        );
+        assert_eq!(
+            body.get("Number").and_then(|v| v.as_unsigned_integer()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn decodes_connect_with_network_order_port() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&3u32.to_le_bytes());
+        body.extend_from_slice(&62078u16.to_be_bytes());
+        body.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            decode(MESSAGE_CONNECT, &body),
+            BinaryRequest::Connect {
+                device_id: 3,
+                port: 62078
+            }
+        ));
+    }
+
+    #[test]
+    fn short_connect_is_unknown() {
+        assert!(matches!(
+            decode(MESSAGE_CONNECT, &[1, 0, 0, 0]),
+            BinaryRequest::Unknown(MESSAGE_CONNECT)
+        ));
+    }
+
+    #[test]
+    fn decodes_listen_and_unknown() {
+        assert!(matches!(decode(MESSAGE_LISTEN, &[]), BinaryRequest::Listen));
+        assert!(matches!(decode(99, &[]), BinaryRequest::Unknown(99)));
+    }
+
+    #[test]
+    fn header_versions() {
+        assert_eq!(PacketVersion::from_header(0), Some(PacketVersion::Binary));
+        assert_eq!(PacketVersion::from_header(1), Some(PacketVersion::Plist));
+        assert_eq!(PacketVersion::from_header(2), None);
+    }
+}
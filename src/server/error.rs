@@ -0,0 +1,33 @@
+// Jackson Coxson
+//
+// usbmuxd `Result` codes. Every request gets a reply: a client left waiting
+// on a closed socket can't tell a missing pair record from a crashed daemon.
+
+/// A failed request, reported to the client as the matching usbmuxd
+/// `Result` number. Success is `Result` 0 and has no variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MuxError {
+    /// Malformed, unknown, or unsupported request.
+    #[error("bad command")]
+    BadCommand,
+    /// No such device or pair record.
+    #[error("bad device")]
+    BadDevice,
+    /// The device (or upstream muxer) refused or dropped the connection.
+    #[error("connection refused")]
+    ConnRefused,
+    /// Header version is neither binary (0) nor plist (1).
+    #[error("bad version")]
+    BadVersion,
+}
+
+impl MuxError {
+    pub fn code(self) -> u32 {
+        match self {
+            Self::BadCommand => 1,
+            Self::BadDevice => 2,
+            Self::ConnRefused => 3,
+            Self::BadVersion => 6,
+        }
+    }
+}
@@ -0,0 +1,1178 @@
+// Jackson Coxson
+//
+// Per-client usbmuxd request dispatch: reads framed requests off a client
+// stream and answers them from the manager, local pairing storage, or (in
+// shim mode) the upstream muxer.
+
+use idevice::{
+    IdeviceError,
+    lockdown::LockdownClient,
+    usbmuxd::{
+        RawPacket,
+        errors::UsbmuxdError,
+        server::{UsbmuxdServerRequest, UsbmuxdServerResponse},
+    },
+};
+use log::{error, info, trace, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot::channel, watch},
+};
+
+use crate::{
+    access::{self, Operation, Peer, Permissions},
+    manager::{self, ManagerRequest, ManagerSender, SHIM_NETWORK_ID_BASE},
+    pairing_file::PairingFileFinder,
+    upstream::{self, Upstream},
+};
+
+use super::{
+    acceptor::BoxedStream,
+    binary::{self, BinaryRequest, PacketVersion},
+    error::MuxError,
+    listen::run_listen,
+    reload::ReloadHandle,
+};
+
+/// Everything a client session needs, cloned into each connection's task.
+#[derive(Clone)]
+pub(super) struct ClientContext {
+    pub manager_sender: ManagerSender,
+    pub pairing_file_finder: PairingFileFinder,
+    pub upstream: Option<Upstream>,
+    pub reload: ReloadHandle,
+    pub stop: watch::Receiver<bool>,
+    /// Held for the life of the client's task so shutdown can wait for it.
+    pub drain: mpsc::Sender<()>,
+}
+
+/// Serve one client connection on its own task until it disconnects, or
+/// until it's handed off to a `Listen` session or `Connect` tunnel.
+pub(super) fn handle_stream(
+    mut socket: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    peer: Peer,
+    ctx: ClientContext,
+) {
+    let ClientContext {
+        manager_sender,
+        pairing_file_finder,
+        upstream,
+        reload,
+        mut stop,
+        drain,
+    } = ctx;
+    tokio::spawn(async move {
+        let _drain = drain;
+        let Some(permissions) = access::permissions(&reload.current().access, &peer) else {
+            info!("Rejecting client {peer:?}: no access rule allows it");
+            return;
+        };
+        // 16 MiB cap on a single packet to avoid unbounded allocations from a
+        // misbehaving client. usbmuxd packets are normally a few KiB.
+        const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+        loop {
+            trace!("Waiting for data from client...");
+            // Read the 16-byte header (size, version, message, tag).
+            let mut header = [0u8; 16];
+            let read = tokio::select! {
+                r = socket.read_exact(&mut header) => r,
+                _ = stop.wait_for(|s| *s) => {
+                    trace!("Server is shutting down, closing idle client");
+                    return;
+                }
+            };
+            if let Err(e) = read {
+                trace!("Header read ended: {e:?}");
+                return;
+            }
+
+            let packet_size =
+                u32::from_le_bytes(header[0..4].try_into().expect("16-byte header")) as usize;
+            if !(16..=MAX_PACKET_SIZE).contains(&packet_size) {
+                warn!("Bogus packet size from client: {packet_size}");
+                return;
+            }
+
+            // Pull the rest of the packet body.
+            let mut buffer = vec![0u8; packet_size];
+            buffer[..16].copy_from_slice(&header);
+            if packet_size > 16
+                && let Err(e) = socket.read_exact(&mut buffer[16..]).await
+            {
+                warn!(
+                    "Failed reading packet body ({} bytes): {e:?}",
+                    packet_size - 16
+                );
+                return;
+            }
+
+            let version = u32::from_le_bytes(header[4..8].try_into().expect("16-byte header"));
+            let tag = u32::from_le_bytes(header[12..16].try_into().expect("16-byte header"));
+            let Some(version) = PacketVersion::from_header(version) else {
+                // Like usbmuxd, answer in binary framing (we don't know what
+                // the client speaks) and hang up.
+                warn!("Unsupported usbmuxd protocol version {version}");
+                send_result(
+                    &mut socket,
+                    PacketVersion::Binary,
+                    Err(MuxError::BadVersion),
+                    tag,
+                )
+                .await;
+                return;
+            };
+            if version == PacketVersion::Binary {
+                let message = u32::from_le_bytes(header[8..12].try_into().expect("16-byte header"));
+                let request = binary::decode(message, &buffer[16..]);
+                trace!("usbmuxd client sent binary {request:?}");
+                let operation = match request {
+                    BinaryRequest::Listen => Some(Operation::Listen),
+                    BinaryRequest::Connect { .. } => Some(Operation::Connect),
+                    BinaryRequest::Unknown(_) => None,
+                };
+                if let Some(op) = operation
+                    && !permissions.allows(op)
+                {
+                    if !deny(&mut socket, &peer, op, version, tag).await {
+                        return;
+                    }
+                    continue;
+                }
+                match request {
+                    BinaryRequest::Listen => {
+                        run_listen(
+                            socket,
+                            manager_sender,
+                            tag,
+                            upstream,
+                            PacketVersion::Binary,
+                            plist::Dictionary::new(),
+                        )
+                        .await;
+                        return;
+                    }
+                    BinaryRequest::Connect { device_id, port } => {
+                        if !handle_connect(
+                            &mut socket,
+                            &manager_sender,
+                            upstream.as_ref(),
+                            device_id as u64,
+                            port,
+                            tag,
+                            PacketVersion::Binary,
+                            &buffer,
+                        )
+                        .await
+                        {
+                            return;
+                        }
+                        continue;
+                    }
+                    BinaryRequest::Unknown(message) => {
+                        warn!("Unknown binary packet type: {message}");
+                        if !send_result(&mut socket, version, Err(MuxError::BadCommand), tag).await
+                        {
+                            return;
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            let parsed: RawPacket = match RawPacket::try_from(&mut buffer) {
+                Ok(p) => p,
+                Err(_) => {
+                    warn!("Could not parse packet");
+                    if !send_result(&mut socket, version, Err(MuxError::BadCommand), tag).await {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            trace!("Recv'd plist: {parsed:#?}");
+
+            // Decode the standard usbmuxd requests via idevice. netmuxd's own
+            // AddDevice/RemoveDevice extensions aren't part of the standard
+            // protocol, so they surface as `UnknownMessageType` and are
+            // dispatched separately below.
+            let request = match UsbmuxdServerRequest::decode(&parsed) {
+                Ok(r) => r,
+                Err(IdeviceError::Usbmuxd(UsbmuxdError::UnknownMessageType(message_type))) => {
+                    let operation = match message_type.as_str() {
+                        "AddDevice" | "RemoveDevice" => Some(Operation::ManageDevices),
+                        "ReloadConfig" => Some(Operation::ReloadConfig),
+                        "DeletePairRecord" => Some(Operation::DeletePairRecord),
+                        "ListListeners" => Some(Operation::ListListeners),
+                        "ApprovePairing" | "DenyPairing" | "ListPairings" => {
+                            Some(Operation::ManagePairing)
+                        }
+                        _ => None,
+                    };
+                    if let Some(op) = operation
+                        && !permissions.allows(op)
+                    {
+                        if !deny(&mut socket, &peer, op, version, parsed.tag).await {
+                            return;
+                        }
+                        continue;
+                    }
+                    match message_type.as_str() {
+                        //////////////////////////////
+                        // netmuxd specific packets //
+                        //////////////////////////////
+                        "AddDevice" => {
+                            handle_add_device(&mut socket, &manager_sender, &parsed).await;
+                            return;
+                        }
+                        "RemoveDevice" => {
+                            handle_remove_device(&mut socket, &manager_sender, &parsed).await;
+                            return;
+                        }
+                        "ReloadConfig" => {
+                            let result = reload.reload().map_err(|e| {
+                                warn!("Config reload failed: {e}");
+                                MuxError::BadCommand
+                            });
+                            if !send_result(&mut socket, version, result, parsed.tag).await {
+                                return;
+                            }
+                            continue;
+                        }
+                        "ApprovePairing" | "DenyPairing" => {
+                            if let Err(e) = handle_decide_pairing(
+                                &mut socket,
+                                &manager_sender,
+                                &parsed,
+                                message_type == "ApprovePairing",
+                            )
+                            .await
+                            {
+                                warn!("Failed to send response to client: {e:?}");
+                                return;
+                            }
+                            continue;
+                        }
+                        "ListPairings" => {
+                            if let Err(e) =
+                                handle_list_pairings(&mut socket, &manager_sender, &parsed).await
+                            {
+                                warn!("Failed to send response to client: {e:?}");
+                                return;
+                            }
+                            continue;
+                        }
+                        ///////////////////////////////////////////////
+                        // usbmuxd packets idevice doesn't model yet //
+                        ///////////////////////////////////////////////
+                        "DeletePairRecord" => {
+                            if let Err(e) = handle_delete_pair_record(
+                                &mut socket,
+                                &pairing_file_finder,
+                                upstream.as_ref(),
+                                &parsed,
+                                &buffer,
+                            )
+                            .await
+                            {
+                                warn!("Failed to send response to client: {e:?}");
+                                return;
+                            }
+                            continue;
+                        }
+                        "ListListeners" => {
+                            if let Err(e) = handle_list_listeners(
+                                &mut socket,
+                                &manager_sender,
+                                upstream.as_ref(),
+                                &parsed,
+                                &buffer,
+                            )
+                            .await
+                            {
+                                warn!("Failed to send response to client: {e:?}");
+                                return;
+                            }
+                            continue;
+                        }
+                        other => {
+                            // Forward anything we don't model to the upstream
+                            // muxer when in shim mode; otherwise it's unknown.
+                            // There's no local equivalent, so a dead upstream
+                            // just means we can't answer it.
+                            if let Some(addr) = upstream.as_ref() {
+                                // We can't tell what it does, so only a client
+                                // allowed everything may send it.
+                                if permissions != Permissions::ALL {
+                                    warn!("Denied forwarding {other} for client {peer:?}");
+                                    if !send_result(
+                                        &mut socket,
+                                        version,
+                                        Err(MuxError::BadCommand),
+                                        parsed.tag,
+                                    )
+                                    .await
+                                    {
+                                        return;
+                                    }
+                                    continue;
+                                }
+                                match upstream::forward_to_upstream(addr, &buffer).await {
+                                    Ok(frame) => {
+                                        if let Err(e) = socket.write_all(&frame).await {
+                                            warn!("Failed to send response to client: {e:?}");
+                                            return;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed forwarding {other} to upstream: {e}");
+                                        if !send_result(
+                                            &mut socket,
+                                            version,
+                                            Err(MuxError::BadCommand),
+                                            parsed.tag,
+                                        )
+                                        .await
+                                        {
+                                            return;
+                                        }
+                                    }
+                                }
+                                continue;
+                            }
+                            warn!("Unknown packet type: {other}");
+                            if !send_result(
+                                &mut socket,
+                                version,
+                                Err(MuxError::BadCommand),
+                                parsed.tag,
+                            )
+                            .await
+                            {
+                                return;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Malformed usbmuxd request: {e}");
+                    if !send_result(&mut socket, version, Err(MuxError::BadCommand), parsed.tag)
+                        .await
+                    {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            trace!("usbmuxd client sent {request:?}");
+
+            let op = match &request {
+                UsbmuxdServerRequest::ListDevices => Operation::ListDevices,
+                UsbmuxdServerRequest::Listen => Operation::Listen,
+                UsbmuxdServerRequest::ReadPairRecord { .. } => Operation::ReadPairRecord,
+                UsbmuxdServerRequest::SavePairRecord { .. } => Operation::SavePairRecord,
+                UsbmuxdServerRequest::ReadBuid => Operation::ReadBuid,
+                UsbmuxdServerRequest::Connect { .. } => Operation::Connect,
+            };
+            if !permissions.allows(op) {
+                if !deny(&mut socket, &peer, op, version, parsed.tag).await {
+                    return;
+                }
+                continue;
+            }
+
+            match request {
+                //////////////////////////////
+                // usbmuxd protocol packets //
+                //////////////////////////////
+                UsbmuxdServerRequest::ListDevices => {
+                    let (tx, rx) = channel();
+                    if let Err(e) = manager_sender
+                        .send(ManagerRequest {
+                            request_type: manager::ManagerRequestType::ListDevices,
+                            response: Some(tx),
+                        })
+                        .await
+                    {
+                        log::error!("Manager channel is closed: {e:?}");
+                    }
+                    let res = match rx.await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::error!("Did not recv manager response: {e:?}");
+                            return;
+                        }
+                    };
+
+                    let out: Vec<u8> = if let Some(addr) = upstream.as_ref() {
+                        // Shim mode: ask upstream for its (USB) device list and
+                        // append our network devices to it.
+                        let network = match res.get("DeviceList") {
+                            Some(plist::Value::Array(a)) => a.clone(),
+                            _ => Vec::new(),
+                        };
+                        match upstream::list_devices_merged(addr, &buffer, network, parsed.tag)
+                            .await
+                        {
+                            Ok(bytes) => bytes,
+                            Err(e) => {
+                                // Upstream is unreachable: serve just our
+                                // network devices instead of dropping the
+                                // client.
+                                warn!(
+                                    "Upstream ListDevices failed ({e}); serving network devices only"
+                                );
+                                RawPacket::new(res, 1, 8, parsed.tag).into()
+                            }
+                        }
+                    } else {
+                        trace!("{}", plist_macro::pretty_print_dictionary(&res));
+                        RawPacket::new(res, 1, 8, parsed.tag).into()
+                    };
+                    if let Err(e) = socket.write_all(&out).await {
+                        warn!("Failed to send response to client: {e:}");
+                        return;
+                    }
+
+                    continue;
+                }
+                UsbmuxdServerRequest::Listen => {
+                    run_listen(
+                        socket,
+                        manager_sender,
+                        parsed.tag,
+                        upstream,
+                        PacketVersion::Plist,
+                        parsed.plist.clone(),
+                    )
+                    .await;
+                    return;
+                }
+                UsbmuxdServerRequest::ReadPairRecord { pair_record_id } => {
+                    // Forward to upstream when possible; fall back to our own
+                    // lockdown storage if it's unreachable.
+                    if let Some(addr) = upstream.as_ref() {
+                        match upstream::forward_to_upstream(addr, &buffer).await {
+                            Ok(frame) => {
+                                if let Err(e) = socket.write_all(&frame).await {
+                                    warn!("Failed to send response to client: {e:?}");
+                                    return;
+                                }
+                                continue;
+                            }
+                            Err(e) => warn!(
+                                "Upstream ReadPairRecord failed ({e}); reading local pairing storage"
+                            ),
+                        }
+                    }
+                    let pair_file = match pairing_file_finder
+                        .get_pairing_record(&pair_record_id)
+                        .await
+                        .and_then(|p| p.serialize())
+                    {
+                        Ok(pair_file) => pair_file,
+                        Err(e) => {
+                            info!("No usable pair record for {pair_record_id}: {e:?}");
+                            if !send_result(
+                                &mut socket,
+                                version,
+                                Err(MuxError::BadDevice),
+                                parsed.tag,
+                            )
+                            .await
+                            {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+
+                    let res: Vec<u8> = UsbmuxdServerResponse::PairRecord(pair_file)
+                        .into_packet(parsed.tag)
+                        .into();
+                    if let Err(e) = socket.write_all(&res).await {
+                        warn!("Failed to send response to client: {e:?}");
+                        return;
+                    }
+
+                    continue;
+                }
+                UsbmuxdServerRequest::SavePairRecord {
+                    pair_record_id,
+                    device_id,
+                    pair_record_data,
+                } => {
+                    // Forward to upstream when possible; fall back to writing
+                    // our own lockdown storage if it's unreachable.
+                    if let Some(addr) = upstream.as_ref() {
+                        match upstream::forward_to_upstream(addr, &buffer).await {
+                            Ok(frame) => {
+                                if let Err(e) = socket.write_all(&frame).await {
+                                    warn!("Failed to send response to client: {e:?}");
+                                    return;
+                                }
+                                continue;
+                            }
+                            Err(e) => warn!(
+                                "Upstream SavePairRecord failed ({e}); writing local pairing storage"
+                            ),
+                        }
+                    }
+                    let udid = match pair_record_id {
+                        Some(u) => u,
+                        None => {
+                            let device_id = match device_id {
+                                Some(d) => d,
+                                None => {
+                                    warn!("SavePairRecord missing both PairRecordID and DeviceID");
+                                    if !send_result(
+                                        &mut socket,
+                                        version,
+                                        Err(MuxError::BadCommand),
+                                        parsed.tag,
+                                    )
+                                    .await
+                                    {
+                                        return;
+                                    }
+                                    continue;
+                                }
+                            };
+                            let (tx, rx) = tokio::sync::oneshot::channel();
+                            if let Err(e) = manager_sender
+                                .send(ManagerRequest {
+                                    request_type:
+                                        manager::ManagerRequestType::GetDeviceConnection {
+                                            id: device_id,
+                                            response: tx,
+                                        },
+                                    response: None,
+                                })
+                                .await
+                            {
+                                error!("Manager thread is stopped: {e:?}");
+                                return;
+                            }
+                            match rx.await {
+                                Ok(Some(c)) => c.serial_number,
+                                Ok(None) => {
+                                    warn!("No device with id {device_id}");
+                                    if !send_result(
+                                        &mut socket,
+                                        version,
+                                        Err(MuxError::BadDevice),
+                                        parsed.tag,
+                                    )
+                                    .await
+                                    {
+                                        return;
+                                    }
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!("Manager did not respond: {e:?}");
+                                    return;
+                                }
+                            }
+                        }
+                    };
+
+                    let result = match pairing_file_finder
+                        .save_pairing_record(&udid, &pair_record_data)
+                        .await
+                    {
+                        Ok(()) => Ok(()),
+                        Err(e) => {
+                            warn!("Failed to save pair record for {udid}: {e:?}");
+                            Err(MuxError::BadDevice)
+                        }
+                    };
+
+                    if !send_result(&mut socket, version, result, parsed.tag).await {
+                        return;
+                    }
+
+                    continue;
+                }
+                UsbmuxdServerRequest::ReadBuid => {
+                    // Forward to upstream when possible; fall back to our own
+                    // host identity if it's unreachable.
+                    if let Some(addr) = upstream.as_ref() {
+                        match upstream::forward_to_upstream(addr, &buffer).await {
+                            Ok(frame) => {
+                                if let Err(e) = socket.write_all(&frame).await {
+                                    warn!("Failed to send response to client: {e:?}");
+                                    return;
+                                }
+                                continue;
+                            }
+                            Err(e) => {
+                                warn!("Upstream ReadBUID failed ({e}); using local host identity")
+                            }
+                        }
+                    }
+                    let buid = match pairing_file_finder.get_buid().await {
+                        Ok(b) => b,
+                        Err(e) => {
+                            log::error!("Failed to get buid: {e:?}");
+                            if !send_result(
+                                &mut socket,
+                                version,
+                                Err(MuxError::BadCommand),
+                                parsed.tag,
+                            )
+                            .await
+                            {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+
+                    let res: Vec<u8> = UsbmuxdServerResponse::Buid(buid)
+                        .into_packet(parsed.tag)
+                        .into();
+                    if let Err(e) = socket.write_all(&res).await {
+                        warn!("Failed to send response to client: {e:?}");
+                        return;
+                    }
+
+                    continue;
+                }
+                UsbmuxdServerRequest::Connect { device_id, port } => {
+                    if !handle_connect(
+                        &mut socket,
+                        &manager_sender,
+                        upstream.as_ref(),
+                        device_id,
+                        port,
+                        parsed.tag,
+                        PacketVersion::Plist,
+                        &buffer,
+                    )
+                    .await
+                    {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        }
+    });
+}
+
+/// Open a tunnel to `port` on `device_id` and splice the client onto it.
+///
+/// Returns `true` if the client connection is still usable for further
+/// requests (the connect failed and a Result was sent), `false` once it has
+/// been handed to a tunnel or is dead.
+#[allow(clippy::too_many_arguments)]
+async fn handle_connect(
+    socket: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    manager_sender: &ManagerSender,
+    upstream: Option<&Upstream>,
+    device_id: u64,
+    port: u16,
+    tag: u32,
+    version: PacketVersion,
+    raw: &[u8],
+) -> bool {
+    info!("Client is establishing connection to port {port}");
+
+    // In shim mode, a DeviceID below the network base belongs to
+    // the upstream muxer: hand the whole connection to it. The
+    // upstream's Result and the tunnel both flow back over the
+    // splice, so we don't reply ourselves.
+    if let Some(addr) = upstream
+        && device_id < SHIM_NETWORK_ID_BASE
+    {
+        let mut up = match upstream::connect(addr).await {
+            Ok(u) => u,
+            Err(e) => {
+                error!("Failed to reach upstream for Connect: {e}");
+                return send_result(socket, version, Err(MuxError::ConnRefused), tag).await;
+            }
+        };
+        if let Err(e) = up.write_all(raw).await {
+            error!("Failed to forward Connect to upstream: {e:?}");
+            return send_result(socket, version, Err(MuxError::ConnRefused), tag).await;
+        }
+        if let Err(e) = tokio::io::copy_bidirectional(&mut *up, socket).await {
+            info!("Upstream proxied stream stopped: {e:?}");
+        }
+        return false;
+    }
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if let Err(e) = manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::GetConnectRoute {
+                id: device_id,
+                response: tx,
+            },
+            response: None,
+        })
+        .await
+    {
+        log::error!("Manager thread is stopped: {e:?}");
+        return false;
+    }
+
+    let route = match rx.await {
+        Ok(r) if !r.is_empty() => r,
+        Ok(_) => {
+            warn!("No device with id {device_id}");
+            return send_result(socket, version, Err(MuxError::BadDevice), tag).await;
+        }
+        Err(e) => {
+            log::error!("Manager thread did not respond: {e:?}");
+            return false;
+        }
+    };
+
+    // Try the transports in the order the device's connection policy
+    // prefers, falling back to the next one if a connect fails.
+    let mut connected = None;
+    for lookup in route {
+        let id = lookup.device_id;
+        match connect_device(lookup, port).await {
+            Ok(s) => {
+                if id != device_id {
+                    info!("Routing connection for device {device_id} over device {id}");
+                }
+                connected = Some((id, s));
+                break;
+            }
+            Err(e) => warn!("Unable to connect to device {id} port {port}: {e}"),
+        }
+    }
+    let Some((device_id, mut device)) = connected else {
+        error!("Unable to connect to device {device_id} port {port}");
+        return send_result(socket, version, Err(MuxError::ConnRefused), tag).await;
+    };
+
+    if !send_result(socket, version, Ok(()), tag).await {
+        return false;
+    }
+
+    let (kill, killed) = channel();
+    manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::OpenSocket { device_id, kill },
+            response: None,
+        })
+        .await
+        .expect("Manager is dead");
+
+    tokio::select! {
+        _ = killed => {
+            info!("Bidirectional stream stopped via heartbeat failure");
+        }
+        e = tokio::io::copy_bidirectional(&mut *device, socket) => {
+            info!("Bidirectional stream stopped: {e:?}");
+        }
+    }
+    false
+}
+
+/// Open `port` on one transport of a device.
+async fn connect_device(
+    lookup: manager::DeviceConnection,
+    port: u16,
+) -> Result<BoxedStream, String> {
+    match lookup.connection_type.as_str() {
+        "Network" => match lookup.network_address {
+            Some(addr) => {
+                let port = match lookup.lockdown_port {
+                    Some(p) if port == LockdownClient::LOCKDOWND_PORT => p,
+                    _ => port,
+                };
+                match tokio::net::TcpStream::connect(addr.socket_addr(port)).await {
+                    Ok(s) => Ok(Box::new(s) as BoxedStream),
+                    Err(e) => Err(format!("tcp connect: {e:?}")),
+                }
+            }
+            None => Err("network device missing address".to_string()),
+        },
+        "USB" => match lookup.usb {
+            Some(handle) => match handle.connect(port).await {
+                Ok(s) => Ok(Box::new(s) as BoxedStream),
+                Err(e) => Err(format!("usb connect: {e:?}")),
+            },
+            None => Err("usb device missing handle".into()),
+        },
+        other => Err(format!("unknown connection type {other}")),
+    }
+}
+
+/// Remove a pairing record. Forwarded to upstream in shim mode, falling back
+/// to local storage if it's unreachable.
+async fn handle_delete_pair_record(
+    socket: &mut (impl AsyncWrite + Unpin),
+    pairing_file_finder: &PairingFileFinder,
+    upstream: Option<&Upstream>,
+    parsed: &RawPacket,
+    raw: &[u8],
+) -> std::io::Result<()> {
+    if let Some(addr) = upstream {
+        match upstream::forward_to_upstream(addr, raw).await {
+            Ok(frame) => return socket.write_all(&frame).await,
+            Err(e) => {
+                warn!("Upstream DeletePairRecord failed ({e}); deleting from local pairing storage")
+            }
+        }
+    }
+
+    let result = match parsed.plist.get("PairRecordID") {
+        Some(plist::Value::String(udid)) => {
+            match pairing_file_finder.remove_pairing_record(udid).await {
+                Ok(()) => {
+                    info!("Deleted pair record for {udid}");
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("Failed to delete pair record for {udid}: {e:?}");
+                    Err(MuxError::BadDevice)
+                }
+            }
+        }
+        _ => {
+            warn!("DeletePairRecord missing PairRecordID");
+            Err(MuxError::BadCommand)
+        }
+    };
+
+    socket
+        .write_all(&PacketVersion::Plist.result(result, parsed.tag))
+        .await
+}
+
+/// Report active `Listen` sessions. In shim mode the upstream's listeners are
+/// listed first, followed by ours.
+async fn handle_list_listeners(
+    socket: &mut (impl AsyncWrite + Unpin),
+    manager_sender: &ManagerSender,
+    upstream: Option<&Upstream>,
+    parsed: &RawPacket,
+    raw: &[u8],
+) -> std::io::Result<()> {
+    let (tx, rx) = channel();
+    if let Err(e) = manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::ListListeners,
+            response: Some(tx),
+        })
+        .await
+    {
+        log::error!("Manager channel is closed: {e:?}");
+    }
+    let res = match rx.await {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("Did not recv manager response: {e:?}");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "manager did not respond",
+            ));
+        }
+    };
+
+    if let Some(addr) = upstream {
+        let ours = match res.get("ListenerList") {
+            Some(plist::Value::Array(a)) => a.clone(),
+            _ => Vec::new(),
+        };
+        match upstream::list_listeners_merged(addr, raw, ours, parsed.tag).await {
+            Ok(bytes) => return socket.write_all(&bytes).await,
+            Err(e) => warn!("Upstream ListListeners failed ({e}); serving local listeners only"),
+        }
+    }
+
+    let out: Vec<u8> = RawPacket::new(res, 1, 8, parsed.tag).into();
+    socket.write_all(&out).await
+}
+
+/// netmuxd extension: answer a USB device waiting for pairing approval
+/// (`pairing_policy = "manual"`), named by `SerialNumber` or `UDID`.
+async fn handle_decide_pairing(
+    socket: &mut (impl AsyncWrite + Unpin),
+    manager_sender: &ManagerSender,
+    parsed: &RawPacket,
+    approve: bool,
+) -> std::io::Result<()> {
+    let id = ["SerialNumber", "UDID"]
+        .iter()
+        .find_map(|key| parsed.plist.get(*key).and_then(|v| v.as_string()));
+    let result = match id {
+        Some(id) => {
+            let (tx, rx) = channel();
+            if let Err(e) = manager_sender
+                .send(ManagerRequest {
+                    request_type: manager::ManagerRequestType::DecidePairing {
+                        id: id.to_string(),
+                        approve,
+                        response: tx,
+                    },
+                    response: None,
+                })
+                .await
+            {
+                log::error!("Manager channel is closed: {e:?}");
+            }
+            if rx.await.unwrap_or(false) {
+                info!(
+                    "{} pairing with {id}",
+                    if approve { "Approved" } else { "Denied" }
+                );
+                Ok(())
+            } else {
+                warn!("No device waiting for pairing approval matches {id}");
+                Err(MuxError::BadDevice)
+            }
+        }
+        None => {
+            warn!("Pairing decision missing SerialNumber or UDID");
+            Err(MuxError::BadCommand)
+        }
+    };
+
+    socket
+        .write_all(&PacketVersion::Plist.result(result, parsed.tag))
+        .await
+}
+
+/// netmuxd extension: USB devices going through pairing and their state.
+async fn handle_list_pairings(
+    socket: &mut (impl AsyncWrite + Unpin),
+    manager_sender: &ManagerSender,
+    parsed: &RawPacket,
+) -> std::io::Result<()> {
+    let (tx, rx) = channel();
+    if let Err(e) = manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::ListPairings,
+            response: Some(tx),
+        })
+        .await
+    {
+        log::error!("Manager channel is closed: {e:?}");
+    }
+    let res = match rx.await {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("Did not recv manager response: {e:?}");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "manager did not respond",
+            ));
+        }
+    };
+    let out: Vec<u8> = RawPacket::new(res, 1, 8, parsed.tag).into();
+    socket.write_all(&out).await
+}
+
+/// netmuxd extension: register a network device the client discovered itself.
+async fn handle_add_device(
+    socket: &mut (impl AsyncWrite + Unpin),
+    manager_sender: &ManagerSender,
+    parsed: &RawPacket,
+) {
+    let connection_type = match parsed.plist.get("ConnectionType") {
+        Some(plist::Value::String(c)) => c,
+        _ => {
+            warn!("Packet didn't contain ConnectionType");
+            send_result(
+                socket,
+                PacketVersion::Plist,
+                Err(MuxError::BadCommand),
+                parsed.tag,
+            )
+            .await;
+            return;
+        }
+    };
+    let service_name = match parsed.plist.get("ServiceName") {
+        Some(plist::Value::String(s)) => s,
+        _ => {
+            warn!("Packet didn't contain ServiceName");
+            send_result(
+                socket,
+                PacketVersion::Plist,
+                Err(MuxError::BadCommand),
+                parsed.tag,
+            )
+            .await;
+            return;
+        }
+    };
+
+    let ip_address = match parsed.plist.get("IPAddress") {
+        Some(plist::Value::String(ip)) => ip,
+        _ => {
+            warn!("Packet didn't contain IPAddress");
+            send_result(
+                socket,
+                PacketVersion::Plist,
+                Err(MuxError::BadCommand),
+                parsed.tag,
+            )
+            .await;
+            return;
+        }
+    };
+
+    let ip_address = match ip_address.parse() {
+        Ok(i) => i,
+        Err(_) => {
+            warn!("Bad IP requested: {ip_address}");
+            send_result(
+                socket,
+                PacketVersion::Plist,
+                Err(MuxError::BadCommand),
+                parsed.tag,
+            )
+            .await;
+            return;
+        }
+    };
+
+    let udid = match parsed.plist.get("DeviceID") {
+        Some(plist::Value::String(u)) => u,
+        _ => {
+            warn!("Packet didn't contain DeviceID");
+            send_result(
+                socket,
+                PacketVersion::Plist,
+                Err(MuxError::BadCommand),
+                parsed.tag,
+            )
+            .await;
+            return;
+        }
+    };
+
+    let (tx, rx) = channel();
+    if let Err(e) = manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::DiscoveredNetworkDevice {
+                udid: udid.clone(),
+                network_address: ip_address,
+                service_name: service_name.to_string(),
+                connection_type: connection_type.to_string(),
+                lockdown_port: None,
+                response: Some(tx),
+            },
+            response: None,
+        })
+        .await
+    {
+        log::error!("Failed to send to manager: {e:?}, stopping!");
+        send_result(
+            socket,
+            PacketVersion::Plist,
+            Err(MuxError::ConnRefused),
+            parsed.tag,
+        )
+        .await;
+        return;
+    }
+    let added = match rx.await {
+        Ok(r) => r,
+        Err(e) => {
+            // Dropped, e.g. because the manager is shutting down.
+            log::error!("Failed to recv manager response: {e:?}");
+            Err(MuxError::ConnRefused)
+        }
+    };
+    if let Err(e) = added {
+        send_result(socket, PacketVersion::Plist, Err(e), parsed.tag).await;
+        return;
+    }
+
+    // AddDevice has always answered success with `Result` 1.
+    let res: Vec<u8> = RawPacket::new(
+        plist_macro::plist!(dict {
+            "Result": 1,
+        }),
+        1,
+        8,
+        parsed.tag,
+    )
+    .into();
+    if let Err(e) = socket.write_all(&res).await {
+        warn!("Failed to send back success message: {e:?}");
+    }
+}
+
+/// netmuxd extension: drop a device the client previously added.
+async fn handle_remove_device(
+    socket: &mut (impl AsyncWrite + Unpin),
+    manager_sender: &ManagerSender,
+    parsed: &RawPacket,
+) {
+    let udid = match parsed.plist.get("DeviceID") {
+        Some(plist::Value::String(u)) => u,
+        _ => {
+            warn!("Packet didn't contain DeviceID");
+            send_result(
+                socket,
+                PacketVersion::Plist,
+                Err(MuxError::BadCommand),
+                parsed.tag,
+            )
+            .await;
+            return;
+        }
+    };
+
+    manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::RemoveDevice {
+                udid: udid.to_string(),
+                connection_type: None,
+            },
+            response: None,
+        })
+        .await
+        .ok();
+    send_result(socket, PacketVersion::Plist, Ok(()), parsed.tag).await;
+}
+
+/// Refuse an operation the client's access rule doesn't grant. usbmuxd has no
+/// "permission denied" result, so use the closest code for the request.
+/// Returns `false` if the client is gone.
+async fn deny(
+    socket: &mut (impl AsyncWrite + Unpin),
+    peer: &Peer,
+    op: Operation,
+    version: PacketVersion,
+    tag: u32,
+) -> bool {
+    warn!("Denied {} for client {peer:?}", op.name());
+    let err = match op {
+        Operation::Connect => MuxError::ConnRefused,
+        _ => MuxError::BadCommand,
+    };
+    send_result(socket, version, Err(err), tag).await
+}
+
+/// Reply with a usbmuxd `Result`. Returns `false` if the client is gone.
+async fn send_result(
+    socket: &mut (impl AsyncWrite + Unpin),
+    version: PacketVersion,
+    result: Result<(), MuxError>,
+    tag: u32,
+) -> bool {
+    if let Err(e) = &result {
+        trace!("Replying to client with {e}");
+    }
+    match socket.write_all(&version.result(result, tag)).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to send response to client: {e:?}");
+            false
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// Jackson Coxson
+//
+// `Listen` sessions: stream the manager's attach/detach events (and, in shim
+// mode, the upstream muxer's) to a subscribed client until it hangs up.
+
+use idevice::usbmuxd::{RawPacket, server::UsbmuxdServerResponse};
+use log::{error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    manager::{self, ListenerEvent, ManagerRequest, ManagerSender},
+    upstream::{self, Upstream},
+};
+
+use super::{
+    acceptor::BoxedStream,
+    binary::{self, PacketVersion},
+};
+
+pub(super) async fn run_listen<S>(
+    mut socket: S,
+    manager_sender: ManagerSender,
+    listen_tag: u32,
+    upstream: Option<Upstream>,
+    version: PacketVersion,
+    client_info: plist::Dictionary,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Err(e) = socket.write_all(&version.result(Ok(()), listen_tag)).await {
+        warn!("Failed to send Listen Result: {e:?}");
+        return;
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<ListenerEvent>();
+    if let Err(e) = manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::Subscribe {
+                listener: tx,
+                info: client_info,
+            },
+            response: None,
+        })
+        .await
+    {
+        error!("Failed to subscribe listener: {e:?}");
+        return;
+    }
+
+    // In shim mode, also relay the upstream muxer's attach/detach frames so the
+    // client sees its USB devices alongside our network devices. The pump
+    // reconnects with backoff if upstream drops; the guard aborts it when this
+    // Listen session ends.
+    let (mut upstream_rx, _upstream_guard) = match upstream {
+        Some(addr) => {
+            let (rx, guard) = spawn_upstream_listen(addr);
+            (Some(rx), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let mut sink = [0u8; 256];
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let Some(event) = event else { return; };
+                let Some(bytes) = encode_event(event, version) else { continue; };
+                if let Err(e) = writer.write_all(&bytes).await {
+                    info!("Listener write failed, dropping: {e:?}");
+                    return;
+                }
+            }
+            // Relay upstream attach/detach frames verbatim, or re-encoded for
+            // binary clients (our upstream Listen always speaks plist).
+            frame = next_upstream(&mut upstream_rx) => {
+                match frame {
+                    Some(frame) => {
+                        let frame = match version {
+                            PacketVersion::Plist => frame,
+                            PacketVersion::Binary => match upstream_frame_to_binary(&frame) {
+                                Some(f) => f,
+                                None => continue,
+                            },
+                        };
+                        if let Err(e) = writer.write_all(&frame).await {
+                            info!("Listener write (upstream) failed, dropping: {e:?}");
+                            return;
+                        }
+                    }
+                    None => {
+                        warn!("Upstream Listen stream ended; relaying network devices only");
+                        upstream_rx = None;
+                    }
+                }
+            }
+            r = reader.read(&mut sink) => {
+                match r {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+}
+
+fn encode_event(event: ListenerEvent, version: PacketVersion) -> Option<Vec<u8>> {
+    match version {
+        PacketVersion::Plist => {
+            let response = match event {
+                ListenerEvent::Attached(p) => UsbmuxdServerResponse::Attached(p),
+                ListenerEvent::Detached(id) => UsbmuxdServerResponse::Detached(id),
+                ListenerEvent::Pairing(p) => return Some(RawPacket::new(p, 1, 8, 0).into()),
+                ListenerEvent::Paired(id) => {
+                    let mut p = plist::Dictionary::new();
+                    p.insert("MessageType".into(), "Paired".into());
+                    p.insert("DeviceID".into(), id.into());
+                    return Some(RawPacket::new(p, 1, 8, 0).into());
+                }
+            };
+            Some(response.into_packet(0).into())
+        }
+        PacketVersion::Binary => match event {
+            ListenerEvent::Attached(p) => binary::device_add(&p),
+            ListenerEvent::Detached(id) => Some(binary::device_remove(id)),
+            // The binary protocol has no message for it.
+            ListenerEvent::Pairing(_) => None,
+            ListenerEvent::Paired(id) => Some(binary::device_paired(id)),
+        },
+    }
+}
+
+/// Translate an upstream plist Attached/Detached/Paired frame for a binary
+/// client.
+fn upstream_frame_to_binary(frame: &[u8]) -> Option<Vec<u8>> {
+    let parsed = RawPacket::try_from(frame).ok()?;
+    match parsed.plist.get("MessageType").and_then(|v| v.as_string()) {
+        Some("Attached") => binary::device_add(&parsed.plist),
+        Some("Detached") => parsed
+            .plist
+            .get("DeviceID")
+            .and_then(|v| v.as_unsigned_integer())
+            .map(binary::device_remove),
+        Some("Paired") => parsed
+            .plist
+            .get("DeviceID")
+            .and_then(|v| v.as_unsigned_integer())
+            .map(binary::device_paired),
+        _ => None,
+    }
+}
+
+/// A spawned task that is aborted when this guard is dropped.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Spawn a task that relays the upstream muxer's `Listen` attach/detach frames
+/// into the returned channel, reconnecting with capped backoff whenever the
+/// upstream stream drops. The returned guard aborts the task when dropped (when
+/// the client's Listen session ends).
+fn spawn_upstream_listen(
+    addr: Upstream,
+) -> (tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>, AbortOnDrop) {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+    let handle = tokio::spawn(upstream_listen_pump(addr, tx));
+    (rx, AbortOnDrop(handle))
+}
+
+async fn upstream_listen_pump(addr: Upstream, tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>) {
+    use std::time::Duration;
+    const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+    const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match open_upstream_listen(&addr).await {
+            Ok(mut up) => {
+                info!("Upstream Listen stream connected");
+                backoff = INITIAL_BACKOFF;
+                loop {
+                    match upstream::read_frame(&mut *up).await {
+                        Ok(frame) => {
+                            // Stop for good once the client's Listen session
+                            // ends (the receiver was dropped).
+                            if tx.send(frame).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Upstream Listen stream dropped ({e:?}); reconnecting");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Upstream Listen connect failed ({e}); retrying in {backoff:?}"),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Open a connection to upstream, send `Listen`, and consume the handshake
+/// `Result` frame so only attach/detach frames remain.
+async fn open_upstream_listen(addr: &Upstream) -> Result<BoxedStream, String> {
+    let mut up = upstream::connect(addr).await?;
+    let mut p = plist::Dictionary::new();
+    p.insert("MessageType".into(), "Listen".into());
+    let listen_pkt: Vec<u8> = RawPacket::new(p, 1, 8, 0).into();
+    up.write_all(&listen_pkt)
+        .await
+        .map_err(|e| format!("write Listen to upstream: {e:?}"))?;
+    upstream::read_frame(&mut *up)
+        .await
+        .map_err(|e| format!("read upstream Listen result: {e:?}"))?;
+    Ok(up)
+}
+
+/// Await the next upstream frame, or never resolve when there is no upstream
+/// subscription (so the `select!` branch stays idle in non-shim mode).
+async fn next_upstream(
+    rx: &mut Option<tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>>,
+) -> Option<Vec<u8>> {
+    match rx {
+        Some(r) => r.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,478 @@
+// Jackson Coxson
+//
+// Embeddable usbmuxd server. `NetmuxdServer` owns the manager thread and the
+// discovery tasks a `NetmuxdConfig` asks for, and serves the usbmuxd protocol
+// on whatever listeners it's given. The `netmuxd` binary is a thin CLI over
+// this: it binds the Unix socket / TCP port and hands them to the builder.
+
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::ConfigError,
+    config::NetmuxdConfig,
+    daemon, dnssd,
+    manager::{self, ManagerRequest, ManagerSender, new_manager_thread},
+    mdns,
+    pairing_file::PairingFileFinder,
+    pairing_store::{self, PairingStore, PairingStoreError},
+    pairing_watch, static_devices,
+    tls::{ACCEPT_BACKOFF, ACCEPT_BACKOFF_MAX, TlsError},
+    upstream::Upstream,
+};
+
+mod acceptor;
+mod binary;
+mod error;
+mod handler;
+mod listen;
+mod reload;
+
+pub use crate::access::Peer;
+pub use acceptor::{AcceptFuture, Acceptor, AsyncReadWrite, BoxedStream};
+pub use error::MuxError;
+pub use reload::{ConfigSource, ReloadError, ReloadHandle};
+
+use handler::{ClientContext, handle_stream};
+
+/// Errors that end [`NetmuxdServer::serve`].
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("mDNS discovery stopped unexpectedly")]
+    MdnsStopped,
+    #[error("upstream TLS configuration: {0}")]
+    UpstreamTls(#[from] TlsError),
+    #[error("pairing store: {0}")]
+    PairingStore(#[from] PairingStoreError),
+}
+
+/// Builder for [`NetmuxdServer`].
+pub struct NetmuxdServerBuilder {
+    config: NetmuxdConfig,
+    listeners: Vec<Box<dyn Acceptor>>,
+    config_source: Option<Arc<ConfigSource>>,
+    pairing_store: Option<Arc<dyn PairingStore>>,
+}
+
+impl NetmuxdServerBuilder {
+    /// Serve clients accepted from `listener`. May be called any number of
+    /// times; every listener is served concurrently.
+    pub fn listener(mut self, listener: impl Acceptor) -> Self {
+        self.listeners.push(Box::new(listener));
+        self
+    }
+
+    /// Where [`ReloadHandle::reload`] (and the `ReloadConfig` control request)
+    /// gets a fresh configuration from. Without one, only
+    /// [`ReloadHandle::apply`] can change settings.
+    pub fn config_source(
+        mut self,
+        source: impl Fn() -> Result<NetmuxdConfig, ConfigError> + Send + Sync + 'static,
+    ) -> Self {
+        self.config_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Keep pairing records in `store` instead of the one the
+    /// configuration's `pairing_store` names.
+    pub fn pairing_store(mut self, store: Arc<dyn PairingStore>) -> Self {
+        self.pairing_store = Some(store);
+        self
+    }
+
+    /// Open the pairing store, start the manager thread and return the
+    /// server, ready to [`serve`].
+    ///
+    /// Must be called from within a tokio runtime.
+    ///
+    /// [`serve`]: NetmuxdServer::serve
+    pub fn build(self) -> Result<NetmuxdServer, ServerError> {
+        let store = match self.pairing_store {
+            Some(store) => store,
+            None => pairing_store::open(&self.config)?,
+        };
+        let pairing_file_finder = PairingFileFinder::new(store);
+        let manager_sender = new_manager_thread(&self.config, pairing_file_finder.clone());
+        let (shutdown_tx, _) = watch::channel(false);
+        Ok(NetmuxdServer {
+            reload: ReloadHandle::new(self.config.clone(), self.config_source),
+            config: self.config,
+            manager_sender,
+            pairing_file_finder,
+            listeners: self.listeners,
+            shutdown: ShutdownHandle { tx: shutdown_tx },
+        })
+    }
+}
+
+/// Cloneable trigger that stops a running [`NetmuxdServer::serve`].
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    tx: watch::Sender<bool>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+}
+
+/// A usbmuxd server embedded in the current process.
+pub struct NetmuxdServer {
+    config: NetmuxdConfig,
+    manager_sender: ManagerSender,
+    pairing_file_finder: PairingFileFinder,
+    listeners: Vec<Box<dyn Acceptor>>,
+    shutdown: ShutdownHandle,
+    reload: ReloadHandle,
+}
+
+impl NetmuxdServer {
+    pub fn builder(config: NetmuxdConfig) -> NetmuxdServerBuilder {
+        NetmuxdServerBuilder {
+            config,
+            listeners: Vec::new(),
+            config_source: None,
+            pairing_store: None,
+        }
+    }
+
+    /// Channel into the device manager, for registering devices or
+    /// subscribing to attach/detach events in-process.
+    pub fn manager(&self) -> ManagerSender {
+        self.manager_sender.clone()
+    }
+
+    /// Pairing records as the server sees them, for adding or removing
+    /// records in-process.
+    pub fn pairing_file_finder(&self) -> PairingFileFinder {
+        self.pairing_file_finder.clone()
+    }
+
+    pub fn config(&self) -> &NetmuxdConfig {
+        &self.config
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+
+    /// Start USB / mDNS discovery as configured and serve every listener
+    /// until [`shutdown`](Self::shutdown) is called.
+    pub async fn serve(self) -> Result<(), ServerError> {
+        let NetmuxdServer {
+            config,
+            manager_sender,
+            pairing_file_finder,
+            listeners,
+            shutdown,
+            reload,
+        } = self;
+
+        // What the embedder (or `main`) set up, restored when `log_level`
+        // is dropped from the configuration.
+        let default_log_level = log::max_level();
+        reload::apply_log_level(&config, default_log_level);
+
+        let upstream = config
+            .upstream
+            .clone()
+            .map(|addr| Upstream::new(addr, &config.upstream_tls))
+            .transpose()?;
+
+        // Every client task holds a clone of `drain_tx`; shutdown waits for
+        // the channel to close.
+        let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+        let ctx = ClientContext {
+            manager_sender: manager_sender.clone(),
+            pairing_file_finder: pairing_file_finder.clone(),
+            upstream,
+            reload: reload.clone(),
+            stop: shutdown.subscribe(),
+            drain: drain_tx,
+        };
+        for listener in listeners {
+            tokio::spawn(accept_loop(listener, ctx.clone(), shutdown.subscribe()));
+        }
+        drop(ctx);
+
+        let pairing_task = tokio::spawn(pairing_watch::run(
+            pairing_file_finder.clone(),
+            manager_sender.clone(),
+        ));
+
+        let mut usb_task = None;
+        if config.use_usb {
+            if daemon::usb_available(config.apple_mux) {
+                let manager_sender = manager_sender.clone();
+                let config = config.clone();
+                let pairing_file_finder = pairing_file_finder.clone();
+                usb_task = Some(tokio::spawn(async move {
+                    daemon::discover(manager_sender, config, pairing_file_finder).await;
+                    error!("USB discovery stopped");
+                }));
+            } else {
+                warn!(
+                    "USB is enabled but the libusbK backend is unavailable (--libusbk was passed but \
+                     libusbK.dll was not found, or this build has no libusbK support); continuing \
+                     without USB. Network/mDNS devices are unaffected. Drop --libusbk to use the default \
+                     Apple-driver backend, or place libusbK.dll next to netmuxd.exe."
+                );
+            }
+        }
+
+        let mut stop = shutdown.subscribe();
+        let mut reloaded = reload.subscribe();
+        let mut current = config;
+        let mut mdns_task = current.use_mdns.then(|| {
+            tokio::spawn(mdns::discover(
+                manager_sender.clone(),
+                current.clone(),
+                pairing_file_finder.clone(),
+            ))
+        });
+        let mut static_task = spawn_static_devices(&current, &manager_sender);
+        let mut dnssd_task = spawn_dnssd(&current, &manager_sender, &pairing_file_finder);
+        loop {
+            tokio::select! {
+                _ = stop.wait_for(|s| *s) => break,
+                _ = reloaded.changed() => {
+                    let new = reloaded.borrow_and_update().clone();
+                    reload::apply_log_level(&new, default_log_level);
+                    apply_reload(
+                        &current,
+                        &new,
+                        &mut mdns_task,
+                        &mut static_task,
+                        &mut dnssd_task,
+                        &manager_sender,
+                        &pairing_file_finder,
+                    )
+                    .await;
+                    current = new;
+                }
+                _ = wait_task(&mut mdns_task) => return Err(ServerError::MdnsStopped),
+            }
+        }
+
+        // Listeners and idle clients have seen `stop` by now. Stop finding
+        // devices, detach the ones we have, and give clients and USB mux
+        // tasks until the drain timeout to wind down.
+        info!("Shutting down");
+        for task in [
+            mdns_task,
+            static_task,
+            dnssd_task,
+            usb_task,
+            Some(pairing_task),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            task.abort();
+        }
+        let (tx, rx) = oneshot::channel();
+        let handles = match manager_sender
+            .send(ManagerRequest {
+                request_type: manager::ManagerRequestType::Shutdown { response: tx },
+                response: None,
+            })
+            .await
+        {
+            Ok(()) => rx.await.unwrap_or_default(),
+            Err(e) => {
+                error!("Manager channel is closed: {e:?}");
+                Vec::new()
+            }
+        };
+        let drain = async {
+            for h in &handles {
+                h.closed().await;
+            }
+            // Resolves once every client task has dropped its sender.
+            let _ = drain_rx.recv().await;
+        };
+        if tokio::time::timeout(current.drain_timeout, drain)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Clients still connected after {:?}, shutting down anyway",
+                current.drain_timeout
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Bring the running server in line with a reloaded configuration.
+async fn apply_reload(
+    old: &NetmuxdConfig,
+    new: &NetmuxdConfig,
+    mdns_task: &mut Option<JoinHandle<()>>,
+    static_task: &mut Option<JoinHandle<()>>,
+    dnssd_task: &mut Option<JoinHandle<()>>,
+    manager_sender: &ManagerSender,
+    pairing_file_finder: &PairingFileFinder,
+) {
+    info!("Applying reloaded configuration");
+
+    let mdns_changed = new.mdns_interfaces != old.mdns_interfaces
+        || new.mdns_exclude_interfaces != old.mdns_exclude_interfaces
+        || new.mdns_address_policy != old.mdns_address_policy
+        || new.mdns_probe != old.mdns_probe;
+    if new.use_mdns != old.use_mdns || (new.use_mdns && mdns_changed) {
+        if let Some(task) = mdns_task.take() {
+            info!("Stopping mDNS discovery");
+            task.abort();
+        }
+        if new.use_mdns {
+            info!("Starting mDNS discovery");
+            *mdns_task = Some(tokio::spawn(mdns::discover(
+                manager_sender.clone(),
+                new.clone(),
+                pairing_file_finder.clone(),
+            )));
+        }
+    }
+
+    if new.dnssd_server != old.dnssd_server
+        || new.dnssd_domain != old.dnssd_domain
+        || new.dnssd_interval != old.dnssd_interval
+    {
+        if let Some(task) = dnssd_task.take() {
+            info!("Stopping DNS-SD discovery");
+            task.abort();
+        }
+        *dnssd_task = spawn_dnssd(new, manager_sender, pairing_file_finder);
+    }
+
+    if new.static_devices != old.static_devices
+        || new.static_device_interval != old.static_device_interval
+    {
+        if let Some(task) = static_task.take() {
+            task.abort();
+        }
+        // Entries that were removed or now point elsewhere go away now rather
+        // than at their next failed heartbeat. The new list (or mDNS) adds
+        // them back if they're still reachable.
+        for gone in old
+            .static_devices
+            .iter()
+            .filter(|d| !new.static_devices.contains(d))
+        {
+            info!("Removing static device {}", gone.udid);
+            if let Err(e) = manager_sender
+                .send(ManagerRequest {
+                    request_type: manager::ManagerRequestType::RemoveDevice {
+                        udid: gone.udid.clone(),
+                        connection_type: Some("Network".to_string()),
+                    },
+                    response: None,
+                })
+                .await
+            {
+                error!("Manager channel is closed: {e:?}");
+            }
+        }
+        *static_task = spawn_static_devices(new, manager_sender);
+    }
+
+    if let Err(e) = manager_sender
+        .send(ManagerRequest {
+            request_type: manager::ManagerRequestType::Reconfigure(new.clone()),
+            response: None,
+        })
+        .await
+    {
+        error!("Manager channel is closed: {e:?}");
+    }
+}
+
+/// Start browsing for wide-area Bonjour services, if a DNS server is set.
+fn spawn_dnssd(
+    config: &NetmuxdConfig,
+    manager_sender: &ManagerSender,
+    pairing_file_finder: &PairingFileFinder,
+) -> Option<JoinHandle<()>> {
+    config.dnssd_server?;
+    Some(tokio::spawn(dnssd::discover(
+        manager_sender.clone(),
+        config.clone(),
+        pairing_file_finder.clone(),
+    )))
+}
+
+/// Start probing the configured static devices, if there are any.
+fn spawn_static_devices(
+    config: &NetmuxdConfig,
+    manager_sender: &ManagerSender,
+) -> Option<JoinHandle<()>> {
+    if config.static_devices.is_empty() {
+        return None;
+    }
+    Some(tokio::spawn(static_devices::run(
+        manager_sender.clone(),
+        config.static_devices.clone(),
+        config.static_device_interval,
+    )))
+}
+
+/// Await a discovery task, or never resolve if it isn't running.
+async fn wait_task(task: &mut Option<JoinHandle<()>>) {
+    match task {
+        Some(t) => {
+            let _ = t.await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn accept_loop(
+    mut listener: Box<dyn Acceptor>,
+    ctx: ClientContext,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let socket = tokio::select! {
+            _ = stop.wait_for(|s| *s) => return,
+            s = listener.accept() => s,
+        };
+        match socket {
+            Ok((socket, peer)) => {
+                backoff = ACCEPT_BACKOFF;
+                handle_stream(socket, peer, ctx.clone())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
+                warn!("Listener closed: {e:?}");
+                return;
+            }
+            Err(e) => {
+                warn!("Error accepting connection: {e:?}");
+                tokio::select! {
+                    _ = stop.wait_for(|s| *s) => return,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Jackson Coxson
+//
+// Live configuration reload. A reload re-reads the configuration, keeps the
+// settings that only make sense at startup (listeners, storage, USB backend,
+// upstream), and applies the rest without touching devices or open tunnels.
+
+use std::sync::Arc;
+
+use log::{LevelFilter, info, warn};
+use tokio::sync::watch;
+
+use crate::config::{ConfigError, NetmuxdConfig};
+
+/// Produces a fresh configuration on reload, e.g. [`NetmuxdConfig::collect`].
+pub type ConfigSource = dyn Fn() -> Result<NetmuxdConfig, ConfigError> + Send + Sync;
+
+/// Errors from [`ReloadHandle::reload`].
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("no configuration source was given to the server builder")]
+    NoSource,
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+/// Cloneable trigger that re-applies configuration to a running
+/// [`NetmuxdServer`](super::NetmuxdServer).
+#[derive(Clone)]
+pub struct ReloadHandle {
+    tx: watch::Sender<NetmuxdConfig>,
+    source: Option<Arc<ConfigSource>>,
+}
+
+impl ReloadHandle {
+    pub(super) fn new(config: NetmuxdConfig, source: Option<Arc<ConfigSource>>) -> Self {
+        let (tx, _) = watch::channel(config);
+        Self { tx, source }
+    }
+
+    /// Re-read the configuration from the builder's source and apply it.
+    /// On error the running configuration is left as it was.
+    pub fn reload(&self) -> Result<(), ReloadError> {
+        let source = self.source.as_ref().ok_or(ReloadError::NoSource)?;
+        self.apply(source()?);
+        Ok(())
+    }
+
+    /// Apply the live-reloadable settings of `config`. Settings that need a
+    /// restart are logged and ignored.
+    pub fn apply(&self, config: NetmuxdConfig) {
+        self.tx
+            .send_modify(|current| *current = merge(current, config));
+    }
+
+    /// The configuration currently in effect.
+    pub fn current(&self) -> NetmuxdConfig {
+        self.tx.borrow().clone()
+    }
+
+    pub(super) fn subscribe(&self) -> watch::Receiver<NetmuxdConfig> {
+        self.tx.subscribe()
+    }
+}
+
+/// `current` with the live settings of `new` applied.
+fn merge(current: &NetmuxdConfig, new: NetmuxdConfig) -> NetmuxdConfig {
+    let mut restart_only = Vec::new();
+    if new.port != current.port || new.host != current.host {
+        restart_only.push("host/port");
+    }
+    if new.plist_storage != current.plist_storage
+        || new.pairing_store != current.pairing_store
+        || new.pairing_database != current.pairing_database
+    {
+        restart_only.push("pairing storage");
+    }
+    if new.device_ids_file != current.device_ids_file {
+        restart_only.push("device_ids_file");
+    }
+    if new.use_usb != current.use_usb
+        || new.apple_mux != current.apple_mux
+        || new.enable_wifi_connections != current.enable_wifi_connections
+    {
+        restart_only.push("usb");
+    }
+    if new.pairing_policy != current.pairing_policy {
+        restart_only.push("pairing_*");
+    }
+    if new.upstream != current.upstream || new.upstream_tls != current.upstream_tls {
+        restart_only.push("upstream_usbmuxd/upstream_tls_*");
+    }
+    if new.tls_listen != current.tls_listen
+        || new.tls_cert != current.tls_cert
+        || new.tls_key != current.tls_key
+        || new.tls_client_ca != current.tls_client_ca
+    {
+        restart_only.push("tls_*");
+    }
+    #[cfg(unix)]
+    if new.use_unix != current.use_unix
+        || new.socket_path != current.socket_path
+        || new.socket_owner != current.socket_owner
+        || new.socket_group != current.socket_group
+        || new.socket_mode != current.socket_mode
+    {
+        restart_only.push("unix/socket_*");
+    }
+    if !restart_only.is_empty() {
+        warn!(
+            "Ignoring changes to {} until netmuxd is restarted",
+            restart_only.join(", ")
+        );
+    }
+
+    let mut merged = current.clone();
+    merged.use_mdns = new.use_mdns;
+    merged.mdns_interfaces = new.mdns_interfaces;
+    merged.mdns_exclude_interfaces = new.mdns_exclude_interfaces;
+    merged.mdns_address_policy = new.mdns_address_policy;
+    merged.mdns_probe = new.mdns_probe;
+    merged.dnssd_server = new.dnssd_server;
+    merged.dnssd_domain = new.dnssd_domain;
+    merged.dnssd_interval = new.dnssd_interval;
+    merged.use_heartbeat = new.use_heartbeat;
+    merged.heartbeat_retries = new.heartbeat_retries;
+    merged.heartbeat_backoff = new.heartbeat_backoff;
+    merged.usb_liveness = new.usb_liveness;
+    merged.static_devices = new.static_devices;
+    merged.static_device_interval = new.static_device_interval;
+    merged.access = new.access;
+    merged.connection_policy = new.connection_policy;
+    merged.drain_timeout = new.drain_timeout;
+    merged.log_level = new.log_level;
+    merged.config_path = new.config_path;
+    merged
+}
+
+/// Set the global log level to the configured one, or back to `default` when
+/// the configuration doesn't name one.
+pub(super) fn apply_log_level(config: &NetmuxdConfig, default: LevelFilter) {
+    let level = config.log_level.unwrap_or(default);
+    if level != log::max_level() {
+        log::set_max_level(level);
+        info!("Log level set to {level}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::UpstreamAddr;
+    use idevice::usbmuxd::UsbmuxdAddr;
+
+    fn tls_upstream(host: &str) -> Option<UpstreamAddr> {
+        Some(UpstreamAddr::Tls {
+            host: host.to_string(),
+            port: 27015,
+        })
+    }
+
+    #[test]
+    fn upstream_changes_are_restart_only() {
+        let mut current = NetmuxdConfig::default();
+        current.upstream = tls_upstream("hub.local");
+        current.upstream_tls.ca = Some("ca.pem".into());
+
+        let mut new = current.clone();
+        new.upstream = tls_upstream("other.local");
+        new.upstream_tls.ca = Some("other-ca.pem".into());
+        let merged = merge(&current, new);
+        assert_eq!(merged.upstream, current.upstream);
+        assert_eq!(merged.upstream_tls, current.upstream_tls);
+    }
+
+    #[test]
+    fn upstream_addresses_compare_by_value() {
+        let tcp = |a: &str| UpstreamAddr::Usbmuxd(UsbmuxdAddr::TcpSocket(a.parse().unwrap()));
+        assert_eq!(tcp("127.0.0.1:27015"), tcp("127.0.0.1:27015"));
+        assert_ne!(tcp("127.0.0.1:27015"), tcp("127.0.0.1:27016"));
+        assert_ne!(tcp("127.0.0.1:27015"), tls_upstream("127.0.0.1").unwrap());
+        assert_eq!(tls_upstream("hub.local"), tls_upstream("hub.local"));
+    }
+
+    #[test]
+    fn live_settings_are_applied() {
+        let current = NetmuxdConfig {
+            log_level: Some(LevelFilter::Debug),
+            ..Default::default()
+        };
+        let new = NetmuxdConfig {
+            use_mdns: !current.use_mdns,
+            log_level: None,
+            ..Default::default()
+        };
+        let merged = merge(&current, new);
+        assert_eq!(merged.use_mdns, !current.use_mdns);
+        // A removed key must not keep the old level.
+        assert_eq!(merged.log_level, None);
+    }
+
+    #[test]
+    fn removed_log_level_falls_back_to_the_default() {
+        let debug = NetmuxdConfig {
+            log_level: Some(LevelFilter::Debug),
+            ..Default::default()
+        };
+        apply_log_level(&debug, LevelFilter::Warn);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+        apply_log_level(&NetmuxdConfig::default(), LevelFilter::Warn);
+        assert_eq!(log::max_level(), LevelFilter::Warn);
+    }
+}
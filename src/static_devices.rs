@@ -0,0 +1,135 @@
+// Jackson Coxson
+//
+// Network devices named in the configuration, for networks where multicast
+// doesn't get through and mDNS never finds them. Each one is offered to the
+// manager at startup and again every probe interval, so a device that was
+// off or dropped by its heartbeat comes back on its own. The manager ignores
+// offers for devices it already lists.
+
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use log::{debug, info, warn};
+use tokio::sync::oneshot;
+
+use crate::{
+    devices::DeviceAddr,
+    manager::{ManagerRequest, ManagerRequestType, ManagerSender, NetworkAdd},
+};
+
+/// Listed as the devices' service name. Not the mDNS one, so mDNS dropping a
+/// device it lost sight of leaves a configured entry alone.
+const SERVICE_NAME: &str = "_apple-mobdev2._tcp.static";
+
+/// A `UDID=host[:port]` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticDevice {
+    pub udid: String,
+    /// IP address (`fe80::1%en0` for a scoped one) or hostname, resolved on
+    /// every probe.
+    pub host: String,
+    /// lockdownd's port, if not the standard 62078.
+    pub port: Option<u16>,
+}
+
+impl std::str::FromStr for StaticDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || "expected UDID=host or UDID=host:port".to_string();
+        let (udid, addr) = s.split_once('=').ok_or_else(bad)?;
+        let (host, port) = match addr.strip_prefix('[') {
+            // [v6] or [v6]:port
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(bad)?;
+                match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None if rest.is_empty() => (host, None),
+                    None => return Err(bad()),
+                }
+            }
+            // A bare IPv6 address has more than one colon.
+            None if addr.matches(':').count() > 1 => (addr, None),
+            None => match addr.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (addr, None),
+            },
+        };
+        if udid.is_empty() || host.is_empty() {
+            return Err(bad());
+        }
+        let port = port
+            .map(|p| p.parse().map_err(|e| format!("bad port: {e}")))
+            .transpose()?;
+        Ok(Self {
+            udid: udid.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Offer every device to the manager now and then every `interval`.
+pub async fn run(sender: ManagerSender, devices: Vec<StaticDevice>, interval: Duration) {
+    info!("Probing {} static network device(s)", devices.len());
+    join_all(
+        devices
+            .into_iter()
+            .map(|device| probe_loop(sender.clone(), device, interval)),
+    )
+    .await;
+}
+
+async fn probe_loop(sender: ManagerSender, device: StaticDevice, interval: Duration) {
+    loop {
+        match resolve(&device.host).await {
+            Some(address) => {
+                let (tx, rx) = oneshot::channel();
+                if sender
+                    .send(ManagerRequest {
+                        request_type: ManagerRequestType::DiscoveredNetworkDevice {
+                            udid: device.udid.clone(),
+                            network_address: address,
+                            service_name: SERVICE_NAME.to_string(),
+                            connection_type: "Network".to_string(),
+                            lockdown_port: device.port,
+                            response: Some(tx),
+                        },
+                        response: None,
+                    })
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                match rx.await {
+                    Ok(Ok(NetworkAdd::Added)) => {
+                        info!("Added static device {} at {address}", device.udid)
+                    }
+                    Ok(Ok(NetworkAdd::AlreadyListed)) => {}
+                    Ok(Err(e)) => debug!("Static device {} was not added: {e}", device.udid),
+                    // The manager is shutting down.
+                    Err(_) => {}
+                }
+            }
+            None => warn!(
+                "Could not resolve {} for static device {}",
+                device.host, device.udid
+            ),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn resolve(host: &str) -> Option<DeviceAddr> {
+    if let Ok(addr) = host.parse() {
+        return Some(addr);
+    }
+    match tokio::net::lookup_host((host, 0)).await {
+        Ok(mut addrs) => addrs.next().map(|a| a.ip().into()),
+        Err(e) => {
+            debug!("Failed to resolve {host}: {e}");
+            None
+        }
+    }
+}
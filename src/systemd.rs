@@ -0,0 +1,246 @@
+// Jackson Coxson
+//
+// systemd integration without libsystemd: socket activation (`LISTEN_FDS`)
+// and the `sd_notify` protocol (`NOTIFY_SOCKET`). Both are plain environment
+// variables plus a datagram socket, so a few lines of std do the job.
+
+use std::{
+    io,
+    os::{
+        fd::{FromRawFd, IntoRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::sync::oneshot;
+
+use crate::manager::{ManagerRequest, ManagerRequestType, ManagerSender};
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// How often STATUS is refreshed when no watchdog asks for more.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A listening socket handed over by systemd.
+pub enum ActivatedListener {
+    Unix(tokio::net::UnixListener),
+    Tcp(tokio::net::TcpListener),
+}
+
+/// Take the listening sockets systemd passed via `LISTEN_FDS`, if they are
+/// meant for this process. Must be called from within a tokio runtime.
+pub fn listen_fds() -> io::Result<Vec<ActivatedListener>> {
+    let count = listen_fd_count(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd passes these descriptors to us open, and nothing
+        // else in the process claims them.
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        // getsockname on a Unix socket fails to convert into a SocketAddr.
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            debug!("Using socket-activated TCP listener on fd {fd}");
+            listeners.push(ActivatedListener::Tcp(tokio::net::TcpListener::from_std(
+                tcp,
+            )?));
+            continue;
+        }
+        // SAFETY: ownership of the same descriptor moves straight across.
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        if let Err(e) = unix.local_addr() {
+            warn!("Ignoring socket-activated fd {fd} that isn't a TCP or Unix listener: {e}");
+            // Leave it open; systemd owns what it passed us.
+            let _ = unix.into_raw_fd();
+            continue;
+        }
+        unix.set_nonblocking(true)?;
+        debug!("Using socket-activated Unix listener on fd {fd}");
+        listeners.push(ActivatedListener::Unix(tokio::net::UnixListener::from_std(
+            unix,
+        )?));
+    }
+    Ok(listeners)
+}
+
+/// How many descriptors `LISTEN_PID` and `LISTEN_FDS` hand to the process
+/// `own_pid`; zero when they are missing, malformed, or meant for another.
+fn listen_fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, own_pid: u32) -> RawFd {
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(own_pid) {
+        return 0;
+    }
+    listen_fds
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(0)
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager. Returns `false` when
+/// not running under systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract NOTIFY_SOCKET is Linux-only",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), &*path)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Watchdog ping interval requested by systemd (half of `WATCHDOG_USEC`).
+fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// The ping interval for `own_pid` given `WATCHDOG_PID` and `WATCHDOG_USEC`.
+/// A missing `WATCHDOG_PID` means the watchdog is ours; zero disables it.
+fn watchdog_interval_from(
+    watchdog_pid: Option<&str>,
+    watchdog_usec: Option<&str>,
+    own_pid: u32,
+) -> Option<Duration> {
+    if let Some(pid) = watchdog_pid.and_then(|p| p.parse::<u32>().ok())
+        && pid != own_pid
+    {
+        return None;
+    }
+    let usec: u64 = watchdog_usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Report readiness, then keep systemd's STATUS line current with the device
+/// counts and feed the watchdog, if one is configured. Runs until the manager
+/// stops answering; returns immediately when not running under systemd.
+pub async fn run_notifier(manager: ManagerSender) {
+    match notify("READY=1\nSTATUS=Starting device discovery") {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            warn!("sd_notify failed: {e}");
+            return;
+        }
+    }
+
+    let watchdog = watchdog_interval();
+    let interval = watchdog.map_or(STATUS_INTERVAL, |w| w.min(STATUS_INTERVAL));
+    let mut last_status = String::new();
+    loop {
+        let Some((usb, network)) = device_counts(&manager).await else {
+            return;
+        };
+        let status = format!(
+            "Serving {} devices ({usb} USB, {network} network)",
+            usb + network
+        );
+        let mut state = String::new();
+        if watchdog.is_some() {
+            state.push_str("WATCHDOG=1\n");
+        }
+        if status != last_status {
+            state.push_str(&format!("STATUS={status}\n"));
+            last_status = status;
+        }
+        if !state.is_empty()
+            && let Err(e) = notify(&state)
+        {
+            warn!("sd_notify failed: {e}");
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// (USB, network) device counts, or `None` if the manager is gone.
+async fn device_counts(manager: &ManagerSender) -> Option<(usize, usize)> {
+    let (tx, rx) = oneshot::channel();
+    manager
+        .send(ManagerRequest {
+            request_type: ManagerRequestType::ListDevices,
+            response: Some(tx),
+        })
+        .await
+        .ok()?;
+    let res = rx.await.ok()?;
+    let list = res.get("DeviceList").and_then(|v| v.as_array())?;
+    let usb = list
+        .iter()
+        .filter(|d| {
+            d.as_dictionary()
+                .and_then(|d| d.get("Properties"))
+                .and_then(|p| p.as_dictionary())
+                .and_then(|p| p.get("ConnectionType"))
+                .and_then(|c| c.as_string())
+                == Some("USB")
+        })
+        .count();
+    Some((usb, list.len() - usb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds_only_for_this_process() {
+        assert_eq!(listen_fd_count(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fd_count(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fd_count(None, Some("2"), 42), 0);
+        assert_eq!(listen_fd_count(Some("pid"), Some("2"), 42), 0);
+    }
+
+    #[test]
+    fn listen_fds_rejects_bad_counts() {
+        assert_eq!(listen_fd_count(Some("42"), Some("two"), 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), Some("-1"), 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), Some(""), 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), None, 42), 0);
+    }
+
+    #[test]
+    fn watchdog_pings_at_half_the_timeout() {
+        let half = Some(Duration::from_secs(15));
+        assert_eq!(watchdog_interval_from(None, Some("30000000"), 42), half);
+        assert_eq!(
+            watchdog_interval_from(Some("42"), Some("30000000"), 42),
+            half
+        );
+    }
+
+    #[test]
+    fn watchdog_for_another_process_is_ignored() {
+        assert_eq!(
+            watchdog_interval_from(Some("41"), Some("30000000"), 42),
+            None
+        );
+        assert_eq!(watchdog_interval_from(Some("42"), None, 42), None);
+        assert_eq!(watchdog_interval_from(None, Some("soon"), 42), None);
+        assert_eq!(watchdog_interval_from(None, Some("0"), 42), None);
+    }
+}
@@ -0,0 +1,260 @@
+// Jackson Coxson
+//
+// TLS for usbmuxd over the network: a listener that wraps accepted TCP
+// clients (optionally requiring client certificates), and the client side
+// used to reach an upstream netmuxd over TLS. Both pin the aws-lc-rs
+// provider explicitly since idevice pulls in more than one.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+    access::Peer,
+    server::{AcceptFuture, Acceptor, BoxedStream},
+};
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept (out of file descriptors, say), doubling up to
+/// the max while it keeps failing, so the accept loop doesn't spin.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+pub(crate) const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {path:?}: {reason}")]
+    Pem { path: PathBuf, reason: String },
+    #[error("no certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+    #[error("TLS setup failed: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("client certificate verifier: {0}")]
+    Verifier(#[from] rustls::server::VerifierBuilderError),
+    #[error("{0}")]
+    Config(String),
+}
+
+/// Certificate files for the TLS listener.
+#[derive(Debug, Clone)]
+pub struct TlsServerFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle that client certificates must chain to. `None` accepts any
+    /// client.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Certificate files for connecting to an upstream over TLS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsClientFiles {
+    /// CA bundle the upstream's certificate must chain to.
+    pub ca: Option<PathBuf>,
+    /// Client certificate and key, for upstreams that require mutual TLS.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Name to verify the upstream's certificate against, if not its host.
+    pub server_name: Option<String>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| TlsError::Pem {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+pub fn server_config(files: &TlsServerFiles) -> Result<Arc<ServerConfig>, TlsError> {
+    let provider = provider();
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &files.client_ca {
+        Some(ca) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca)?), provider)
+                .build()?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(&files.cert)?, load_key(&files.key)?)?;
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(files: &TlsClientFiles) -> Result<Arc<ClientConfig>, TlsError> {
+    let ca = files
+        .ca
+        .as_ref()
+        .ok_or_else(|| TlsError::Config("a CA bundle is required for TLS upstreams".into()))?;
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match (&files.cert, &files.key) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(TlsError::Config(
+                "a client certificate needs both a cert and a key".into(),
+            ));
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+/// Client side of a TLS upstream: a connector plus the name to verify.
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: tokio_rustls::TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl std::fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsClient {
+    pub fn new(files: &TlsClientFiles) -> Result<Self, TlsError> {
+        let server_name = files
+            .server_name
+            .as_ref()
+            .map(|n| {
+                ServerName::try_from(n.clone())
+                    .map_err(|_| TlsError::Config(format!("invalid TLS server name {n:?}")))
+            })
+            .transpose()?;
+        Ok(Self {
+            connector: tokio_rustls::TlsConnector::from(client_config(files)?),
+            server_name,
+        })
+    }
+
+    /// Connect to `host:port` and complete the handshake.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let server_name = match &self.server_name {
+            Some(n) => n.clone(),
+            None => ServerName::try_from(host.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        };
+        let tcp = tokio::net::TcpStream::connect((host, port)).await?;
+        let stream = self.connector.connect(server_name, tcp).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// A TCP listener that serves usbmuxd over TLS. Handshakes run on their own
+/// tasks so a slow client can't hold up the others.
+pub struct TlsListener {
+    accepted: mpsc::Receiver<(BoxedStream, Peer)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Start accepting on `listener`. Must be called from within a tokio
+    /// runtime.
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(config);
+        let (tx, accepted) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut backoff = ACCEPT_BACKOFF;
+            loop {
+                // Once the server drops us (it shut down), stop and free the
+                // port.
+                let accepted = tokio::select! {
+                    _ = tx.closed() => return,
+                    a = listener.accept() => a,
+                };
+                let (tcp, addr) = match accepted {
+                    Ok(a) => {
+                        backoff = ACCEPT_BACKOFF;
+                        a
+                    }
+                    Err(e) => {
+                        warn!("Error accepting TLS connection: {e:?}");
+                        tokio::select! {
+                            _ = tx.closed() => return,
+                            _ = tokio::time::sleep(backoff) => {}
+                        }
+                        backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx
+                                .send((Box::new(stream) as BoxedStream, Peer::Tcp(addr)))
+                                .await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {addr} failed: {e}"),
+                        Err(_) => debug!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            accepted,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Acceptor for TlsListener {
+    fn accept(&mut self) -> AcceptFuture<'_> {
+        Box::pin(async move {
+            self.accepted
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "TLS listener stopped"))
+        })
+    }
+}
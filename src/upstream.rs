@@ -2,22 +2,101 @@
 //
 // Forwarding to an upstream usbmuxd when running in shim mode. The shim adds
 // network devices of its own but defers USB devices and most request/response
-// traffic to the real muxer pointed at by `--upstream-usbmuxd`.
+// traffic to the real muxer pointed at by `--upstream-usbmuxd`, which may be
+// a local usbmuxd or a remote netmuxd's TLS listener.
 
-use idevice::{
-    ReadWrite,
-    usbmuxd::{RawPacket, UsbmuxdAddr, server::UsbmuxdServerResponse},
-};
+use idevice::usbmuxd::{RawPacket, UsbmuxdAddr, server::UsbmuxdServerResponse};
 use log::warn;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
+use crate::{
+    server::BoxedStream,
+    tls::{TlsClient, TlsClientFiles, TlsError},
+};
+
+/// Where the upstream muxer lives, as configured.
+#[derive(Debug, Clone)]
+pub enum UpstreamAddr {
+    /// A plain usbmuxd socket (Unix or TCP).
+    Usbmuxd(UsbmuxdAddr),
+    /// Another netmuxd's TLS listener, written `tls://host:port`.
+    Tls { host: String, port: u16 },
+}
+
+// `UsbmuxdAddr` doesn't implement `PartialEq`, so compare its variants here.
+impl PartialEq for UpstreamAddr {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Usbmuxd(a), Self::Usbmuxd(b)) => match (a, b) {
+                #[cfg(unix)]
+                (UsbmuxdAddr::UnixSocket(a), UsbmuxdAddr::UnixSocket(b)) => a == b,
+                (UsbmuxdAddr::TcpSocket(a), UsbmuxdAddr::TcpSocket(b)) => a == b,
+                _ => false,
+            },
+            (Self::Tls { host, port }, Self::Tls { host: h, port: p }) => host == h && port == p,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for UpstreamAddr {}
+
+impl std::fmt::Display for UpstreamAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Usbmuxd(addr) => write!(f, "{addr:?}"),
+            Self::Tls { host, port } if host.contains(':') => write!(f, "tls://[{host}]:{port}"),
+            Self::Tls { host, port } => write!(f, "tls://{host}:{port}"),
+        }
+    }
+}
+
+/// A configured upstream, ready to connect to.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    addr: UpstreamAddr,
+    tls: Option<TlsClient>,
+}
+
+impl Upstream {
+    /// Load the TLS client settings up front when `addr` is a TLS upstream,
+    /// so bad certificates fail at startup rather than on first use.
+    pub fn new(addr: UpstreamAddr, tls: &TlsClientFiles) -> Result<Self, TlsError> {
+        let tls = match addr {
+            UpstreamAddr::Tls { .. } => Some(TlsClient::new(tls)?),
+            UpstreamAddr::Usbmuxd(_) => None,
+        };
+        Ok(Self { addr, tls })
+    }
+
+    pub fn addr(&self) -> &UpstreamAddr {
+        &self.addr
+    }
+}
+
 /// Open a fresh connection to the upstream muxer.
-pub async fn connect(addr: &UsbmuxdAddr) -> Result<Box<dyn ReadWrite>, String> {
-    addr.to_socket()
-        .await
-        .map_err(|e| format!("connect to upstream usbmuxd: {e:?}"))
+pub async fn connect(upstream: &Upstream) -> Result<BoxedStream, String> {
+    match (&upstream.addr, &upstream.tls) {
+        (UpstreamAddr::Usbmuxd(addr), _) => addr
+            .to_socket()
+            .await
+            .map(|s| Box::new(s) as BoxedStream)
+            .map_err(|e| format!("connect to upstream usbmuxd: {e:?}")),
+        (UpstreamAddr::Tls { host, port }, Some(tls)) => tls
+            .connect(host, *port)
+            .await
+            .map_err(|e| format!("connect to upstream {}: {e}", upstream.addr)),
+        (UpstreamAddr::Tls { .. }, None) => {
+            Err("TLS upstream has no client configuration".to_string())
+        }
+    }
 }
 
+/// Largest frame accepted from upstream, the same cap the server puts on
+/// client packets. A remote upstream could otherwise have us allocate up to
+/// 4 GiB off one header.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
 /// Read one usbmuxd frame (16-byte header + body) as raw bytes.
 pub async fn read_frame<R: AsyncRead + Unpin + ?Sized>(sock: &mut R) -> std::io::Result<Vec<u8>> {
     let mut header = [0u8; 16];
@@ -29,6 +108,12 @@ pub async fn read_frame<R: AsyncRead + Unpin + ?Sized>(sock: &mut R) -> std::io:
             "upstream frame smaller than its header",
         ));
     }
+    if size > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("upstream frame of {size} bytes is over the {MAX_FRAME_SIZE}-byte limit"),
+        ));
+    }
     let mut buf = vec![0u8; size];
     buf[..16].copy_from_slice(&header);
     sock.read_exact(&mut buf[16..]).await?;
@@ -41,7 +126,7 @@ pub async fn read_frame<R: AsyncRead + Unpin + ?Sized>(sock: &mut R) -> std::io:
 /// fall back to handling the request locally if upstream is unreachable. Used
 /// for the request/response messages the shim doesn't special-case (ReadBUID,
 /// ReadPairRecord, SavePairRecord, and any unknown type).
-pub async fn forward_to_upstream(addr: &UsbmuxdAddr, request: &[u8]) -> Result<Vec<u8>, String> {
+pub async fn forward_to_upstream(addr: &Upstream, request: &[u8]) -> Result<Vec<u8>, String> {
     let mut up = connect(addr).await?;
     up.write_all(request)
         .await
@@ -51,11 +136,36 @@ pub async fn forward_to_upstream(addr: &UsbmuxdAddr, request: &[u8]) -> Result<V
         .map_err(|e| format!("read from upstream: {e:?}"))
 }
 
+/// Forward the client's verbatim `ListListeners` request to upstream and append
+/// `local_listeners` to the `ListenerList` it returns.
+pub async fn list_listeners_merged(
+    addr: &Upstream,
+    request: &[u8],
+    local_listeners: Vec<plist::Value>,
+    tag: u32,
+) -> Result<Vec<u8>, String> {
+    let frame = forward_to_upstream(addr, request).await?;
+    let parsed = RawPacket::try_from(frame.as_slice())
+        .map_err(|_| "could not parse upstream ListListeners response".to_string())?;
+    let mut list = match parsed.plist.get("ListenerList") {
+        Some(plist::Value::Array(a)) => a.clone(),
+        _ => {
+            warn!("upstream ListListeners response had no ListenerList array");
+            Vec::new()
+        }
+    };
+    list.extend(local_listeners);
+
+    let mut p = plist::Dictionary::new();
+    p.insert("ListenerList".into(), plist::Value::Array(list));
+    Ok(RawPacket::new(p, 1, 8, tag).into())
+}
+
 /// Forward the client's verbatim `ListDevices` request to upstream, then return
 /// a response whose `DeviceList` is the upstream list with `network_devices`
 /// appended. Preserves every property upstream reports for its USB devices.
 pub async fn list_devices_merged(
-    addr: &UsbmuxdAddr,
+    addr: &Upstream,
     request: &[u8],
     network_devices: Vec<plist::Value>,
     tag: u32,
@@ -83,3 +193,38 @@ pub async fn list_devices_merged(
         .into_packet(tag)
         .into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(size: u32, body: &[u8]) -> Vec<u8> {
+        let mut f = size.to_le_bytes().to_vec();
+        f.extend_from_slice(&[1, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0]);
+        f.extend_from_slice(body);
+        f
+    }
+
+    #[tokio::test]
+    async fn reads_a_whole_frame() {
+        let bytes = frame(20, b"abcdextra");
+        let mut reader = bytes.as_slice();
+        let read = read_frame(&mut reader).await.unwrap();
+        assert_eq!(read, bytes[..20]);
+        assert_eq!(reader, b"extra");
+    }
+
+    #[tokio::test]
+    async fn rejects_frame_smaller_than_header() {
+        let bytes = frame(8, &[]);
+        let err = read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frame_without_reading_it() {
+        let bytes = frame(MAX_FRAME_SIZE as u32 + 1, &[]);
+        let err = read_frame(&mut bytes.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
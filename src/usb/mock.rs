@@ -0,0 +1,372 @@
+//! In-memory iOS device that speaks the device side of the USB mux protocol.
+//!
+//! [`MockDevice`] answers the VERSION / SETUP handshake and the TCP-emulation
+//! framing that [`crate::usb::mux::spawn`] drives, over a
+//! [`tokio::io::duplex`] pipe instead of bulk endpoints. Each device port is
+//! backed by a caller-supplied handler that receives the device end of the
+//! virtual connection, so the mux, the manager, and the usbmuxd server can be
+//! exercised end-to-end without hardware.
+//!
+//! ```ignore
+//! let handle = MockDevice::new()
+//!     .service(62078, |mut stream| async move {
+//!         // speak lockdown over `stream`
+//!     })
+//!     .spawn_mux(1, "00008030-0000000000000000".into(), exit_tx);
+//! ```
+//!
+//! Only enabled with the `mock` feature.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use log::{debug, trace, warn};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::usb::mux::{self, UsbMuxHandle};
+
+// Wire constants mirrored from `mux.rs`; the mock is the other end of the
+// same protocol.
+const V1_HEADER_SIZE: usize = 8;
+const V2_HEADER_SIZE: usize = 16;
+const MUX_MAGIC: u32 = 0xfeedface;
+const TCP_HEADER_SIZE: usize = 20;
+const PROTO_VERSION: u32 = 0;
+const PROTO_SETUP: u32 = 2;
+const PROTO_TCP: u32 = 6;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/// Largest payload the mock puts in one TCP frame. Well under the host's
+/// `USB_MTU` so frames are never rejected as implausible.
+const MAX_CHUNK: usize = 16 * 1024;
+/// Size of the pipe between the host and the mock, and between the mock and
+/// each service handler.
+const PIPE_BUF: usize = 65536;
+/// Advertised receive window (in 256-byte units, so ~16 MiB). The mock never
+/// throttles the host.
+const WINDOW: u16 = 0xffff;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Per-port service: called once per accepted virtual connection with the
+/// device end of the stream.
+pub type ServiceHandler = Arc<dyn Fn(DuplexStream) -> BoxFuture + Send + Sync>;
+
+/// A fake device with a set of listening ports.
+#[derive(Clone, Default)]
+pub struct MockDevice {
+    services: HashMap<u16, ServiceHandler>,
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept connections to `port`, handing each one to `handler`. Connects
+    /// to ports without a handler are refused with an RST.
+    pub fn service<F, Fut>(mut self, port: u16, handler: F) -> Self
+    where
+        F: Fn(DuplexStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.services
+            .insert(port, Arc::new(move |s| Box::pin(handler(s)) as BoxFuture));
+        self
+    }
+
+    /// Start the device task and return the host's ends of the "bulk"
+    /// pipe, ready to pass to [`mux::spawn`] as `reader` / `writer`.
+    pub fn spawn(self) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+        let (host, device) = tokio::io::duplex(PIPE_BUF);
+        let (device_read, device_write) = tokio::io::split(device);
+        crate::spawn(async move {
+            if let Err(e) = run(self.services, device_read, device_write).await {
+                debug!("mock device exited: {e:?}");
+            }
+        });
+        tokio::io::split(host)
+    }
+
+    /// Convenience for [`spawn`](Self::spawn) followed by [`mux::spawn`].
+    pub fn spawn_mux(
+        self,
+        device_id: u64,
+        serial: String,
+        on_exit: oneshot::Sender<u64>,
+    ) -> UsbMuxHandle {
+        let (reader, writer) = self.spawn();
+        mux::spawn(device_id, serial, reader, writer, on_exit)
+    }
+}
+
+/// Device-side state for one virtual connection, keyed by the host's sport.
+struct Connection {
+    dport: u16,
+    /// Next sequence number for bytes we send.
+    seq: u32,
+    /// Host bytes consumed so far (what we ACK).
+    ack: u32,
+    to_service: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Outgoing-side framing state.
+struct Framer<W> {
+    writer: W,
+    version: u8,
+    tx_seq: u16,
+    rx_seq: u16,
+}
+
+impl<W: AsyncWrite + Unpin> Framer<W> {
+    async fn send(&mut self, proto: u32, header: &[u8], payload: &[u8]) -> io::Result<()> {
+        let header_size = if self.version < 2 {
+            V1_HEADER_SIZE
+        } else {
+            V2_HEADER_SIZE
+        };
+        let total = header_size + header.len() + payload.len();
+        let mut buf = Vec::with_capacity(total);
+        buf.extend_from_slice(&proto.to_be_bytes());
+        buf.extend_from_slice(&(total as u32).to_be_bytes());
+        if self.version >= 2 {
+            buf.extend_from_slice(&MUX_MAGIC.to_be_bytes());
+            buf.extend_from_slice(&self.tx_seq.to_be_bytes());
+            buf.extend_from_slice(&self.rx_seq.to_be_bytes());
+            self.tx_seq = self.tx_seq.wrapping_add(1);
+        }
+        buf.extend_from_slice(header);
+        buf.extend_from_slice(payload);
+        self.writer.write_all(&buf).await?;
+        self.writer.flush().await
+    }
+
+    async fn send_tcp(
+        &mut self,
+        sport: u16,
+        conn_dport: u16,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // From the device's point of view the host's dport is our source.
+        let mut hdr = [0u8; TCP_HEADER_SIZE];
+        hdr[0..2].copy_from_slice(&conn_dport.to_be_bytes());
+        hdr[2..4].copy_from_slice(&sport.to_be_bytes());
+        hdr[4..8].copy_from_slice(&seq.to_be_bytes());
+        hdr[8..12].copy_from_slice(&ack.to_be_bytes());
+        hdr[12] = (TCP_HEADER_SIZE as u8 / 4) << 4;
+        hdr[13] = flags;
+        hdr[14..16].copy_from_slice(&WINDOW.to_be_bytes());
+        self.send(PROTO_TCP, &hdr, payload).await
+    }
+}
+
+async fn run<R, W>(
+    services: HashMap<u16, ServiceHandler>,
+    mut reader: R,
+    writer: W,
+) -> io::Result<()>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let mut framer = Framer {
+        writer,
+        version: 0,
+        tx_seq: 0,
+        rx_seq: 0,
+    };
+
+    // VERSION request arrives in v1 framing; answer in kind with 2.0.
+    let pkt = read_frame(&mut reader).await?;
+    if proto_of(&pkt) != PROTO_VERSION {
+        return Err(io::Error::other("expected VERSION from host"));
+    }
+    let mut reply = [0u8; 12];
+    reply[0..4].copy_from_slice(&2u32.to_be_bytes());
+    framer.send(PROTO_VERSION, &[], &reply).await?;
+    framer.version = 2;
+
+    let (frame_tx, mut frame_rx) = mpsc::channel::<io::Result<Vec<u8>>>(16);
+    crate::spawn(async move {
+        loop {
+            let res = read_frame(&mut reader).await;
+            let stop = res.is_err();
+            if frame_tx.send(res).await.is_err() || stop {
+                break;
+            }
+        }
+    });
+
+    // Service pumps report (sport, Some(bytes)) for data and (sport, None)
+    // once the handler closes its end.
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<(u16, Option<Vec<u8>>)>();
+    let mut connections: HashMap<u16, Connection> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            frame = frame_rx.recv() => {
+                let pkt = match frame {
+                    Some(Ok(p)) => p,
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                };
+                if pkt.len() >= V2_HEADER_SIZE {
+                    framer.rx_seq = u16::from_be_bytes(pkt[12..14].try_into().unwrap());
+                }
+                match proto_of(&pkt) {
+                    PROTO_SETUP => trace!("mock device got SETUP"),
+                    PROTO_TCP => {
+                        handle_tcp(&pkt, &services, &mut connections, &mut framer, &out_tx).await?;
+                    }
+                    other => warn!("mock device ignoring protocol {other}"),
+                }
+            }
+            out = out_rx.recv() => {
+                let Some((sport, data)) = out else { continue; };
+                let Some(conn) = connections.get_mut(&sport) else { continue; };
+                match data {
+                    Some(bytes) => {
+                        for chunk in bytes.chunks(MAX_CHUNK) {
+                            framer
+                                .send_tcp(sport, conn.dport, conn.seq, conn.ack, ACK, chunk)
+                                .await?;
+                            conn.seq = conn.seq.wrapping_add(chunk.len() as u32);
+                        }
+                    }
+                    None => {
+                        framer
+                            .send_tcp(sport, conn.dport, conn.seq, conn.ack, RST, &[])
+                            .await?;
+                        connections.remove(&sport);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_tcp<W: AsyncWrite + Unpin>(
+    pkt: &[u8],
+    services: &HashMap<u16, ServiceHandler>,
+    connections: &mut HashMap<u16, Connection>,
+    framer: &mut Framer<W>,
+    out_tx: &mpsc::UnboundedSender<(u16, Option<Vec<u8>>)>,
+) -> io::Result<()> {
+    if pkt.len() < V2_HEADER_SIZE + TCP_HEADER_SIZE {
+        return Err(io::Error::other("TCP frame too short"));
+    }
+    let th = &pkt[V2_HEADER_SIZE..V2_HEADER_SIZE + TCP_HEADER_SIZE];
+    let payload = &pkt[V2_HEADER_SIZE + TCP_HEADER_SIZE..];
+    let sport = u16::from_be_bytes(th[0..2].try_into().unwrap());
+    let dport = u16::from_be_bytes(th[2..4].try_into().unwrap());
+    let seq = u32::from_be_bytes(th[4..8].try_into().unwrap());
+    let flags = th[13];
+
+    if flags & RST != 0 {
+        // Dropping the sender ends the writer pump, which closes the
+        // handler's stream.
+        connections.remove(&sport);
+        return Ok(());
+    }
+
+    if flags == SYN {
+        let Some(handler) = services.get(&dport) else {
+            debug!("mock device refusing connect to port {dport}");
+            return framer
+                .send_tcp(sport, dport, 0, seq.wrapping_add(1), RST, &[])
+                .await;
+        };
+        framer
+            .send_tcp(sport, dport, 0, seq.wrapping_add(1), SYN | ACK, &[])
+            .await?;
+
+        let (service_side, our_side) = tokio::io::duplex(PIPE_BUF);
+        let (mut our_read, mut our_write) = tokio::io::split(our_side);
+        let (to_service, mut from_host) = mpsc::unbounded_channel::<Vec<u8>>();
+        connections.insert(
+            sport,
+            Connection {
+                dport,
+                seq: 1,
+                ack: seq.wrapping_add(1),
+                to_service,
+            },
+        );
+
+        crate::spawn(handler(service_side));
+        crate::spawn(async move {
+            while let Some(chunk) = from_host.recv().await {
+                if our_write.write_all(&chunk).await.is_err() {
+                    return;
+                }
+            }
+            let _ = our_write.shutdown().await;
+        });
+        let out_tx = out_tx.clone();
+        crate::spawn(async move {
+            let mut buf = vec![0u8; MAX_CHUNK];
+            loop {
+                match our_read.read(&mut buf).await {
+                    Ok(0) | Err(_) => {
+                        let _ = out_tx.send((sport, None));
+                        return;
+                    }
+                    Ok(n) => {
+                        if out_tx.send((sport, Some(buf[..n].to_vec()))).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        return Ok(());
+    }
+
+    let Some(conn) = connections.get_mut(&sport) else {
+        return framer.send_tcp(sport, dport, 0, seq, RST, &[]).await;
+    };
+    if !payload.is_empty() {
+        if conn.to_service.send(payload.to_vec()).is_err() {
+            framer
+                .send_tcp(sport, conn.dport, conn.seq, conn.ack, RST, &[])
+                .await?;
+            connections.remove(&sport);
+            return Ok(());
+        }
+        conn.ack = conn.ack.wrapping_add(payload.len() as u32);
+        framer
+            .send_tcp(sport, conn.dport, conn.seq, conn.ack, ACK, &[])
+            .await?;
+    }
+    Ok(())
+}
+
+fn proto_of(pkt: &[u8]) -> u32 {
+    u32::from_be_bytes(pkt[0..4].try_into().unwrap())
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = [0u8; V1_HEADER_SIZE];
+    reader.read_exact(&mut head).await?;
+    let length = u32::from_be_bytes(head[4..8].try_into().unwrap()) as usize;
+    if length < V1_HEADER_SIZE {
+        return Err(io::Error::other(format!(
+            "mux frame length {length} too short"
+        )));
+    }
+    let mut pkt = vec![0u8; length];
+    pkt[..V1_HEADER_SIZE].copy_from_slice(&head);
+    reader.read_exact(&mut pkt[V1_HEADER_SIZE..]).await?;
+    Ok(pkt)
+}
@@ -2,5 +2,7 @@
 
 pub mod apple;
 pub mod bulk_io;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod mux;
 pub mod provider;
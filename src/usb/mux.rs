@@ -62,6 +62,29 @@ mod tcp_flags {
 #[derive(Clone, Debug)]
 pub struct UsbMuxHandle {
     cmd: mpsc::Sender<Command>,
+    /// Set by [`enable_reset`](Self::enable_reset).
+    reset: Option<Arc<AtomicBool>>,
+}
+
+/// A [`UsbMuxHandle`] that doesn't keep the mux task alive, for monitors
+/// that should go away with the device.
+#[derive(Clone, Debug)]
+pub struct WeakUsbMuxHandle {
+    cmd: mpsc::WeakSender<Command>,
+    reset: Option<Arc<AtomicBool>>,
+}
+
+impl WeakUsbMuxHandle {
+    pub fn upgrade(&self) -> Option<UsbMuxHandle> {
+        Some(UsbMuxHandle {
+            cmd: self.cmd.upgrade()?,
+            reset: self.reset.clone(),
+        })
+    }
+
+    pub fn can_reset(&self) -> bool {
+        self.reset.is_some()
+    }
 }
 
 enum Command {
@@ -90,6 +113,46 @@ impl UsbMuxHandle {
     pub async fn shutdown(&self) {
         let _ = self.cmd.send(Command::Shutdown).await;
     }
+
+    /// Shut the mux task down because the device stopped responding, asking
+    /// the discovery backend to reopen the device once it has exited. Does
+    /// nothing unless the backend called [`enable_reset`](Self::enable_reset),
+    /// since the device would otherwise stay gone until it's replugged.
+    pub async fn reset(&self) {
+        let Some(reset) = &self.reset else {
+            warn!("Not resetting a USB mux whose backend can't reopen it");
+            return;
+        };
+        reset.store(true, Ordering::Release);
+        self.shutdown().await;
+    }
+
+    /// Opt this device into [`reset`](Self::reset), for a backend that can
+    /// reopen it. The returned flag is set once it has been reset; the
+    /// backend checks it after the mux task exits to tell a reset from a
+    /// disconnect. Call before cloning the handle.
+    pub fn enable_reset(&mut self) -> Arc<AtomicBool> {
+        self.reset
+            .get_or_insert_with(|| Arc::new(AtomicBool::new(false)))
+            .clone()
+    }
+
+    pub fn can_reset(&self) -> bool {
+        self.reset.is_some()
+    }
+
+    pub fn downgrade(&self) -> WeakUsbMuxHandle {
+        WeakUsbMuxHandle {
+            cmd: self.cmd.downgrade(),
+            reset: self.reset.clone(),
+        }
+    }
+
+    /// Wait until the mux task has exited, e.g. after [`shutdown`](Self::shutdown)
+    /// once it has reset its open connections.
+    pub async fn closed(&self) {
+        self.cmd.closed().await;
+    }
 }
 
 /// State for a single virtual connection.
@@ -149,7 +212,10 @@ where
     W: AsyncWrite + Send + Unpin + 'static,
 {
     let (cmd_tx, cmd_rx) = mpsc::channel(16);
-    let handle = UsbMuxHandle { cmd: cmd_tx };
+    let handle = UsbMuxHandle {
+        cmd: cmd_tx,
+        reset: None,
+    };
 
     crate::spawn(async move {
         if let Err(e) = run(device_id, &serial, reader, writer, cmd_rx).await {
@@ -790,3 +856,41 @@ async fn send_raw(
         .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "writer task gone"))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idle_mux() -> UsbMuxHandle {
+        let (on_exit, _) = oneshot::channel();
+        spawn(
+            0,
+            "test".into(),
+            tokio::io::empty(),
+            tokio::io::sink(),
+            on_exit,
+        )
+    }
+
+    #[tokio::test]
+    async fn reset_is_off_until_the_backend_enables_it() {
+        let handle = idle_mux();
+        assert!(!handle.can_reset());
+        assert!(!handle.downgrade().can_reset());
+        // Nothing to set; the device is left alone.
+        handle.reset().await;
+    }
+
+    #[tokio::test]
+    async fn reset_sets_the_backends_flag() {
+        let mut handle = idle_mux();
+        let flag = handle.enable_reset();
+        assert!(handle.can_reset());
+        assert!(handle.downgrade().can_reset());
+        assert!(handle.clone().can_reset());
+
+        assert!(!flag.load(Ordering::Acquire));
+        handle.reset().await;
+        assert!(flag.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,409 @@
+// Jackson Coxson
+//
+// End-to-end tests: a `MockDevice` registered with the manager, served to
+// usbmuxd clients by an in-process `NetmuxdServer`. Requires the `mock`
+// feature.
+
+use std::{sync::Arc, time::Duration};
+
+use netmuxd::{
+    config::NetmuxdConfig,
+    manager::{ManagerRequest, ManagerRequestType, ManagerSender},
+    pairing_store::{MemoryStore, PairingStore},
+    server::{NetmuxdServer, ShutdownHandle},
+    usb::mock::MockDevice,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+const UDID: &str = "00008030-001A2B3C4D5E6F70";
+const ECHO_PORT: u16 = 62078;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running server with nothing but the clients we hand it.
+struct Harness {
+    clients: mpsc::UnboundedSender<DuplexStream>,
+    manager: ManagerSender,
+    store: Arc<MemoryStore>,
+    shutdown: ShutdownHandle,
+}
+
+impl Harness {
+    fn start() -> Self {
+        let config = NetmuxdConfig {
+            use_usb: false,
+            use_mdns: false,
+            #[cfg(unix)]
+            use_unix: false,
+            ..Default::default()
+        };
+        let (clients, accepted) = mpsc::unbounded_channel::<DuplexStream>();
+        let store = Arc::new(MemoryStore::new());
+        let server = NetmuxdServer::builder(config)
+            .pairing_store(store.clone())
+            .listener(accepted)
+            .build()
+            .expect("server builds");
+        let harness = Self {
+            clients,
+            manager: server.manager(),
+            store,
+            shutdown: server.shutdown_handle(),
+        };
+        tokio::spawn(server.serve());
+        harness
+    }
+
+    fn connect(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(65536);
+        self.clients.send(server).expect("server is accepting");
+        client
+    }
+
+    /// Plug in a mock device that echoes everything sent to `ECHO_PORT`.
+    async fn attach_echo_device(&self) {
+        let (exit_tx, _exit_rx) = oneshot::channel();
+        let handle = MockDevice::new()
+            .service(ECHO_PORT, |stream| async move {
+                let (mut r, mut w) = tokio::io::split(stream);
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            })
+            .spawn_mux(1, UDID.into(), exit_tx);
+        self.send(ManagerRequestType::DiscoveredUsbDevice {
+            udid: UDID.into(),
+            location_id: 0x1420000,
+            product_id: 0x12a8,
+            speed: 480_000_000,
+            handle,
+            status: None,
+        })
+        .await;
+    }
+
+    async fn send(&self, request_type: ManagerRequestType) {
+        self.manager
+            .send(ManagerRequest {
+                request_type,
+                response: None,
+            })
+            .await
+            .expect("manager is running");
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+    }
+}
+
+fn field<'a>(dict: &'a plist::Dictionary, key: &str) -> &'a plist::Value {
+    dict.get(key)
+        .unwrap_or_else(|| panic!("no {key} in {dict:?}"))
+}
+
+/// `SerialNumber` from an `Attached` message or `DeviceList` entry.
+fn serial_number(device: &plist::Dictionary) -> Option<&str> {
+    field(device, "Properties")
+        .as_dictionary()?
+        .get("SerialNumber")?
+        .as_string()
+}
+
+async fn send_plist(stream: &mut DuplexStream, tag: u32, body: plist::Dictionary) {
+    let mut xml = Vec::new();
+    plist::to_writer_xml(&mut xml, &body).unwrap();
+    let mut packet = Vec::with_capacity(16 + xml.len());
+    packet.extend_from_slice(&((16 + xml.len()) as u32).to_le_bytes());
+    packet.extend_from_slice(&1u32.to_le_bytes());
+    packet.extend_from_slice(&8u32.to_le_bytes());
+    packet.extend_from_slice(&tag.to_le_bytes());
+    packet.extend_from_slice(&xml);
+    stream.write_all(&packet).await.unwrap();
+}
+
+/// Read one packet: (version, message, tag, body).
+async fn read_packet(stream: &mut DuplexStream) -> (u32, u32, u32, Vec<u8>) {
+    let mut header = [0u8; 16];
+    timeout(TIMEOUT, stream.read_exact(&mut header))
+        .await
+        .expect("reply in time")
+        .unwrap();
+    let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let mut body = vec![0u8; word(0) as usize - 16];
+    stream.read_exact(&mut body).await.unwrap();
+    (word(4), word(8), word(12), body)
+}
+
+async fn read_plist(stream: &mut DuplexStream) -> (u32, plist::Dictionary) {
+    let (version, message, tag, body) = read_packet(stream).await;
+    assert_eq!((version, message), (1, 8));
+    (tag, plist::from_bytes(&body).unwrap())
+}
+
+fn binary_connect(device_id: u32, port: u16) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&24u32.to_le_bytes());
+    packet.extend_from_slice(&0u32.to_le_bytes());
+    packet.extend_from_slice(&2u32.to_le_bytes());
+    packet.extend_from_slice(&7u32.to_le_bytes());
+    packet.extend_from_slice(&device_id.to_le_bytes());
+    packet.extend_from_slice(&port.to_be_bytes());
+    packet.extend_from_slice(&[0u8; 2]);
+    packet
+}
+
+async fn list_devices(harness: &Harness) -> Vec<plist::Dictionary> {
+    let mut client = harness.connect();
+    send_plist(
+        &mut client,
+        3,
+        plist_macro::plist!(dict { "MessageType": "ListDevices" }),
+    )
+    .await;
+    let (tag, reply) = read_plist(&mut client).await;
+    assert_eq!(tag, 3);
+    field(&reply, "DeviceList")
+        .as_array()
+        .expect("DeviceList is an array")
+        .iter()
+        .map(|d| d.as_dictionary().unwrap().clone())
+        .collect()
+}
+
+fn usb_device_id(devices: &[plist::Dictionary]) -> u64 {
+    let device = devices
+        .iter()
+        .find(|d| serial_number(d) == Some(UDID))
+        .expect("mock device is listed");
+    field(device, "DeviceID").as_unsigned_integer().unwrap()
+}
+
+#[tokio::test]
+async fn list_devices_includes_mock_usb_device() {
+    let harness = Harness::start();
+    assert!(list_devices(&harness).await.is_empty());
+
+    harness.attach_echo_device().await;
+    let devices = list_devices(&harness).await;
+    assert_eq!(devices.len(), 1);
+    let props = field(&devices[0], "Properties").as_dictionary().unwrap();
+    assert_eq!(field(props, "ConnectionType").as_string(), Some("USB"));
+    assert_eq!(serial_number(&devices[0]), Some(UDID));
+    assert_eq!(
+        field(props, "ProductID").as_unsigned_integer(),
+        Some(0x12a8)
+    );
+}
+
+#[tokio::test]
+async fn connect_tunnels_to_mock_service() {
+    let harness = Harness::start();
+    harness.attach_echo_device().await;
+    let id = usb_device_id(&list_devices(&harness).await) as u32;
+
+    let mut client = harness.connect();
+    client
+        .write_all(&binary_connect(id, ECHO_PORT))
+        .await
+        .unwrap();
+    let (version, message, tag, body) = read_packet(&mut client).await;
+    assert_eq!((version, message, tag), (0, 1, 7));
+    assert_eq!(body, 0u32.to_le_bytes());
+
+    // The socket is now the device's port 62078.
+    client.write_all(b"hello device").await.unwrap();
+    let mut echoed = [0u8; 12];
+    timeout(TIMEOUT, client.read_exact(&mut echoed))
+        .await
+        .expect("echo in time")
+        .unwrap();
+    assert_eq!(&echoed, b"hello device");
+}
+
+#[tokio::test]
+async fn connect_to_closed_port_is_refused() {
+    let harness = Harness::start();
+    harness.attach_echo_device().await;
+    let id = usb_device_id(&list_devices(&harness).await) as u32;
+
+    let mut client = harness.connect();
+    client.write_all(&binary_connect(id, 1234)).await.unwrap();
+    let (_, message, _, body) = read_packet(&mut client).await;
+    assert_eq!(message, 1);
+    assert_eq!(body, 3u32.to_le_bytes());
+}
+
+#[tokio::test]
+async fn connect_to_unknown_device_is_bad_device() {
+    let harness = Harness::start();
+
+    let mut client = harness.connect();
+    client
+        .write_all(&binary_connect(42, ECHO_PORT))
+        .await
+        .unwrap();
+    let (_, message, _, body) = read_packet(&mut client).await;
+    assert_eq!(message, 1);
+    assert_eq!(body, 2u32.to_le_bytes());
+}
+
+#[tokio::test]
+async fn listen_reports_attach_and_detach() {
+    let harness = Harness::start();
+
+    let mut client = harness.connect();
+    send_plist(
+        &mut client,
+        5,
+        plist_macro::plist!(dict { "MessageType": "Listen" }),
+    )
+    .await;
+    let (tag, result) = read_plist(&mut client).await;
+    assert_eq!(tag, 5);
+    assert_eq!(field(&result, "Number").as_unsigned_integer(), Some(0));
+
+    harness.attach_echo_device().await;
+    let (_, attached) = read_plist(&mut client).await;
+    assert_eq!(
+        field(&attached, "MessageType").as_string(),
+        Some("Attached")
+    );
+    let id = field(&attached, "DeviceID").as_unsigned_integer().unwrap();
+    assert_eq!(serial_number(&attached), Some(UDID));
+
+    harness
+        .send(ManagerRequestType::RemoveDevice {
+            udid: UDID.into(),
+            connection_type: Some("USB".into()),
+        })
+        .await;
+    let (_, detached) = read_plist(&mut client).await;
+    assert_eq!(
+        field(&detached, "MessageType").as_string(),
+        Some("Detached")
+    );
+    assert_eq!(field(&detached, "DeviceID").as_unsigned_integer(), Some(id));
+}
+
+#[tokio::test]
+async fn add_device_without_pair_record_is_bad_device() {
+    let harness = Harness::start();
+
+    let mut client = harness.connect();
+    send_plist(
+        &mut client,
+        9,
+        plist_macro::plist!(dict {
+            "MessageType": "AddDevice",
+            "ConnectionType": "Network",
+            "ServiceName": "_apple-mobdev2._tcp.local",
+            "IPAddress": "127.0.0.1",
+            "DeviceID": UDID,
+        }),
+    )
+    .await;
+    let (tag, result) = read_plist(&mut client).await;
+    assert_eq!(tag, 9);
+    assert_eq!(field(&result, "Number").as_unsigned_integer(), Some(2));
+}
+
+/// Ask for the `ListenerList` on a fresh connection.
+async fn list_listeners(harness: &Harness) -> Vec<plist::Dictionary> {
+    let mut client = harness.connect();
+    send_plist(
+        &mut client,
+        4,
+        plist_macro::plist!(dict { "MessageType": "ListListeners" }),
+    )
+    .await;
+    let (_, reply) = read_plist(&mut client).await;
+    field(&reply, "ListenerList")
+        .as_array()
+        .expect("ListenerList is an array")
+        .iter()
+        .map(|l| l.as_dictionary().unwrap().clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn list_listeners_reports_listen_sessions() {
+    let harness = Harness::start();
+    assert!(list_listeners(&harness).await.is_empty());
+
+    let mut listener = harness.connect();
+    send_plist(
+        &mut listener,
+        5,
+        plist_macro::plist!(dict {
+            "MessageType": "Listen",
+            "ProgName": "mock-test",
+            "BundleID": "dev.netmuxd.test",
+            "ClientVersionString": "mock-1.0",
+        }),
+    )
+    .await;
+    read_plist(&mut listener).await;
+
+    // The session subscribes right after its Result goes out.
+    let listeners = timeout(TIMEOUT, async {
+        loop {
+            let listeners = list_listeners(&harness).await;
+            if !listeners.is_empty() {
+                return listeners;
+            }
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("listener registered in time");
+    assert_eq!(listeners.len(), 1);
+    let l = &listeners[0];
+    assert_eq!(field(l, "ProgName").as_string(), Some("mock-test"));
+    assert_eq!(field(l, "BundleID").as_string(), Some("dev.netmuxd.test"));
+    assert_eq!(field(l, "Version String").as_string(), Some("mock-1.0"));
+    assert!(
+        field(l, "ID String")
+            .as_string()
+            .is_some_and(|s| s.ends_with("-mock-test"))
+    );
+}
+
+#[tokio::test]
+async fn delete_pair_record_removes_it() {
+    let harness = Harness::start();
+    let mut record = Vec::new();
+    plist::to_writer_xml(&mut record, &plist::Dictionary::new()).unwrap();
+    harness.store.save(UDID, &record).await.unwrap();
+
+    let mut client = harness.connect();
+    send_plist(
+        &mut client,
+        6,
+        plist_macro::plist!(dict {
+            "MessageType": "DeletePairRecord",
+            "PairRecordID": UDID,
+        }),
+    )
+    .await;
+    let (tag, result) = read_plist(&mut client).await;
+    assert_eq!(tag, 6);
+    assert_eq!(field(&result, "Number").as_unsigned_integer(), Some(0));
+    assert_eq!(harness.store.get(UDID).await.unwrap(), None);
+
+    send_plist(
+        &mut client,
+        7,
+        plist_macro::plist!(dict {
+            "MessageType": "ReadPairRecord",
+            "PairRecordID": UDID,
+        }),
+    )
+    .await;
+    let (tag, result) = read_plist(&mut client).await;
+    assert_eq!(tag, 7);
+    assert_eq!(field(&result, "Number").as_unsigned_integer(), Some(2));
+}